@@ -32,7 +32,9 @@ async fn main() -> RSyncResult<()> {
         .await?
         .try_buffer_unordered(12)
         .try_filter(|x| match *x {
-            RMirrorStatus::NotDeleted(_) => futures::future::ready(false),
+            RMirrorStatus::NotDeleted(_) | RMirrorStatus::Excluded(_) => {
+                futures::future::ready(false)
+            }
             _ => futures::future::ready(true),
         })
         .for_each(|x| {
@@ -1,13 +1,23 @@
-use std::{path::Path, str::FromStr};
+use std::{
+    fmt::Debug,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 
-use futures::{StreamExt, TryStreamExt};
+use futures::StreamExt;
 use gcs_rsync::{
     oauth2::token::TokenGenerator,
     storage::{
         credentials::{authorizeduser, metadata},
-        Error, StorageResult,
+        Error, ObjectClient, RetryPolicy, StorageResult,
+    },
+    sync::{
+        Backup, BandwidthLimiter, BisyncState, ComparisonStrategy, Concurrency, ConflictResolution,
+        ErrorPolicy, FilterRules, MaxDelete, ProgressEvent, ProgressStage, RSync, RSyncError,
+        RSyncResult, RelativePath, Source, SyncReport,
     },
-    sync::{RSync, RSyncError, RSyncResult, Source},
 };
 
 use structopt::StructOpt;
@@ -26,10 +36,123 @@ struct Opt {
     #[structopt(short, long)]
     mirror: bool,
 
+    /// Abort the mirror before changing anything when it would delete more than this number of files or percentage of the destination (ex: 100 or 10%)
+    #[structopt(long)]
+    max_delete: Option<MaxDelete>,
+
+    /// Also delete the excluded destination files in mirror mode, they are protected by default
+    #[structopt(long)]
+    delete_excluded: bool,
+
+    /// Keep deleted or overwritten destination files under this directory or prefix of the destination, in a timestamped subdirectory
+    #[structopt(long)]
+    backup_dir: Option<String>,
+
+    /// Suffix appended to the backed up files (ex: ~)
+    #[structopt(long, default_value = "")]
+    backup_suffix: String,
+
+    /// Keep the partial file of a failed fs download (.<name>.gcs-rsync-partial) instead of removing it so that the next download resumes it
+    #[structopt(long)]
+    partial: bool,
+
     /// Restore mtime on filesystem (disabled by default)
     #[structopt(short, long)]
     restore_fs_mtime: bool,
 
+    /// Show what would be synced or deleted without changing the destination
+    #[structopt(short = "n", long)]
+    dry_run: bool,
+
+    /// Compare merge-joined listings (size, mtime and crc32c) instead of requesting metadata per entry
+    #[structopt(short, long)]
+    listing_comparison: bool,
+
+    /// How existing destination entries are compared: default (mtime and size, then crc32c), checksum (crc32c only), size-only, mtime-only or md5 (crc32c for gcs composite objects)
+    #[structopt(long, default_value = "default")]
+    compare: ComparisonStrategy,
+
+    /// Modification times within this number of seconds count as equal (ex: 2 for FAT file systems)
+    #[structopt(long, default_value = "0")]
+    modify_window: u64,
+
+    /// Skip files whose destination is newer than the source
+    #[structopt(long)]
+    update: bool,
+
+    /// Never overwrite existing destination files, only create missing ones
+    #[structopt(long)]
+    ignore_existing: bool,
+
+    /// Activate bidirectional sync mode, the state of the last run is loaded from and saved to this file
+    #[structopt(short, long)]
+    bisync: Option<PathBuf>,
+
+    /// Bisync conflict resolution when a path changed on both sides: newer-wins, keep-both or fail
+    #[structopt(long, default_value = "keep-both")]
+    conflict_resolution: ConflictResolution,
+
+    /// Show a live progress line with throughput and ETA on stderr
+    #[structopt(short, long)]
+    progress: bool,
+
+    /// Max attempts for requests and transfers failing with a transient error (429, 5xx, connection reset, ...)
+    #[structopt(long, default_value = "6")]
+    max_attempts: u32,
+
+    /// Give up retrying after this number of seconds
+    #[structopt(long, default_value = "120")]
+    retry_deadline: u64,
+
+    /// Bandwidth limit shared by all transfers in KiB/s or with a B, K, M or G suffix (ex: 10M).
+    /// Upload and download can be limited separately with UPLOAD:DOWNLOAD (ex: 1M:10M or off:10M)
+    #[structopt(long)]
+    bwlimit: Option<BwLimit>,
+
+    /// Max number of data transfers in flight, lowered on rate limiting (429) and slowly raised back
+    #[structopt(long, default_value = "8")]
+    transfers: usize,
+
+    /// Max number of metadata requests (size, mtime, crc32c, delete) in flight
+    #[structopt(long, default_value = "32")]
+    metadata_concurrency: usize,
+
+    /// Max number of listing requests in flight
+    #[structopt(long, default_value = "2")]
+    listing_concurrency: usize,
+
+    /// Keep the number of transfers in flight fixed instead of adapting it to rate limiting
+    #[structopt(long)]
+    fixed_transfers: bool,
+
+    /// What to do when entries fail: continue, fail-fast or abort-after:<errors>
+    #[structopt(long, default_value = "continue")]
+    on_error: ErrorPolicy,
+
+    /// Only retry the failed paths of the json report written by a previous run with --report
+    #[structopt(long)]
+    retry_failed: Option<PathBuf>,
+
+    /// Also write the end-of-run report as json to this file
+    #[structopt(long)]
+    report: Option<PathBuf>,
+
+    /// Only sync source files of at least this size in bytes or with a K, M, G or T suffix (ex: 1K)
+    #[structopt(long, parse(try_from_str = parse_size))]
+    min_size: Option<u64>,
+
+    /// Only sync source files of at most this size in bytes or with a K, M, G or T suffix (ex: 5G)
+    #[structopt(long, parse(try_from_str = parse_size))]
+    max_size: Option<u64>,
+
+    /// Only sync source files modified less than this age ago, in seconds or with a s, m, h or d suffix (ex: 7d)
+    #[structopt(long, parse(try_from_str = parse_age))]
+    newer_than: Option<chrono::Duration>,
+
+    /// Only sync source files modified more than this age ago, in seconds or with a s, m, h or d suffix (ex: 1h)
+    #[structopt(long, parse(try_from_str = parse_age))]
+    older_than: Option<chrono::Duration>,
+
     /// Include glob pattern, can be repeated
     #[structopt(short = "i", long = "include")]
     includes: Vec<String>,
@@ -38,6 +161,23 @@ struct Opt {
     #[structopt(short = "x", long = "exclude")]
     excludes: Vec<String>,
 
+    /// rsync filter rule (ex: "- *.tmp" or "+ /logs/***"), can be repeated, rules are checked in order and the first match wins
+    #[structopt(
+        short = "f",
+        long = "filter",
+        number_of_values = 1,
+        allow_hyphen_values = true
+    )]
+    filters: Vec<String>,
+
+    /// Read rsync filter rules from this file, one rule per line
+    #[structopt(long)]
+    filter_from: Option<PathBuf>,
+
+    /// Read exclude patterns from this file, one pattern per line
+    #[structopt(long)]
+    exclude_from: Option<PathBuf>,
+
     /// Source path: can be either gs (gs://bucket/path/to/object) or fs source
     /// To synchronize only a prefix: gs://bucket/path/to/your/prefix
     /// To synchronize a full folder: gs://bucket/path/to/your/folder/ with the trailing slash in the end
@@ -50,6 +190,93 @@ struct Opt {
     dest: String,
 }
 
+#[derive(Debug, PartialEq, Eq)]
+enum BwLimit {
+    Global(u64),
+    UploadDownload {
+        upload: Option<u64>,
+        download: Option<u64>,
+    },
+}
+
+/// Rate in bytes per second, KiB/s without suffix like rsync, `off` for no limit
+fn parse_rate(s: &str) -> Result<Option<u64>, String> {
+    let s = s.trim();
+    if s.is_empty() || s.eq_ignore_ascii_case("off") {
+        return Ok(None);
+    }
+    let (value, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c.to_ascii_uppercase()),
+        _ => (s, 'K'),
+    };
+    let multiplier: f64 = match unit {
+        'B' => 1.0,
+        'K' => 1024.0,
+        'M' => 1024.0 * 1024.0,
+        'G' => 1024.0 * 1024.0 * 1024.0,
+        _ => return Err(format!("unknown bandwidth unit {unit} in {s}")),
+    };
+    let value: f64 = value
+        .parse()
+        .map_err(|_| format!("invalid bandwidth limit {s}"))?;
+    match (value * multiplier) as u64 {
+        0 => Err(format!("bandwidth limit {s} should be greater than 0")),
+        rate => Ok(Some(rate)),
+    }
+}
+
+/// Size in bytes, like rsync a suffix is a power of 1024
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (value, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c.to_ascii_uppercase()),
+        _ => (s, 'B'),
+    };
+    let exponent = match unit {
+        'B' => 0,
+        'K' => 1,
+        'M' => 2,
+        'G' => 3,
+        'T' => 4,
+        _ => return Err(format!("unknown size unit {unit} in {s}")),
+    };
+    let value: f64 = value.parse().map_err(|_| format!("invalid size {s}"))?;
+    Ok((value * 1024f64.powi(exponent)) as u64)
+}
+
+/// Age in seconds or with a s, m, h or d suffix
+fn parse_age(s: &str) -> Result<chrono::Duration, String> {
+    let s = s.trim();
+    let (value, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c.to_ascii_lowercase()),
+        _ => (s, 's'),
+    };
+    let value: i64 = value.parse().map_err(|_| format!("invalid age {s}"))?;
+    match unit {
+        's' => Ok(chrono::Duration::seconds(value)),
+        'm' => Ok(chrono::Duration::minutes(value)),
+        'h' => Ok(chrono::Duration::hours(value)),
+        'd' => Ok(chrono::Duration::days(value)),
+        _ => Err(format!("unknown age unit {unit} in {s}")),
+    }
+}
+
+impl FromStr for BwLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((upload, download)) => Ok(BwLimit::UploadDownload {
+                upload: parse_rate(upload)?,
+                download: parse_rate(download)?,
+            }),
+            None => parse_rate(s)?
+                .map(BwLimit::Global)
+                .ok_or_else(|| "bandwidth limit is off".to_owned()),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct BucketPrefix {
@@ -87,7 +314,9 @@ impl BucketPrefix {
 async fn get_source(
     path: &str,
     is_dest: bool,
+    keep_partial: bool,
     use_metadata_token_api: bool,
+    retry_policy: &RetryPolicy,
 ) -> RSyncResult<Source> {
     match BucketPrefix::from_str(path).ok() {
         Some(o) => {
@@ -107,14 +336,20 @@ async fn get_source(
             };
             let bucket = o.bucket.as_str();
             let prefix = o.prefix.as_str();
-            match token_generator {
-                None => Ok(Source::gcs_no_auth(bucket, prefix)),
-                Some(token_generator) => Source::gcs(token_generator, bucket, prefix).await,
-            }
+            let client = match token_generator {
+                None => ObjectClient::no_auth(),
+                Some(token_generator) => ObjectClient::new(token_generator)
+                    .await
+                    .map_err(RSyncError::StorageError)?,
+            };
+            let client = client.with_retry_policy(retry_policy.to_owned());
+            Ok(Source::gcs_with_client(client, bucket, prefix))
         }
         None => {
             let path = Path::new(path);
-            if path.exists() || is_dest {
+            if (path.exists() || is_dest) && keep_partial {
+                Ok(Source::fs_keep_partial(path))
+            } else if path.exists() || is_dest {
                 Ok(Source::fs(path))
             } else {
                 Err(RSyncError::EmptyRelativePathError)
@@ -123,16 +358,206 @@ async fn get_source(
     }
 }
 
+fn human_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Live progress line redrawn on stderr at most every 200ms
+struct ProgressLine {
+    started_at: Instant,
+    drawn_at: Mutex<Option<Instant>>,
+}
+
+impl ProgressLine {
+    fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            drawn_at: Mutex::new(None),
+        }
+    }
+
+    fn draw(&self, event: &ProgressEvent) {
+        let now = Instant::now();
+        {
+            let mut drawn_at = self.drawn_at.lock().unwrap();
+            let throttled = drawn_at.is_some_and(|x| now - x < Duration::from_millis(200));
+            if event.stage == ProgressStage::Transferring && throttled {
+                return;
+            }
+            *drawn_at = Some(now);
+        }
+
+        let totals = &event.totals;
+        let elapsed = (now - self.started_at).as_secs_f64();
+        let throughput = totals.bytes_written as f64 / elapsed.max(0.001);
+        let eta = if throughput > 0.0 {
+            format!("{:.0}s", totals.bytes_remaining as f64 / throughput)
+        } else {
+            "-".to_owned()
+        };
+        eprint!(
+            "\r\x1b[K{}/{} files, {} written, {}/s, ETA {}",
+            totals.files_finished,
+            totals.files_started,
+            human_bytes(totals.bytes_written as f64),
+            human_bytes(throughput),
+            eta
+        );
+    }
+}
+
+fn print_status<T: Debug>(progress: bool, status: T) {
+    if progress {
+        eprint!("\r\x1b[K");
+    }
+    println!("{:?}", status);
+}
+
+fn state_error(path: &Path, message: &str, error: std::io::Error) -> RSyncError {
+    RSyncError::FsIoError {
+        path: path.to_path_buf(),
+        message: message.to_owned(),
+        error,
+    }
+}
+
+fn print_summary(report: &SyncReport) {
+    println!(
+        "{}created: {}, updated: {}, already synced: {}, skipped: {}, deleted: {}, not deleted: {}, excluded: {}, backed up: {}, conflicts: {}, failed: {}",
+        if report.dry_run { "(dry run) " } else { "" },
+        report.created,
+        report.updated,
+        report.already_synced,
+        report.skipped,
+        report.deleted,
+        report.not_deleted,
+        report.excluded,
+        report.backed_up,
+        report.conflicts,
+        report.failed
+    );
+    println!(
+        "{} transferred in {:.1}s ({}/s)",
+        human_bytes(report.bytes_transferred as f64),
+        report.elapsed_secs,
+        human_bytes(report.bytes_per_second)
+    );
+    report.failures.iter().for_each(|failure| {
+        let path = failure.path.as_ref().map(RelativePath::as_str);
+        match (path, failure.operation) {
+            (Some(path), Some(operation)) => {
+                println!("failed {} ({:?}): {}", path, operation, failure.error)
+            }
+            (Some(path), None) => println!("failed {}: {}", path, failure.error),
+            _ => println!("failed: {}", failure.error),
+        }
+    });
+}
+
+async fn save_report(path: &Path, report: &SyncReport) -> RSyncResult<()> {
+    let content = serde_json::to_vec_pretty(report)
+        .map_err(|e| state_error(path, "cannot serialize report", e.into()))?;
+    tokio::fs::write(path, content)
+        .await
+        .map_err(|e| state_error(path, "cannot write report", e))
+}
+
+async fn read_rules_file(path: &Path) -> RSyncResult<String> {
+    tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| state_error(path, "cannot read filter rules", e))
+}
+
+async fn load_filter_rules(opt: &Opt) -> RSyncResult<FilterRules> {
+    let mut rules = FilterRules::parse(&opt.filters.join("\n"))?;
+    if let Some(path) = opt.filter_from.as_deref() {
+        rules = rules.extend(FilterRules::parse(&read_rules_file(path).await?)?);
+    }
+    if let Some(path) = opt.exclude_from.as_deref() {
+        rules = rules.extend(FilterRules::parse_excludes(&read_rules_file(path).await?)?);
+    }
+    Ok(rules)
+}
+
+async fn load_failed_paths(path: &Path) -> RSyncResult<Vec<RelativePath>> {
+    let content = tokio::fs::read(path)
+        .await
+        .map_err(|e| state_error(path, "cannot read report", e))?;
+    let report: SyncReport = serde_json::from_slice(&content)
+        .map_err(|e| state_error(path, "cannot parse report", e.into()))?;
+    Ok(report.failed_paths())
+}
+
+async fn load_bisync_state(path: &Path) -> RSyncResult<BisyncState> {
+    if !path.exists() {
+        return Ok(BisyncState::default());
+    }
+    let content = tokio::fs::read(path)
+        .await
+        .map_err(|e| state_error(path, "cannot read bisync state", e))?;
+    serde_json::from_slice(&content)
+        .map_err(|e| state_error(path, "cannot parse bisync state", e.into()))
+}
+
+async fn save_bisync_state(path: &Path, state: &BisyncState) -> RSyncResult<()> {
+    let content = serde_json::to_vec(state)
+        .map_err(|e| state_error(path, "cannot serialize bisync state", e.into()))?;
+    tokio::fs::write(path, content)
+        .await
+        .map_err(|e| state_error(path, "cannot write bisync state", e))
+}
+
 #[tokio::main]
 async fn main() -> RSyncResult<()> {
-    let num_cpus = num_cpus::get();
-
     let opt = Opt::from_args();
-    let source = get_source(&opt.source, false, opt.use_metadata_token_api).await?;
-    let dest = get_source(&opt.dest, true, opt.use_metadata_token_api).await?;
+    let retry_policy = RetryPolicy::default()
+        .with_max_attempts(opt.max_attempts)
+        .with_deadline(Some(Duration::from_secs(opt.retry_deadline)));
+    let source = get_source(
+        &opt.source,
+        false,
+        false,
+        opt.use_metadata_token_api,
+        &retry_policy,
+    )
+    .await?;
+    let dest = get_source(
+        &opt.dest,
+        true,
+        opt.partial,
+        opt.use_metadata_token_api,
+        &retry_policy,
+    )
+    .await?;
+
+    let filter_rules = load_filter_rules(&opt).await?;
 
-    let rsync = RSync::new(source, dest)
+    let mut rsync = RSync::new(source, dest)
         .with_restore_fs_mtime(opt.restore_fs_mtime)
+        .with_dry_run(opt.dry_run)
+        .with_listing_comparison(opt.listing_comparison)
+        .with_comparison_strategy(opt.compare)
+        .with_modify_window(Duration::from_secs(opt.modify_window))
+        .with_update_only(opt.update)
+        .with_ignore_existing(opt.ignore_existing)
+        .with_delete_excluded(opt.delete_excluded)
+        .with_conflict_resolution(opt.conflict_resolution)
+        .with_retry_policy(retry_policy)
+        .with_error_policy(opt.on_error)
+        .with_filter_rules(filter_rules)
+        .with_concurrency(Concurrency {
+            listing: opt.listing_concurrency,
+            metadata: opt.metadata_concurrency,
+            transfers: opt.transfers,
+            adaptive: !opt.fixed_transfers,
+        })
         .with_includes(
             opt.includes
                 .iter()
@@ -147,29 +572,96 @@ async fn main() -> RSyncResult<()> {
                 .collect::<Vec<_>>()
                 .as_slice(),
         )?;
+    match opt.bwlimit {
+        None => (),
+        Some(BwLimit::Global(rate)) => {
+            rsync = rsync.with_bandwidth_limiter(BandwidthLimiter::new(rate));
+        }
+        Some(BwLimit::UploadDownload { upload, download }) => {
+            if let Some(rate) = upload {
+                rsync = rsync.with_upload_limiter(BandwidthLimiter::new(rate));
+            }
+            if let Some(rate) = download {
+                rsync = rsync.with_download_limiter(BandwidthLimiter::new(rate));
+            }
+        }
+    }
+    if let Some(dir) = opt.backup_dir.as_deref() {
+        rsync = rsync.with_backup(Backup::new(dir)?.with_suffix(&opt.backup_suffix));
+    }
+    if let Some(max_delete) = opt.max_delete {
+        rsync = rsync.with_max_delete(max_delete);
+    }
+    if let Some(size) = opt.min_size {
+        rsync = rsync.with_min_size(size);
+    }
+    if let Some(size) = opt.max_size {
+        rsync = rsync.with_max_size(size);
+    }
+    let now = chrono::Utc::now();
+    if let Some(age) = opt.newer_than {
+        rsync = rsync.with_newer_than(now - age);
+    }
+    if let Some(age) = opt.older_than {
+        rsync = rsync.with_older_than(now - age);
+    }
+    if let Some(path) = opt.retry_failed.as_deref() {
+        rsync = rsync.with_only_paths(load_failed_paths(path).await?);
+    }
+    if opt.progress {
+        let progress_line = ProgressLine::new();
+        rsync = rsync.with_progress(move |x| progress_line.draw(x));
+    }
 
-    if opt.mirror {
+    let mut reporter = rsync.reporter();
+    if let Some(state_path) = opt.bisync.as_deref() {
+        println!("bisyncing {} <> {}", &opt.source, &opt.dest);
+        let state = load_bisync_state(state_path).await?;
+        let report = rsync
+            .bisync(&state, opt.metadata_concurrency + opt.transfers)
+            .await?;
+        report.statuses.iter().for_each(|x| {
+            print_status(opt.progress, x);
+            reporter.record_bisync(x);
+        });
+        report.failures.iter().for_each(|(path, error)| {
+            println!("failed {:?}: {:?}", path, error);
+            reporter.record_error(error);
+        });
+        save_bisync_state(state_path, &report.state).await?;
+    } else if opt.mirror {
         println!("mirroring {} > {}", &opt.source, &opt.dest);
         rsync
-            .mirror()
+            .run_mirror()
             .await?
-            .try_buffer_unordered(num_cpus)
             .for_each(|x| {
-                println!("{:?}", x);
+                print_status(opt.progress, &x);
+                reporter.record_mirror(&x);
                 futures::future::ready(())
             })
             .await;
     } else {
         println!("syncing {} > {}", &opt.source, &opt.dest);
         rsync
-            .sync()
+            .run_sync()
             .await
-            .try_buffer_unordered(num_cpus)
             .for_each(|x| {
-                println!("{:?}", x);
+                print_status(opt.progress, &x);
+                reporter.record_sync(&x);
                 futures::future::ready(())
             })
             .await;
     };
+    if opt.progress {
+        eprintln!();
+    }
+    let report = reporter.finish();
+    print_summary(&report);
+    if let Some(path) = opt.report.as_deref() {
+        save_report(path, &report).await?;
+    }
+    if report.has_failures() {
+        std::process::exit(1);
+    }
     Ok(())
 }
@@ -1,4 +1,4 @@
-use super::{Error, StorageResult};
+use super::{Error, RetryPolicy, StorageResult, CRC32C};
 use crate::gcp::{
     oauth2::token::{AccessToken, Token, TokenGenerator},
     Client,
@@ -66,6 +66,7 @@ pub(super) struct StorageClient {
     client: Client,
     token_state_holder: Option<TokenStateHolder>,
     host: String,
+    retry_policy: RetryPolicy,
 }
 
 const MT_SEPARATOR: &[u8] = b"--gcs-storage\n";
@@ -89,6 +90,7 @@ impl StorageClient {
             client,
             token_state_holder,
             host,
+            retry_policy: RetryPolicy::default(),
         })
     }
 
@@ -100,9 +102,15 @@ impl StorageClient {
             client,
             token_state_holder,
             host,
+            retry_policy: RetryPolicy::default(),
         }
     }
 
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
     async fn success_response(
         url: &str,
         response: reqwest::Response,
@@ -118,10 +126,23 @@ impl StorageClient {
             });
         }
 
+        if status == reqwest::StatusCode::PRECONDITION_FAILED {
+            return Err(super::Error::GcsPreconditionFailed {
+                url: url.to_owned(),
+            });
+        }
+
         let err = response
             .text()
             .await
             .map_err(super::Error::GcsHttpNoTextError)?;
+        if super::is_transient_status(status) {
+            return Err(super::Error::GcsTransientResponse {
+                url: url.to_owned(),
+                status: status.as_u16(),
+                value: err,
+            });
+        }
         Err(super::Error::gcs_unexpected_response_error(url, err))
     }
 
@@ -139,22 +160,86 @@ impl StorageClient {
         format!("{host}/{url}")
     }
 
-    pub async fn delete(&self, url: &str) -> StorageResult<()> {
-        let url = self.resolve_url(url);
-        let request = self
-            .with_auth(self.client.client.delete(url.as_str()))
-            .await?;
+    async fn send_delete(&self, url: &str, if_generation_match: Option<i64>) -> StorageResult<()> {
+        let request = self.client.client.delete(url);
+        let request = match if_generation_match {
+            Some(generation) => request.query(&[("ifGenerationMatch", generation)]),
+            None => request,
+        };
+        let request = self.with_auth(request).await?;
         let response = request
             .send()
             .await
             .map_err(super::Error::GcsHttpDeleteError)?;
-        Self::success_response(url.as_str(), response).await?;
+        Self::success_response(url, response).await?;
         Ok(())
     }
 
+    /// Unconditional deletes are not idempotent (a retry could delete an object written in the meantime) so they are never retried
+    pub async fn delete(&self, url: &str) -> StorageResult<()> {
+        let url = self.resolve_url(url);
+        self.send_delete(url.as_str(), None).await
+    }
+
+    /// Delete guarded by an `ifGenerationMatch` precondition, it is retried.
+    ///
+    /// A retry failing because the object is gone or its generation changed means an earlier attempt deleted it, so it is a success
+    pub async fn delete_if_generation_match(
+        &self,
+        url: &str,
+        generation: i64,
+    ) -> StorageResult<()> {
+        let url = self.resolve_url(url);
+        let url = url.as_str();
+        let attempts = std::sync::atomic::AtomicU32::new(0);
+        self.retry_policy
+            .retry(Error::is_retryable, || async {
+                let retried = attempts.fetch_add(1, std::sync::atomic::Ordering::Relaxed) > 0;
+                match self.send_delete(url, Some(generation)).await {
+                    Err(
+                        Error::GcsResourceNotFound { .. } | Error::GcsPreconditionFailed { .. },
+                    ) if retried => Ok(()),
+                    result => result,
+                }
+            })
+            .await
+    }
+
+    /// Post without body and read the json response, the request is retried so it has to be idempotent (ex: rewrite)
+    pub async fn post_as_json<R, Q>(&self, url: &str, query: &Q) -> StorageResult<R>
+    where
+        R: DeserializeOwned,
+        Q: serde::Serialize,
+    {
+        let url = self.resolve_url(url);
+        let url = url.as_str();
+
+        self.retry_policy
+            .retry(Error::is_retryable, || async {
+                let request = self
+                    .with_auth(self.client.client.post(url).query(query))
+                    .await?;
+                let response = request
+                    .header("Content-Length", 0)
+                    .send()
+                    .await
+                    .map_err(super::Error::GcsHttpPostError)?;
+                let r: super::super::DeserializedResponse<R> =
+                    Self::success_response(url, response)
+                        .await?
+                        .json()
+                        .await
+                        .map_err(super::Error::GcsHttpJsonResponseError)?;
+                r.into_result()
+                    .map_err(|err| super::Error::gcs_unexpected_json::<R>(url, err))
+            })
+            .await
+    }
+
+    /// Streamed bodies cannot be replayed so uploads are never retried here, see [`crate::sync::RSync::with_retry_policy`]
     pub async fn post<S>(&self, url: &str, body: S) -> StorageResult<()>
     where
-        S: TryStream + Send + Sync + 'static,
+        S: TryStream + Send + 'static,
         S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
         bytes::Bytes: From<S::Ok>,
     {
@@ -190,10 +275,15 @@ impl StorageClient {
     //
     // <ObjectStream>
     // --gcs-storage--
-    pub async fn post_multipart<S, M>(&self, url: &str, metadata: &M, body: S) -> StorageResult<()>
+    async fn send_multipart<S, M>(
+        &self,
+        url: &str,
+        metadata: &M,
+        body: S,
+    ) -> StorageResult<reqwest::Response>
     where
         M: Serialize,
-        S: TryStream<Ok = bytes::Bytes> + Send + Sync + 'static,
+        S: TryStream<Ok = bytes::Bytes> + Send + 'static,
         S::Error: Into<Box<dyn std::error::Error + Send + Sync>> + Send + Sync,
     {
         let url = self.resolve_url(url);
@@ -236,31 +326,150 @@ impl StorageClient {
             .await
             .map_err(super::Error::GcsHttpPostMultipartError)?;
 
-        Self::success_response(url.as_str(), response).await?;
+        Self::success_response(url.as_str(), response).await
+    }
+
+    pub async fn post_multipart<S, M>(&self, url: &str, metadata: &M, body: S) -> StorageResult<()>
+    where
+        M: Serialize,
+        S: TryStream<Ok = bytes::Bytes> + Send + 'static,
+        S::Error: Into<Box<dyn std::error::Error + Send + Sync>> + Send + Sync,
+    {
+        self.send_multipart(url, metadata, body).await?;
         Ok(())
     }
 
+    /// [`StorageClient::post_multipart`] returning the uploaded object resource
+    pub async fn post_multipart_as_json<S, M, R>(
+        &self,
+        url: &str,
+        metadata: &M,
+        body: S,
+    ) -> StorageResult<R>
+    where
+        M: Serialize,
+        S: TryStream<Ok = bytes::Bytes> + Send + 'static,
+        S::Error: Into<Box<dyn std::error::Error + Send + Sync>> + Send + Sync,
+        R: DeserializeOwned,
+    {
+        let r: super::super::DeserializedResponse<R> = self
+            .send_multipart(url, metadata, body)
+            .await?
+            .json()
+            .await
+            .map_err(super::Error::GcsHttpJsonResponseError)?;
+        r.into_result()
+            .map_err(|err| super::Error::gcs_unexpected_json::<R>(url, err))
+    }
+
     pub async fn get_as_stream<Q>(
         &self,
         url: &str,
         query: &Q,
     ) -> StorageResult<impl Stream<Item = StorageResult<bytes::Bytes>>>
+    where
+        Q: Serialize,
+    {
+        let response = self.open_stream(url, query, 0, &self.retry_policy).await?;
+        Ok(response
+            .bytes_stream()
+            .map_err(super::Error::GcsHttpBytesStreamError))
+    }
+
+    /// [`StorageClient::get_as_stream`] along with the crc32c of the `x-goog-hash` response header.
+    ///
+    /// The stream is opened once, the caller retries the whole download (ex: sync transfers) so that retries are not nested.
+    /// The crc32c is unknown for objects stored with a content encoding (ex: gzip) since it is the one of the stored content,
+    /// not of the possibly decompressed downloaded content
+    pub async fn get_as_stream_with_crc32c<Q>(
+        &self,
+        url: &str,
+        query: &Q,
+    ) -> StorageResult<(
+        Option<CRC32C>,
+        impl Stream<Item = StorageResult<bytes::Bytes>>,
+    )>
+    where
+        Q: Serialize,
+    {
+        let (crc32c, _, stream) = self.get_range_as_stream_with_crc32c(url, query, 0).await?;
+        Ok((crc32c, stream))
+    }
+
+    /// [`StorageClient::get_as_stream_with_crc32c`] of the content from `offset` (`Range` header), the crc32c is still the one of the whole content.
+    ///
+    /// Returns the offset the stream actually starts at since gcs ignores the range of objects served decompressed and sends
+    /// the whole content instead
+    pub async fn get_range_as_stream_with_crc32c<Q>(
+        &self,
+        url: &str,
+        query: &Q,
+        offset: u64,
+    ) -> StorageResult<(
+        Option<CRC32C>,
+        u64,
+        impl Stream<Item = StorageResult<bytes::Bytes>>,
+    )>
+    where
+        Q: Serialize,
+    {
+        let response = self
+            .open_stream(url, query, offset, &RetryPolicy::no_retry())
+            .await?;
+        let start = match response.status() {
+            reqwest::StatusCode::PARTIAL_CONTENT => offset,
+            _ => 0,
+        };
+        let headers = response.headers();
+        let encoded = headers
+            .get("x-goog-stored-content-encoding")
+            .is_some_and(|x| x != "identity");
+        let crc32c = match encoded {
+            true => None,
+            false => headers
+                .get_all("x-goog-hash")
+                .iter()
+                .filter_map(|x| x.to_str().ok())
+                .find_map(CRC32C::from_goog_hash),
+        };
+        Ok((
+            crc32c,
+            start,
+            response
+                .bytes_stream()
+                .map_err(super::Error::GcsHttpBytesStreamError),
+        ))
+    }
+
+    async fn open_stream<Q>(
+        &self,
+        url: &str,
+        query: &Q,
+        offset: u64,
+        retry_policy: &RetryPolicy,
+    ) -> StorageResult<reqwest::Response>
     where
         Q: Serialize,
     {
         let url = self.resolve_url(url);
+        let url = url.as_str();
 
-        let request = self.with_auth(self.client.client.get(url.as_str())).await?;
-        let response = request
-            .query(query)
-            .send()
+        // only opening the stream is retried, a failure while streaming is returned through the stream
+        retry_policy
+            .retry(Error::is_retryable, || async {
+                let request = self.with_auth(self.client.client.get(url)).await?;
+                let request = match offset {
+                    0 => request,
+                    offset => request.header(reqwest::header::RANGE, format!("bytes={offset}-")),
+                };
+                let response = request
+                    .query(query)
+                    .send()
+                    .await
+                    .map_err(super::Error::GcsHttpGetAsStreamError)?;
+                Self::success_response(url, response).await
+            })
             .await
-            .map_err(super::Error::GcsHttpGetAsStreamError)?;
-
-        Ok(Self::success_response(url.as_str(), response)
-            .await?
-            .bytes_stream()
-            .map_err(super::Error::GcsHttpBytesStreamError))
     }
 
     pub async fn get_as_json<R, Q>(&self, url: &str, query: &Q) -> StorageResult<R>
@@ -269,21 +478,26 @@ impl StorageClient {
         Q: serde::Serialize,
     {
         let url = self.resolve_url(url);
+        let url = url.as_str();
 
-        let request = self
-            .with_auth(self.client.client.get(url.as_str()).query(query))
-            .await?;
-        let response = request
-            .send()
+        self.retry_policy
+            .retry(Error::is_retryable, || async {
+                let request = self
+                    .with_auth(self.client.client.get(url).query(query))
+                    .await?;
+                let response = request
+                    .send()
+                    .await
+                    .map_err(super::Error::GcsHttpJsonRequestError)?;
+                let r: super::super::DeserializedResponse<R> =
+                    Self::success_response(url, response)
+                        .await?
+                        .json()
+                        .await
+                        .map_err(super::Error::GcsHttpJsonResponseError)?;
+                r.into_result()
+                    .map_err(|err| super::Error::gcs_unexpected_json::<R>(url, err))
+            })
             .await
-            .map_err(super::Error::GcsHttpJsonRequestError)?;
-        let r: super::super::DeserializedResponse<R> =
-            Self::success_response(url.as_str(), response)
-                .await?
-                .json()
-                .await
-                .map_err(super::Error::GcsHttpJsonResponseError)?;
-        r.into_result()
-            .map_err(|err| super::Error::gcs_unexpected_json::<R>(url.as_str(), err))
     }
 }
@@ -1,11 +1,14 @@
 mod client;
 mod object;
 mod resources;
+mod retry;
 
 pub use object::ObjectClient;
 pub use resources::object::{
-    Bucket, Metadata, Object, ObjectMetadata, ObjectsListRequest, PartialObject,
+    Bucket, Metadata, Object, ObjectMetadata, ObjectsListRequest, PartialObject, CRC32C, MD5,
 };
+pub(crate) use resources::object::{MtimeMetadata, ObjectWithMtime};
+pub use retry::RetryPolicy;
 
 pub mod credentials {
 
@@ -103,6 +106,12 @@ pub enum Error {
         url: String,
         value: String,
     },
+    /// Response status worth a retry (408, 429 and 5xx)
+    GcsTransientResponse {
+        url: String,
+        status: u16,
+        value: String,
+    },
     GcsUnexpectedJson {
         url: String,
         expected_type: String,
@@ -121,6 +130,10 @@ pub enum Error {
         expected_type: String,
         error: serde_json::Error,
     },
+    /// The `ifGenerationMatch` precondition of the request failed (412), the object changed in the meantime
+    GcsPreconditionFailed {
+        url: String,
+    },
 }
 
 impl std::fmt::Display for Error {
@@ -131,6 +144,26 @@ impl std::fmt::Display for Error {
 impl std::error::Error for Error {}
 
 impl Error {
+    /// Transient errors (408, 429, 5xx, connection and timeout errors) are retried by [`RetryPolicy`]
+    pub fn is_retryable(&self) -> bool {
+        fn is_transient(e: &reqwest::Error) -> bool {
+            e.is_connect() || e.is_timeout() || e.status().is_some_and(is_transient_status)
+        }
+
+        match self {
+            Self::GcsTransientResponse { .. } => true,
+            Self::GcsHttpJsonRequestError(e)
+            | Self::GcsHttpGetAsStreamError(e)
+            | Self::GcsHttpPostMultipartError(e)
+            | Self::GcsHttpPostError(e)
+            | Self::GcsHttpDeleteError(e) => is_transient(e) || e.is_request(),
+            Self::GcsHttpJsonResponseError(e)
+            | Self::GcsHttpBytesStreamError(e)
+            | Self::GcsHttpNoTextError(e) => is_transient(e) || e.is_body(),
+            _ => false,
+        }
+    }
+
     fn gcs_invalid_metadata<T>(error: serde_json::Error) -> Self {
         Self::InvalidMetadata {
             expected_type: std::any::type_name::<T>().to_owned(),
@@ -158,11 +191,37 @@ impl Error {
     }
 }
 
+fn is_transient_status(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::REQUEST_TIMEOUT
+        || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        || status.is_server_error()
+}
+
 pub type StorageResult<T> = std::result::Result<T, Error>;
 
 #[cfg(test)]
 mod tests {
     use crate::storage::Error;
+    #[test]
+    fn test_error_is_retryable() {
+        let transient = |status| Error::GcsTransientResponse {
+            url: "url".to_owned(),
+            status,
+            value: "value".to_owned(),
+        };
+        assert!(transient(429).is_retryable());
+        assert!(transient(503).is_retryable());
+        assert!(!Error::gcs_unexpected_response_error("url", "value").is_retryable());
+        assert!(!Error::GcsResourceNotFound {
+            url: "url".to_owned()
+        }
+        .is_retryable());
+        assert!(!Error::GcsPreconditionFailed {
+            url: "url".to_owned()
+        }
+        .is_retryable());
+    }
+
     #[test]
     fn test_error_display() {
         let e = Error::gcs_unexpected_response_error("url", "value");
@@ -2,10 +2,13 @@ use futures::{Stream, StreamExt, TryStream, TryStreamExt};
 
 use crate::oauth2::token::TokenGenerator;
 
+use serde::{de::DeserializeOwned, Serialize};
+
 use super::{
     client::StorageClient,
-    resources::object::{ObjectMetadata, Objects},
-    Bucket, StorageResult, {Object, ObjectsListRequest, PartialObject},
+    resources::object::{ObjectMetadata, Objects, RewriteResponse, UploadMetadata},
+    Bucket, MtimeMetadata, RetryPolicy, StorageResult, CRC32C,
+    {Object, ObjectsListRequest, PartialObject},
 };
 
 pub struct ObjectClient {
@@ -25,19 +28,70 @@ impl ObjectClient {
         }
     }
 
+    /// Retry policy applied to idempotent requests (get, list, download and delete guarded by a generation), [`RetryPolicy::default`] by default.
+    ///
+    /// Uploads are not retried since the given stream cannot be replayed
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.storage_client = self.storage_client.with_retry_policy(retry_policy);
+        self
+    }
+
     pub async fn get(&self, o: &Object, fields: &str) -> StorageResult<PartialObject> {
+        self.get_as(o, fields).await
+    }
+
+    /// [`ObjectClient::get`] deserialized as `T` (ex: [`super::ObjectWithMtime`])
+    pub(crate) async fn get_as<T>(&self, o: &Object, fields: &str) -> StorageResult<T>
+    where
+        T: DeserializeOwned,
+    {
         let url = o.url();
         self.storage_client
             .get_as_json(url.as_str(), &[("fields", fields)])
             .await
     }
 
+    /// Unconditional delete, it is not retried (see [`ObjectClient::delete_if_generation_match`])
     pub async fn delete(&self, o: &Object) -> StorageResult<String> {
         let url = o.url();
         self.storage_client.delete(&url).await?;
         super::StorageResult::Ok(url)
     }
 
+    /// Delete the object only while its generation is `generation`, it is retried.
+    ///
+    /// Fails with [`super::Error::GcsPreconditionFailed`] when the object has been replaced since
+    pub async fn delete_if_generation_match(
+        &self,
+        o: &Object,
+        generation: i64,
+    ) -> StorageResult<String> {
+        let url = o.url();
+        self.storage_client
+            .delete_if_generation_match(&url, generation)
+            .await?;
+        super::StorageResult::Ok(url)
+    }
+
+    /// Server-side copy of `o` to `dest` with its metadata, large objects are copied in several requests
+    pub async fn rewrite(&self, o: &Object, dest: &Object) -> StorageResult<()> {
+        let url = o.rewrite_url(dest);
+        let mut rewrite_token: Option<String> = None;
+        loop {
+            let mut query = vec![("fields", "done,rewriteToken".to_owned())];
+            if let Some(token) = rewrite_token {
+                query.push(("rewriteToken", token));
+            }
+            let response: RewriteResponse = self.storage_client.post_as_json(&url, &query).await?;
+            if response.done {
+                return Ok(());
+            }
+            rewrite_token = Some(response.rewrite_token.ok_or_else(|| {
+                super::Error::gcs_unexpected_response_error(&url, "missing rewriteToken")
+            })?);
+        }
+    }
+
     pub async fn download(
         &self,
         o: &Object,
@@ -48,9 +102,43 @@ impl ObjectClient {
             .await
     }
 
+    /// [`ObjectClient::download`] along with the crc32c gcs sends for the content (`x-goog-hash` header), when it is the one of the downloaded content.
+    ///
+    /// Unlike [`ObjectClient::download`] it is not retried, the caller is expected to retry the whole download (ex: sync transfers)
+    pub async fn download_with_crc32c(
+        &self,
+        o: &Object,
+    ) -> StorageResult<(
+        Option<CRC32C>,
+        impl Stream<Item = StorageResult<bytes::Bytes>>,
+    )> {
+        let url = o.url();
+        self.storage_client
+            .get_as_stream_with_crc32c(&url, &[("alt", "media")])
+            .await
+    }
+
+    /// [`ObjectClient::download_with_crc32c`] of the content from `offset`, the crc32c is still the one of the whole content.
+    ///
+    /// Also returns the offset the downloaded content actually starts at, `0` when gcs sends the whole content instead (ex: objects served decompressed)
+    pub async fn download_range_with_crc32c(
+        &self,
+        o: &Object,
+        offset: u64,
+    ) -> StorageResult<(
+        Option<CRC32C>,
+        u64,
+        impl Stream<Item = StorageResult<bytes::Bytes>>,
+    )> {
+        let url = o.url();
+        self.storage_client
+            .get_range_as_stream_with_crc32c(&url, &[("alt", "media")], offset)
+            .await
+    }
+
     pub async fn upload<S>(&self, o: &Object, stream: S) -> StorageResult<()>
     where
-        S: futures::TryStream + Send + Sync + 'static,
+        S: futures::TryStream + Send + 'static,
         S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
         bytes::Bytes: From<S::Ok>,
     {
@@ -66,7 +154,7 @@ impl ObjectClient {
         stream: S,
     ) -> StorageResult<()>
     where
-        S: TryStream<Ok = bytes::Bytes> + Send + Sync + 'static,
+        S: TryStream<Ok = bytes::Bytes> + Send + 'static,
         S::Error: Into<Box<dyn std::error::Error + Send + Sync>> + Send + Sync,
     {
         let url = o.upload_url("multipart");
@@ -74,6 +162,70 @@ impl ObjectClient {
         super::StorageResult::Ok(())
     }
 
+    /// [`ObjectClient::upload_with_metadata`] returning the `fields` of the uploaded object (ex: `crc32c` to verify the upload).
+    ///
+    /// gcs rejects the upload when the content does not match `crc32c`, when known. With `if_generation_match` only this
+    /// object generation is replaced, `0` when the object must not exist.
+    /// Such an upload can safely be replayed, it fails with [`super::Error::GcsPreconditionFailed`] when the object changed in the meantime
+    pub async fn upload_with_metadata_fields<S>(
+        &self,
+        m: &ObjectMetadata,
+        o: &Object,
+        crc32c: Option<&CRC32C>,
+        if_generation_match: Option<i64>,
+        fields: &str,
+        stream: S,
+    ) -> StorageResult<PartialObject>
+    where
+        S: TryStream<Ok = bytes::Bytes> + Send + 'static,
+        S::Error: Into<Box<dyn std::error::Error + Send + Sync>> + Send + Sync,
+    {
+        let m = UploadMetadata::new(&m.metadata, crc32c);
+        self.upload_fields(&m, o, if_generation_match, fields, stream)
+            .await
+    }
+
+    /// [`ObjectClient::upload_with_metadata_fields`] keeping the sub-second part of the modification time, see [`MtimeMetadata`]
+    pub(crate) async fn upload_with_mtime_fields<S>(
+        &self,
+        m: &MtimeMetadata,
+        o: &Object,
+        crc32c: Option<&CRC32C>,
+        if_generation_match: Option<i64>,
+        fields: &str,
+        stream: S,
+    ) -> StorageResult<PartialObject>
+    where
+        S: TryStream<Ok = bytes::Bytes> + Send + 'static,
+        S::Error: Into<Box<dyn std::error::Error + Send + Sync>> + Send + Sync,
+    {
+        let m = UploadMetadata::new(m, crc32c);
+        self.upload_fields(&m, o, if_generation_match, fields, stream)
+            .await
+    }
+
+    async fn upload_fields<M, S>(
+        &self,
+        m: &UploadMetadata<'_, M>,
+        o: &Object,
+        if_generation_match: Option<i64>,
+        fields: &str,
+        stream: S,
+    ) -> StorageResult<PartialObject>
+    where
+        M: Serialize,
+        S: TryStream<Ok = bytes::Bytes> + Send + 'static,
+        S::Error: Into<Box<dyn std::error::Error + Send + Sync>> + Send + Sync,
+    {
+        let mut url = format!("{}&fields={fields}", o.upload_url("multipart"));
+        if let Some(generation) = if_generation_match {
+            url.push_str(&format!("&ifGenerationMatch={generation}"));
+        }
+        self.storage_client
+            .post_multipart_as_json(&url, m, stream)
+            .await
+    }
+
     fn list_url(bucket: &str) -> String {
         format!("{}/o", Bucket::new(bucket).url())
     }
@@ -100,6 +252,18 @@ impl ObjectClient {
         bucket: &str,
         objects_list_request: &ObjectsListRequest,
     ) -> impl Stream<Item = StorageResult<PartialObject>> + '_ {
+        self.list_as(bucket, objects_list_request).await
+    }
+
+    /// [`ObjectClient::list`] with the items deserialized as `T` (ex: [`super::ObjectWithMtime`])
+    pub(crate) async fn list_as<T>(
+        &self,
+        bucket: &str,
+        objects_list_request: &ObjectsListRequest,
+    ) -> impl Stream<Item = StorageResult<T>> + '_
+    where
+        T: DeserializeOwned + 'static,
+    {
         let objects_list_request = objects_list_request.to_owned();
         let url = Self::list_url(bucket);
         futures::stream::try_unfold(
@@ -108,7 +272,7 @@ impl ObjectClient {
                 match state {
                     None => Ok(None),
                     Some(state) => {
-                        let objects: Objects =
+                        let objects: Objects<T> =
                             self.storage_client.get_as_json(&url, &state).await?;
                         let items = futures::stream::iter(objects.items).map(Ok);
                         match objects.next_page_token {
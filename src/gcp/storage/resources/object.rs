@@ -33,24 +33,86 @@ pub struct ObjectsListRequest {
 pub struct ObjectMetadata {
     pub metadata: Metadata,
 }
+
+/// Multipart upload metadata, the custom metadata (ex: [`Metadata`]) along with the crc32c of the uploaded content when known
+#[derive(Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadMetadata<'a, M = Metadata> {
+    pub metadata: &'a M,
+    /// Base64 encoded crc32c (see [`CRC32C::to_base64`]), gcs rejects an upload not matching it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crc32c: Option<String>,
+}
+
+impl<'a, M> UploadMetadata<'a, M> {
+    pub fn new(metadata: &'a M, crc32c: Option<&CRC32C>) -> Self {
+        Self {
+            metadata,
+            crc32c: crc32c.map(CRC32C::to_base64),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize, Default, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Metadata {
     #[serde(
         rename = "goog-reserved-file-mtime",
-        deserialize_with = "from_string_option"
+        deserialize_with = "from_string_option",
+        skip_serializing_if = "Option::is_none"
     )] //compat with gsutil rsync
     pub modification_time: Option<i64>,
 }
 
+/// [`Metadata`] along with the sub-second part of the modification time written by gcs-rsync.
+///
+/// Whole seconds stay in [`Metadata::modification_time`] for gsutil, the nanoseconds are always written by gcs-rsync
+/// so that their absence means a whole seconds mtime (ex: objects written by gsutil)
+#[derive(Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize, Default, Clone)]
+pub struct MtimeMetadata {
+    #[serde(flatten)]
+    pub metadata: Metadata,
+    #[serde(
+        rename = "gcs-rsync-file-mtime-nanos",
+        default,
+        deserialize_with = "from_string_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub modification_time_nanos: Option<u32>,
+}
+
+impl MtimeMetadata {
+    pub fn with_modification_date_time(mtime: chrono::DateTime<chrono::Utc>) -> Self {
+        Self {
+            metadata: Metadata {
+                modification_time: Some(mtime.timestamp()),
+            },
+            modification_time_nanos: Some(mtime.timestamp_subsec_nanos()),
+        }
+    }
+
+    /// Modification time with the best available precision, whole seconds when only the gsutil key is set
+    pub fn modification_date_time(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        use chrono::TimeZone;
+        let secs = self.metadata.modification_time?;
+        let nanos = self.modification_time_nanos.unwrap_or_default();
+        chrono::Utc.timestamp_opt(secs, nanos).single()
+    }
+
+    /// Only the whole seconds are known (ex: object written by gsutil)
+    pub fn is_in_seconds(&self) -> bool {
+        self.metadata.modification_time.is_some() && self.modification_time_nanos.is_none()
+    }
+}
+
 /// ObjectList response
 #[derive(Debug, serde::Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
-pub struct Objects {
+pub struct Objects<T = PartialObject> {
     pub kind: Option<String>,
 
     #[serde(default = "Vec::new")]
-    pub items: Vec<PartialObject>,
+    pub items: Vec<T>,
 
     #[serde(default = "Vec::new")]
     pub prefixes: Vec<String>,
@@ -58,6 +120,16 @@ pub struct Objects {
     pub next_page_token: Option<String>,
 }
 
+/// Rewrite response, a large rewrite is done in several calls resumed with `rewrite_token`.
+///
+/// See [GCS rewrite API reference](https://cloud.google.com/storage/docs/json_api/v1/objects/rewrite)
+#[derive(Debug, serde::Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct RewriteResponse {
+    pub done: bool,
+    pub rewrite_token: Option<String>,
+}
+
 #[derive(Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Object {
@@ -125,6 +197,15 @@ impl Object {
         )
     }
 
+    pub fn rewrite_url(&self, dest: &Object) -> String {
+        format!(
+            "{}/rewriteTo/b/{}/o/{}",
+            self.url(),
+            percent_encode(&dest.bucket),
+            percent_encode(&dest.name)
+        )
+    }
+
     pub fn upload_url(&self, upload_type: &str) -> String {
         format!(
             "{}/b/{}/o?uploadType={}&name={}",
@@ -186,6 +267,9 @@ pub struct PartialObject {
     pub storage_class: Option<String>,
     #[serde(default, deserialize_with = "from_string_option")]
     pub size: Option<u64>,
+    /// Version of the object content, see <https://cloud.google.com/storage/docs/metadata#generation-number>
+    #[serde(default, deserialize_with = "from_string_option")]
+    pub generation: Option<i64>,
     pub media_link: Option<String>,
     pub content_encoding: Option<String>,
     pub content_disposition: Option<String>,
@@ -194,9 +278,20 @@ pub struct PartialObject {
     pub metadata: Option<Metadata>,
     #[serde(default, deserialize_with = "from_string_option")]
     pub crc32c: Option<CRC32C>,
+    /// Only set for non composite objects
+    #[serde(default, deserialize_with = "from_string_option")]
+    pub md5_hash: Option<MD5>,
     pub etag: Option<String>,
 }
 
+/// [`PartialObject`] with its [`MtimeMetadata`], [`PartialObject::metadata`] is always `None`
+#[derive(Debug, PartialEq, Eq, serde::Deserialize, Default)]
+pub struct ObjectWithMtime {
+    #[serde(flatten)]
+    pub object: PartialObject,
+    pub metadata: Option<MtimeMetadata>,
+}
+
 #[derive(Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct CRC32C {
@@ -210,6 +305,19 @@ impl CRC32C {
     pub fn to_u32(&self) -> u32 {
         self.value
     }
+
+    /// Big endian base64 encoding used by gcs, see [`FromStr`]
+    pub fn to_base64(&self) -> String {
+        base64::engine::general_purpose::STANDARD.encode(self.value.to_be_bytes())
+    }
+
+    /// crc32c of a gcs `x-goog-hash` header value (ex: `crc32c=n03x6A==,md5=Ojk9c3dhfxgoKVVHYwFbHQ==`)
+    pub fn from_goog_hash(header: &str) -> Option<Self> {
+        header
+            .split(',')
+            .find_map(|x| x.trim().strip_prefix("crc32c="))
+            .and_then(|x| Self::from_str(x).ok())
+    }
 }
 #[derive(Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum Base64EncodedCRC32CError {
@@ -238,6 +346,51 @@ impl FromStr for CRC32C {
     }
 }
 
+#[derive(Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct MD5 {
+    value: [u8; 16],
+}
+
+impl MD5 {
+    pub fn new(value: [u8; 16]) -> Self {
+        Self { value }
+    }
+    pub fn to_bytes(&self) -> [u8; 16] {
+        self.value
+    }
+    /// Lowercase hex digest like md5sum
+    pub fn to_hex(&self) -> String {
+        self.value.iter().map(|x| format!("{x:02x}")).collect()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Base64EncodedMD5Error {
+    Base64DecodeError(String),
+    Base64ToMD5Error(Vec<u8>),
+}
+
+impl Display for Base64EncodedMD5Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl FromStr for MD5 {
+    type Err = Base64EncodedMD5Error;
+
+    fn from_str(base64md5: &str) -> Result<Self, Self::Err> {
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(base64md5)
+            .map_err(|err| Base64EncodedMD5Error::Base64DecodeError(format!("{:?}", err)))?;
+        let md5 = decoded
+            .try_into()
+            .map_err(Base64EncodedMD5Error::Base64ToMD5Error)?;
+        Ok(MD5::new(md5))
+    }
+}
+
 fn from_string_option<'de, T, D>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
 where
     T: std::str::FromStr,
@@ -266,7 +419,7 @@ mod tests {
 
     use crate::storage::{Bucket, Error, Object};
 
-    use super::PartialObject;
+    use super::{Metadata, MtimeMetadata, ObjectWithMtime, PartialObject, UploadMetadata, CRC32C};
 
     #[test]
     fn fn_gs_url_parsing_to_object() {
@@ -302,6 +455,16 @@ mod tests {
         assert_eq!("storage/v1/b/hello%2Fhello/o/world%2Fworld", o.url());
     }
 
+    #[test]
+    fn test_object_rewrite_url() {
+        let o = Object::new("hello", "world/a.txt").unwrap();
+        let dest = Object::new("hello", "backup/world/a.txt").unwrap();
+        assert_eq!(
+            "storage/v1/b/hello/o/world%2Fa%2Etxt/rewriteTo/b/hello/o/backup%2Fworld%2Fa%2Etxt",
+            o.rewrite_url(&dest)
+        );
+    }
+
     #[test]
     fn test_object_upload_url() {
         let o = Object::new("hello/hello", "world/world").unwrap();
@@ -330,4 +493,80 @@ mod tests {
             p.try_into().unwrap()
         );
     }
+
+    #[test]
+    fn test_partial_object_md5_hash() {
+        let p: PartialObject =
+            serde_json::from_str(r#"{"name":"hello","md5Hash":"XUFAKrxLKna5cZ2REBfFkg=="}"#)
+                .unwrap();
+        assert_eq!(
+            "5d41402abc4b2a76b9719d911017c592",
+            p.md5_hash.unwrap().to_hex()
+        );
+
+        let composite: PartialObject = serde_json::from_str(r#"{"name":"hello"}"#).unwrap();
+        assert_eq!(None, composite.md5_hash);
+    }
+
+    #[test]
+    fn test_mtime_metadata_modification_date_time() {
+        use chrono::TimeZone;
+
+        let gsutil: MtimeMetadata =
+            serde_json::from_str(r#"{"goog-reserved-file-mtime":"1700000000"}"#).unwrap();
+        assert_eq!(
+            chrono::Utc.timestamp_opt(1700000000, 0).unwrap(),
+            gsutil.modification_date_time().unwrap()
+        );
+        assert!(gsutil.is_in_seconds());
+
+        let mtime = chrono::Utc.timestamp_opt(1700000000, 123_456_789).unwrap();
+        let metadata = MtimeMetadata::with_modification_date_time(mtime);
+        let json = serde_json::to_value(&metadata).unwrap();
+        assert_eq!(1700000000, json["goog-reserved-file-mtime"]);
+        assert_eq!(123_456_789, json["gcs-rsync-file-mtime-nanos"]);
+        let restored: ObjectWithMtime = serde_json::from_str(
+            r#"{"name":"hello","metadata":{"goog-reserved-file-mtime":"1700000000","gcs-rsync-file-mtime-nanos":"123456789"}}"#,
+        )
+        .unwrap();
+        assert_eq!(Some("hello".to_owned()), restored.object.name);
+        let restored = restored.metadata.unwrap();
+        assert_eq!(Some(mtime), restored.modification_date_time());
+        assert!(!restored.is_in_seconds());
+
+        let whole =
+            MtimeMetadata::with_modification_date_time(gsutil.modification_date_time().unwrap());
+        assert_eq!(
+            0,
+            serde_json::to_value(whole).unwrap()["gcs-rsync-file-mtime-nanos"]
+        );
+    }
+
+    #[test]
+    fn test_upload_metadata_crc32c() {
+        let crc32c = CRC32C::new(crc32c::crc32c(b"hello"));
+        let encoded = crc32c.to_base64();
+        assert_eq!(crc32c, CRC32C::from_str(&encoded).unwrap());
+
+        let m = Metadata::default();
+        let json = serde_json::to_value(UploadMetadata::new(&m, Some(&crc32c))).unwrap();
+        assert_eq!(encoded, json["crc32c"]);
+        assert_eq!(
+            serde_json::json!({"metadata": {}}),
+            serde_json::to_value(UploadMetadata::new(&m, None)).unwrap(),
+            "unknown fields are not sent"
+        );
+    }
+
+    #[test]
+    fn test_crc32c_from_goog_hash() {
+        let crc32c = CRC32C::new(crc32c::crc32c(b"hello"));
+        let header = format!(
+            "crc32c={}, md5=XUFAKrxLKna5cZ2REBfFkg==",
+            crc32c.to_base64()
+        );
+        assert_eq!(Some(crc32c), CRC32C::from_goog_hash(&header));
+        assert_eq!(None, CRC32C::from_goog_hash("md5=XUFAKrxLKna5cZ2REBfFkg=="));
+        assert_eq!(None, CRC32C::from_goog_hash("crc32c=invalid"));
+    }
 }
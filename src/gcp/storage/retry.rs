@@ -0,0 +1,183 @@
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+/// Retry policy for transient errors (see [`super::Error::is_retryable`]) following <https://cloud.google.com/storage/docs/retry-strategy>.
+///
+/// Each retry waits for an exponential backoff (capped to the max backoff) with a random jitter between 50% and 100% of the backoff.
+/// Retries stop after `max_attempts` attempts (the first one included) or when the next backoff would exceed the deadline.
+///
+/// Example
+/// ```rust
+/// use std::time::Duration;
+///
+/// use gcs_rsync::storage::RetryPolicy;
+///
+/// let retry_policy = RetryPolicy::default()
+///     .with_max_attempts(3)
+///     .with_deadline(Some(Duration::from_secs(30)));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    multiplier: f64,
+    deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 6,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(32),
+            multiplier: 2.0,
+            deadline: Some(Duration::from_secs(120)),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A single attempt, errors are returned as is
+    pub fn no_retry() -> Self {
+        Self::default().with_max_attempts(1)
+    }
+
+    /// Max number of attempts including the first one, at least 1
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn with_backoff(
+        mut self,
+        initial_backoff: Duration,
+        max_backoff: Duration,
+        multiplier: f64,
+    ) -> Self {
+        self.initial_backoff = initial_backoff;
+        self.max_backoff = max_backoff;
+        self.multiplier = multiplier;
+        self
+    }
+
+    /// Overall time budget from the first attempt, `None` to only rely on the max attempts
+    pub fn with_deadline(mut self, deadline: Option<Duration>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self.initial_backoff.as_secs_f64() * self.multiplier.powi(retry as i32);
+        let backoff = backoff.min(self.max_backoff.as_secs_f64());
+        Duration::from_secs_f64(backoff * (0.5 + fastrand::f64() / 2.0))
+    }
+
+    /// Run `f` until it succeeds, fails with a non retryable error or the policy gives up
+    pub(crate) async fn retry<T, E, R, F, Fut>(&self, is_retryable: R, mut f: F) -> Result<T, E>
+    where
+        R: Fn(&E) -> bool,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let started_at = Instant::now();
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Err(e) if attempt < self.max_attempts && is_retryable(&e) => {
+                    let backoff = self.backoff(attempt - 1);
+                    let deadline_exceeded = self
+                        .deadline
+                        .is_some_and(|deadline| started_at.elapsed() + backoff > deadline);
+                    if deadline_exceeded {
+                        return Err(e);
+                    }
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, time::Duration};
+
+    use super::RetryPolicy;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy::default().with_backoff(Duration::ZERO, Duration::ZERO, 2.0)
+    }
+
+    async fn attempts(policy: &RetryPolicy, retryable: bool) -> (u32, Result<(), u32>) {
+        let attempts = Cell::new(0);
+        let result = policy
+            .retry(
+                |_| retryable,
+                || {
+                    attempts.set(attempts.get() + 1);
+                    futures::future::ready(Err(attempts.get()))
+                },
+            )
+            .await;
+        (attempts.get(), result)
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::default().with_backoff(
+            Duration::from_secs(1),
+            Duration::from_secs(10),
+            2.0,
+        );
+        for (retry, max) in [
+            (0, 1.0),
+            (1, 2.0),
+            (2, 4.0),
+            (3, 8.0),
+            (4, 10.0),
+            (10, 10.0),
+        ] {
+            let backoff = policy.backoff(retry).as_secs_f64();
+            assert!(
+                backoff >= max / 2.0 && backoff <= max,
+                "retry {retry}: {backoff}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        assert_eq!((6, Err(6)), attempts(&policy(), true).await);
+        assert_eq!(
+            (3, Err(3)),
+            attempts(&policy().with_max_attempts(3), true).await
+        );
+        assert_eq!((1, Err(1)), attempts(&policy(), false).await);
+        assert_eq!((1, Err(1)), attempts(&RetryPolicy::no_retry(), true).await);
+
+        let attempts = Cell::new(0);
+        let result = policy()
+            .retry(
+                |_: &()| true,
+                || {
+                    attempts.set(attempts.get() + 1);
+                    futures::future::ready(if attempts.get() < 3 { Err(()) } else { Ok(()) })
+                },
+            )
+            .await;
+        assert_eq!((3, Ok(())), (attempts.get(), result));
+    }
+
+    #[tokio::test]
+    async fn test_retry_deadline() {
+        let policy = RetryPolicy::default()
+            .with_backoff(Duration::from_secs(60), Duration::from_secs(60), 2.0)
+            .with_deadline(Some(Duration::from_secs(1)));
+        assert_eq!((1, Err(1)), attempts(&policy, true).await);
+    }
+}
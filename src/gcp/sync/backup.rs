@@ -0,0 +1,179 @@
+use std::ops::Not;
+
+use super::{Operation, RSync, RSyncResult, RelativePath};
+
+/// Where [`RSync`] keeps the previous version of the destination entries it deletes or overwrites, see [`RSync::with_backup`].
+///
+/// An entry `a/b.txt` is backed up to `<dir>/<timestamp>/a/b.txt<suffix>` relatively to the destination root,
+/// the timestamp (ex: `20240131T235959Z`) is the creation time of the [`Backup`] so that all the backups of a run end up together
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backup {
+    dir: String,
+    timestamp: String,
+    suffix: String,
+}
+
+impl Backup {
+    /// Backup directory or prefix relative to the destination root (ex: `.backup`)
+    pub fn new(dir: &str) -> RSyncResult<Self> {
+        let dir = RelativePath::new(dir.trim_matches('/'))?;
+        Ok(Self {
+            dir: dir.path,
+            timestamp: Self::format(chrono::Utc::now()),
+            suffix: String::new(),
+        })
+    }
+
+    /// Replace the timestamp of the backup prefix
+    pub fn with_timestamp(mut self, timestamp: chrono::DateTime<chrono::Utc>) -> Self {
+        self.timestamp = Self::format(timestamp);
+        self
+    }
+
+    /// Append a suffix to the backed up entries (ex: `~` or `.bak`)
+    pub fn with_suffix(mut self, suffix: &str) -> Self {
+        suffix.clone_into(&mut self.suffix);
+        self
+    }
+
+    fn format(timestamp: chrono::DateTime<chrono::Utc>) -> String {
+        timestamp.format("%Y%m%dT%H%M%SZ").to_string()
+    }
+
+    fn backup_path(&self, path: &RelativePath) -> RSyncResult<RelativePath> {
+        let path = path.as_str().trim_start_matches('/');
+        RelativePath::new(&format!(
+            "{}/{}/{}{}",
+            self.dir, self.timestamp, path, self.suffix
+        ))
+    }
+
+    /// Entries under the backup directory are neither synced nor deleted
+    fn contains(&self, path: &RelativePath) -> bool {
+        path.as_str()
+            .trim_start_matches('/')
+            .strip_prefix(self.dir.as_str())
+            .is_some_and(|x| x.starts_with('/'))
+    }
+}
+
+impl RSync {
+    /// Keep the destination entries deleted by mirror or overwritten by sync under the [`Backup`] directory.
+    ///
+    /// gcs entries are copied server-side, overwritten fs entries are hard linked and deleted ones renamed. Backed up entries are reported as
+    /// [`super::RSyncStatus::UpdatedWithBackup`] and [`super::RMirrorStatus::DeletedWithBackup`]
+    pub fn with_backup(mut self, backup: Backup) -> Self {
+        self.backup = Some(backup);
+        self
+    }
+
+    pub(super) fn is_backup(&self, path: &RelativePath) -> bool {
+        self.backup.as_ref().is_some_and(|x| x.contains(path))
+    }
+
+    /// Back up a destination entry about to be deleted or overwritten, `None` when there is nothing to back up
+    pub(super) async fn backup_entry(
+        &self,
+        path: &RelativePath,
+        before_delete: bool,
+    ) -> RSyncResult<Option<RelativePath>> {
+        let backup = match &self.backup {
+            Some(backup) if self.dry_run.not() => backup,
+            _ => return Ok(None),
+        };
+        let backup_path = backup.backup_path(path)?;
+        let backed_up = if before_delete {
+            self.dest.backup_before_delete(path, &backup_path).await
+        } else {
+            self.dest.backup(path, &backup_path).await
+        };
+        let backed_up = backed_up.map_err(|e| e.entry_failed(path, Operation::Backup))?;
+        Ok(backed_up.then_some(backup_path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use crate::sync::testing::{already_synced, mirror, mtime, not_deleted, path, rsync, synced};
+    use crate::sync::{MemoryStore, RMirrorStatus, RSyncStatus};
+
+    use super::Backup;
+
+    #[test]
+    fn test_backup_path() {
+        let backup = Backup::new("/.backup/")
+            .unwrap()
+            .with_timestamp(chrono::Utc.timestamp_opt(1706745599, 0).unwrap());
+        assert_eq!(
+            path(".backup/20240131T235959Z/a/b.txt"),
+            backup.backup_path(&path("a/b.txt")).unwrap()
+        );
+        assert_eq!(
+            path(".backup/20240131T235959Z/a/b.txt~"),
+            backup
+                .with_suffix("~")
+                .backup_path(&path("/a/b.txt"))
+                .unwrap()
+        );
+        assert!(Backup::new("/").is_err());
+    }
+
+    #[test]
+    fn test_contains() {
+        let backup = Backup::new(".backup").unwrap();
+        assert!(backup.contains(&path(".backup/20240131T235959Z/a.txt")));
+        assert!(!backup.contains(&path(".backup.txt")));
+        assert!(!backup.contains(&path("a/.backup/b.txt")));
+    }
+
+    #[tokio::test]
+    async fn test_mirror_backup() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        source.insert("a.txt", "new", mtime(2)).unwrap();
+        dest.insert("a.txt", "old", mtime(1)).unwrap();
+        dest.insert("extra.txt", "extra", mtime(1)).unwrap();
+
+        let backup = Backup::new(".backup")
+            .unwrap()
+            .with_timestamp(chrono::Utc.timestamp_opt(1706745599, 0).unwrap())
+            .with_suffix("~");
+        let dry_run = rsync(&source, &dest)
+            .with_backup(backup.clone())
+            .with_dry_run(true);
+        mirror(&dry_run).await;
+        assert_eq!(vec![path("a.txt"), path("extra.txt")], dest.paths());
+
+        let with_backup = rsync(&source, &dest).with_backup(backup);
+        assert_eq!(
+            vec![
+                synced(RSyncStatus::UpdatedWithBackup {
+                    reason: "different size or mtime".to_owned(),
+                    path: path("a.txt"),
+                    backup: path(".backup/20240131T235959Z/a.txt~"),
+                }),
+                not_deleted("a.txt"),
+                RMirrorStatus::DeletedWithBackup {
+                    path: path("extra.txt"),
+                    backup: path(".backup/20240131T235959Z/extra.txt~"),
+                },
+            ],
+            mirror(&with_backup).await
+        );
+        let old = dest.get(".backup/20240131T235959Z/a.txt~").unwrap();
+        assert_eq!((&b"old"[..], mtime(1)), (old.content.as_ref(), old.mtime));
+        assert_eq!("new", dest.get("a.txt").unwrap().content);
+
+        assert_eq!(
+            vec![
+                synced(already_synced("same mtime and size", "a.txt")),
+                not_deleted("a.txt"),
+            ],
+            mirror(&with_backup).await,
+            "backups are neither synced nor deleted"
+        );
+        assert_eq!(3, dest.paths().len());
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::StreamExt;
+
+use super::ByteStream;
+
+/// Bandwidth limit in bytes per second shared by all the transfers using it, cloning a limiter shares the same budget.
+///
+/// Chunks are delayed so that the bytes going through the limiter never exceed the rate on average
+#[derive(Debug, Clone)]
+pub struct BandwidthLimiter {
+    bytes_per_second: u64,
+    next: Arc<Mutex<Instant>>,
+}
+
+impl BandwidthLimiter {
+    /// `bytes_per_second` has to be greater than 0
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second: bytes_per_second.max(1),
+            next: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second
+    }
+
+    /// Reserve the time slot needed to send `len` bytes and return how long to wait before sending them
+    fn reserve(&self, len: usize) -> Duration {
+        let now = Instant::now();
+        let mut next = self.next.lock().unwrap_or_else(|e| e.into_inner());
+        let start = (*next).max(now);
+        *next = start + Duration::from_secs_f64(len as f64 / self.bytes_per_second as f64);
+        *next - now
+    }
+
+    async fn acquire(&self, len: usize) {
+        let delay = self.reserve(len);
+        if delay > Duration::ZERO {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    pub(super) fn throttle(&self, stream: ByteStream) -> ByteStream {
+        let limiter = self.clone();
+        stream
+            .then(move |chunk| {
+                let limiter = limiter.clone();
+                async move {
+                    if let Ok(chunk) = &chunk {
+                        limiter.acquire(chunk.len()).await;
+                    }
+                    chunk
+                }
+            })
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::sync::testing::{created, mtime, rsync, sync};
+    use crate::sync::MemoryStore;
+
+    use super::BandwidthLimiter;
+
+    #[test]
+    fn test_reserve() {
+        let limiter = BandwidthLimiter::new(1000);
+        let shared = limiter.clone();
+        let approx = |actual: Duration, expected: u64| {
+            let actual = actual.as_millis() as u64;
+            assert!(
+                actual <= expected && actual + 50 >= expected,
+                "{actual}ms instead of {expected}ms"
+            );
+        };
+        approx(limiter.reserve(100), 100);
+        approx(shared.reserve(200), 300);
+        approx(limiter.reserve(0), 300);
+    }
+
+    #[tokio::test]
+    async fn test_sync_bandwidth_limit_is_shared() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        source.insert("a.bin", vec![0; 25_000], mtime(1)).unwrap();
+        source.insert("b.bin", vec![1; 25_000], mtime(1)).unwrap();
+        let rsync = rsync(&source, &dest).with_bandwidth_limiter(BandwidthLimiter::new(100_000));
+
+        let started_at = std::time::Instant::now();
+        assert_eq!(vec![created("a.bin"), created("b.bin")], sync(&rsync).await);
+        let elapsed = started_at.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(450) && elapsed < Duration::from_secs(5),
+            "50KB at 100KB/s took {elapsed:?}"
+        );
+    }
+}
@@ -0,0 +1,733 @@
+use std::{collections::BTreeMap, collections::BTreeSet, ops::Not, str::FromStr};
+
+use futures::{StreamExt, TryStreamExt};
+
+use super::{
+    EntryState, Operation, RSync, RSyncError, RSyncResult, RSyncStatus, ReaderWriterBackend,
+    RelativePath,
+};
+
+/// Source and destination states of an entry as recorded at the end of the last [`RSync::bisync`] run
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BisyncEntryState {
+    pub source: EntryState,
+    pub dest: EntryState,
+}
+
+/// State snapshot of the last [`RSync::bisync`] run, the default (empty) state is used for the first run.
+///
+/// The snapshot has to be stored (serde) between runs, it is how changes and deletions are detected on both sides
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BisyncState {
+    pub entries: BTreeMap<RelativePath, BisyncEntryState>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BisyncSide {
+    Source,
+    Dest,
+}
+
+impl BisyncSide {
+    fn other(self) -> Self {
+        match self {
+            Self::Source => Self::Dest,
+            Self::Dest => Self::Source,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Source => "source",
+            Self::Dest => "dest",
+        }
+    }
+}
+
+/// What to do when the same path changed on both sides since the last run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConflictResolution {
+    /// The most recently modified side overwrites the other one, source wins on ties
+    NewerWins,
+    /// The source version is kept under the original path while the destination version is copied to both sides
+    /// under a path with the suffix inserted before the file extension (ex: `notes.conflict.txt`).
+    ///
+    /// Previous conflict copies are never overwritten, a counter is appended to the suffix when the copy path is taken (ex: `notes.conflict-1.txt`)
+    KeepBoth { suffix: String },
+    /// Nothing is changed and the entry fails with [`RSyncError::BisyncConflict`] until it is resolved by hand
+    Fail,
+}
+
+impl Default for ConflictResolution {
+    fn default() -> Self {
+        Self::KeepBoth {
+            suffix: ".conflict".to_owned(),
+        }
+    }
+}
+
+impl FromStr for ConflictResolution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "newer-wins" => Ok(Self::NewerWins),
+            "keep-both" => Ok(Self::default()),
+            "fail" => Ok(Self::Fail),
+            _ => Err(format!(
+                "unknown conflict resolution {s}, expected newer-wins, keep-both or fail"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BisyncStatus {
+    /// A source change written to the destination
+    ToDest(RSyncStatus),
+    /// A destination change written to the source
+    ToSource(RSyncStatus),
+    Deleted {
+        side: BisyncSide,
+        path: RelativePath,
+    },
+    WouldDelete {
+        side: BisyncSide,
+        path: RelativePath,
+    },
+    AlreadySynced(RelativePath),
+    /// Both sides changed, `kept` is the side whose version stays under `path` and `copy` is where the other version has been kept
+    Conflict {
+        path: RelativePath,
+        kept: BisyncSide,
+        copy: Option<RelativePath>,
+    },
+}
+
+/// Result of a [`RSync::bisync`] run.
+///
+/// `state` has to be stored and given to the next run, failed entries keep their previous state so that they are retried
+#[derive(Debug, Default)]
+pub struct BisyncReport {
+    pub statuses: Vec<BisyncStatus>,
+    pub failures: Vec<(RelativePath, RSyncError)>,
+    pub state: BisyncState,
+}
+
+#[derive(Default)]
+struct BisyncOutcome {
+    statuses: Vec<BisyncStatus>,
+    states: Vec<(RelativePath, Option<BisyncEntryState>)>,
+}
+
+fn conflict_path(path: &RelativePath, suffix: &str, counter: usize) -> RSyncResult<RelativePath> {
+    let suffix = match counter {
+        0 => suffix.to_owned(),
+        counter => format!("{suffix}-{counter}"),
+    };
+    let path = path.as_str();
+    let name_start = path.rfind('/').map(|i| i + 1).unwrap_or(0);
+    let conflict_path = match path[name_start..].rfind('.') {
+        Some(i) if i > 0 => {
+            let (stem, extension) = path.split_at(name_start + i);
+            format!("{stem}{suffix}{extension}")
+        }
+        _ => format!("{path}{suffix}"),
+    };
+    RelativePath::new(&conflict_path)
+}
+
+impl RSync {
+    /// Set the [`ConflictResolution`] used by [`RSync::bisync`], [`ConflictResolution::KeepBoth`] by default
+    pub fn with_conflict_resolution(mut self, conflict_resolution: ConflictResolution) -> Self {
+        self.conflict_resolution = conflict_resolution;
+        self
+    }
+
+    fn backend(&self, side: BisyncSide) -> &dyn ReaderWriterBackend {
+        match side {
+            BisyncSide::Source => self.source.as_ref(),
+            BisyncSide::Dest => self.dest.as_ref(),
+        }
+    }
+
+    /// mtime and size are enough to detect changes except when the mtime is unknown (ex: gcs object without mtime metadata)
+    async fn bisync_entry_state(
+        &self,
+        side: BisyncSide,
+        path: &RelativePath,
+    ) -> RSyncResult<EntryState> {
+        let client = self.backend(side);
+        let mut state = Self::entry_state(client, path).await?;
+        if state.exists() && state.mtime.is_none() {
+            state.crc32c = Self::crc32c(client, path).await?;
+        }
+        Ok(state)
+    }
+
+    async fn current_state(&self, path: &RelativePath) -> RSyncResult<Option<BisyncEntryState>> {
+        let source = self.bisync_entry_state(BisyncSide::Source, path).await?;
+        let dest = self.bisync_entry_state(BisyncSide::Dest, path).await?;
+        Ok((source.exists() || dest.exists()).then_some(BisyncEntryState { source, dest }))
+    }
+
+    async fn same_content(
+        &self,
+        path: &RelativePath,
+        source: &EntryState,
+        dest: &EntryState,
+    ) -> RSyncResult<bool> {
+        if source.size != dest.size {
+            return Ok(false);
+        }
+        let source_crc32c = Self::crc32c(self.source.as_ref(), path).await?;
+        let dest_crc32c = Self::crc32c(self.dest.as_ref(), path).await?;
+        Ok(source_crc32c.is_some() && source_crc32c == dest_crc32c)
+    }
+
+    async fn copy(
+        &self,
+        from: BisyncSide,
+        path: &RelativePath,
+        to_path: &RelativePath,
+        to: BisyncSide,
+        state: &EntryState,
+        to_state: &EntryState,
+    ) -> RSyncResult<()> {
+        self.transfer(
+            self.backend(from),
+            path,
+            self.backend(to),
+            to_path,
+            state,
+            to_state,
+        )
+        .await
+    }
+
+    /// First conflict copy path taken on neither side
+    async fn free_conflict_path(
+        &self,
+        path: &RelativePath,
+        suffix: &str,
+    ) -> RSyncResult<RelativePath> {
+        let mut counter = 0;
+        loop {
+            let copy = conflict_path(path, suffix, counter)?;
+            let mut taken = false;
+            for side in [BisyncSide::Source, BisyncSide::Dest] {
+                taken = taken
+                    || self
+                        .backend(side)
+                        .exists(&copy)
+                        .await
+                        .map_err(|e| e.entry_failed(&copy, Operation::Stat))?;
+            }
+            if taken.not() {
+                return Ok(copy);
+            }
+            counter += 1;
+        }
+    }
+
+    /// Copy the `from` version over the other side and report it
+    async fn send(
+        &self,
+        from: BisyncSide,
+        path: &RelativePath,
+        from_state: &EntryState,
+        to_state: &EntryState,
+        reason: &str,
+    ) -> RSyncResult<BisyncStatus> {
+        self.copy(from, path, path, from.other(), from_state, to_state)
+            .await?;
+        let status = if to_state.exists() {
+            self.updated(reason, path)
+        } else {
+            self.created(path)
+        };
+        Ok(match from {
+            BisyncSide::Source => BisyncStatus::ToDest(status),
+            BisyncSide::Dest => BisyncStatus::ToSource(status),
+        })
+    }
+
+    async fn remove(&self, side: BisyncSide, path: &RelativePath) -> RSyncResult<BisyncStatus> {
+        let path = path.to_owned();
+        if self.dry_run {
+            return Ok(BisyncStatus::WouldDelete { side, path });
+        }
+        self.backend(side)
+            .delete(&path)
+            .await
+            .map_err(|e| e.entry_failed(&path, Operation::Delete))?;
+        Ok(BisyncStatus::Deleted { side, path })
+    }
+
+    async fn resolve_conflict(
+        &self,
+        path: &RelativePath,
+        source: &EntryState,
+        dest: &EntryState,
+    ) -> RSyncResult<(Vec<BisyncStatus>, Vec<RelativePath>)> {
+        let winner = match (&self.conflict_resolution, source.exists(), dest.exists()) {
+            (ConflictResolution::Fail, _, _) => {
+                return Err(RSyncError::BisyncConflict(path.to_owned()))
+            }
+            (_, true, false) => BisyncSide::Source,
+            (_, false, true) => BisyncSide::Dest,
+            (ConflictResolution::NewerWins, _, _) if dest.mtime > source.mtime => BisyncSide::Dest,
+            (ConflictResolution::NewerWins, _, _) => BisyncSide::Source,
+            (ConflictResolution::KeepBoth { suffix }, _, _) => {
+                let copy = self.free_conflict_path(path, suffix).await?;
+                let free = EntryState::default();
+                self.copy(
+                    BisyncSide::Dest,
+                    path,
+                    &copy,
+                    BisyncSide::Source,
+                    dest,
+                    &free,
+                )
+                .await?;
+                self.copy(BisyncSide::Dest, path, &copy, BisyncSide::Dest, dest, &free)
+                    .await?;
+                let sent = self
+                    .send(BisyncSide::Source, path, source, dest, "conflict")
+                    .await?;
+                let conflict = BisyncStatus::Conflict {
+                    path: path.to_owned(),
+                    kept: BisyncSide::Source,
+                    copy: Some(copy.to_owned()),
+                };
+                return Ok((vec![conflict, sent], vec![copy]));
+            }
+        };
+
+        let (from, to) = match winner {
+            BisyncSide::Source => (source, dest),
+            BisyncSide::Dest => (dest, source),
+        };
+        let sent = self.send(winner, path, from, to, "conflict").await?;
+        let conflict = BisyncStatus::Conflict {
+            path: path.to_owned(),
+            kept: winner,
+            copy: None,
+        };
+        Ok((vec![conflict, sent], vec![]))
+    }
+
+    async fn bisync_entry(
+        &self,
+        path: &RelativePath,
+        previous: Option<&BisyncEntryState>,
+    ) -> RSyncResult<BisyncOutcome> {
+        fn changed(previous: Option<&EntryState>, current: &EntryState) -> bool {
+            match previous {
+                None => current.exists(),
+                // states recorded without generation (ex: by an older version) are compared without it
+                Some(previous) if previous.generation.is_none() => {
+                    previous
+                        != &EntryState {
+                            generation: None,
+                            ..current.to_owned()
+                        }
+                }
+                Some(previous) => previous != current,
+            }
+        }
+
+        let source = self.bisync_entry_state(BisyncSide::Source, path).await?;
+        let dest = self.bisync_entry_state(BisyncSide::Dest, path).await?;
+        let source_changed = changed(previous.map(|x| &x.source), &source);
+        let dest_changed = changed(previous.map(|x| &x.dest), &dest);
+
+        let mut touched = vec![path.to_owned()];
+        let statuses = match (source_changed, dest_changed, source.exists(), dest.exists()) {
+            (_, _, false, false) => vec![],
+            (false, false, true, true) => vec![BisyncStatus::AlreadySynced(path.to_owned())],
+            (true, true, true, true) if self.same_content(path, &source, &dest).await? => {
+                vec![BisyncStatus::AlreadySynced(path.to_owned())]
+            }
+            (true, true, _, _) => {
+                let (statuses, copies) = self.resolve_conflict(path, &source, &dest).await?;
+                touched.extend(copies);
+                statuses
+            }
+            (true, false, true, _) | (false, false, true, false) => {
+                let reason = format!("changed on {}", BisyncSide::Source.name());
+                vec![
+                    self.send(BisyncSide::Source, path, &source, &dest, &reason)
+                        .await?,
+                ]
+            }
+            (false, true, _, true) | (false, false, false, true) => {
+                let reason = format!("changed on {}", BisyncSide::Dest.name());
+                vec![
+                    self.send(BisyncSide::Dest, path, &dest, &source, &reason)
+                        .await?,
+                ]
+            }
+            (true, false, false, true) => vec![self.remove(BisyncSide::Dest, path).await?],
+            (false, true, true, false) => vec![self.remove(BisyncSide::Source, path).await?],
+        };
+
+        if self.dry_run {
+            return Ok(BisyncOutcome {
+                statuses,
+                states: vec![],
+            });
+        }
+
+        let mut states = Vec::with_capacity(touched.len());
+        for path in touched {
+            let state = self.current_state(&path).await?;
+            states.push((path, state));
+        }
+        Ok(BisyncOutcome { statuses, states })
+    }
+
+    /// Bisync propagates creations, updates and deletions in both directions since the `previous` run.
+    ///
+    /// Changes are detected by comparing each side with the state recorded in `previous` (mtime and size, crc32c when mtime is missing).
+    /// When the same path changed on both sides (with a different content), the [`ConflictResolution`] set with [`RSync::with_conflict_resolution`] is applied.
+    ///
+    /// Entries are processed with up to `concurrency` entries at a time, entry failures are reported without stopping the run.
+    /// The returned [`BisyncReport::state`] has to be given to the next run, it is left unchanged in dry run.
+    ///
+    /// Example
+    /// ```rust
+    /// use gcs_rsync::sync::{BisyncState, MemoryStore, RSync, RSyncResult, ReaderWriter};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> RSyncResult<()> {
+    ///     let workstation = MemoryStore::new();
+    ///     workstation.insert("notes.txt", "hello", None)?;
+    ///     let shared = MemoryStore::new();
+    ///     let rsync = RSync::new(ReaderWriter::memory(&workstation), ReaderWriter::memory(&shared));
+    ///
+    ///     let report = rsync.bisync(&BisyncState::default(), 12).await?;
+    ///     println!("{:?}", report.statuses);
+    ///     println!("{}", serde_json::to_string(&report.state).unwrap());
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn bisync(
+        &self,
+        previous: &BisyncState,
+        concurrency: usize,
+    ) -> RSyncResult<BisyncReport> {
+        self.source.is_valid().await?;
+        self.dest.is_valid().await?;
+
+        let mut paths = previous.entries.keys().cloned().collect::<BTreeSet<_>>();
+        for backend in [self.source.as_ref(), self.dest.as_ref()] {
+            let listed = backend.list_in_scope(self.list_scope()).await;
+            paths.extend(listed.try_collect::<Vec<_>>().await?);
+        }
+
+        let outcomes = futures::stream::iter(paths.into_iter().filter(|x| self.filter(x)))
+            .map(|path| async move {
+                let outcome = self.bisync_entry(&path, previous.entries.get(&path)).await;
+                (path, outcome)
+            })
+            .buffer_unordered(concurrency);
+        let outcomes = self
+            .error_policy
+            .apply(outcomes, |(_, outcome)| outcome.is_err())
+            .collect::<Vec<_>>()
+            .await;
+
+        let mut report = BisyncReport {
+            state: previous.to_owned(),
+            ..BisyncReport::default()
+        };
+        for (path, outcome) in outcomes {
+            match outcome {
+                Ok(outcome) => {
+                    report.statuses.extend(outcome.statuses);
+                    for (path, state) in outcome.states {
+                        match state {
+                            Some(state) => report.state.entries.insert(path, state),
+                            None => report.state.entries.remove(&path),
+                        };
+                    }
+                }
+                Err(error) => report.failures.push((path, error)),
+            }
+        }
+        report.statuses.sort();
+        report.failures.sort_by(|x, y| x.0.cmp(&y.0));
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::sync::testing::{content, created, mtime, path, rsync, updated, CONCURRENCY_LEVEL};
+    use crate::sync::{MemoryStore, RSync, RSyncError, RSyncStatus, ReaderWriter, RelativePath};
+
+    use super::{
+        conflict_path, BisyncReport, BisyncSide, BisyncState, BisyncStatus, ConflictResolution,
+    };
+
+    async fn bisync(rsync: &RSync, state: &BisyncState) -> BisyncReport {
+        rsync.bisync(state, CONCURRENCY_LEVEL).await.unwrap()
+    }
+
+    #[test]
+    fn test_conflict_path() {
+        fn actual(path: &str) -> String {
+            let path = RelativePath::new(path).unwrap();
+            conflict_path(&path, ".conflict", 0)
+                .unwrap()
+                .as_str()
+                .to_owned()
+        }
+        assert_eq!("notes.conflict.txt", actual("notes.txt"));
+        assert_eq!("a.b/notes.tar.conflict.gz", actual("a.b/notes.tar.gz"));
+        assert_eq!("a.b/notes.conflict", actual("a.b/notes"));
+        assert_eq!("a/.env.conflict", actual("a/.env"));
+
+        let path = RelativePath::new("a/notes.txt").unwrap();
+        assert_eq!(
+            "a/notes.conflict-2.txt",
+            conflict_path(&path, ".conflict", 2).unwrap().as_str()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_keep_both_never_overwrites_conflict_copies() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        let rsync = RSync::new(ReaderWriter::memory(&source), ReaderWriter::memory(&dest));
+        source.insert("notes.txt", "v1", mtime(1)).unwrap();
+        let mut state = rsync
+            .bisync(&BisyncState::default(), 4)
+            .await
+            .unwrap()
+            .state;
+
+        for version in 2..4 {
+            source
+                .insert("notes.txt", format!("source v{version}"), mtime(version))
+                .unwrap();
+            dest.insert("notes.txt", format!("dest v{version}"), mtime(version))
+                .unwrap();
+            let report = rsync.bisync(&state, 4).await.unwrap();
+            assert!(report.failures.is_empty());
+            state = report.state;
+        }
+        for store in [&source, &dest] {
+            let content = |path: &str| store.get(path).map(|x| x.content);
+            assert_eq!(Some("dest v2".into()), content("notes.conflict.txt"));
+            assert_eq!(Some("dest v3".into()), content("notes.conflict-1.txt"));
+            assert_eq!(Some("source v3".into()), content("notes.txt"));
+        }
+    }
+
+    #[test]
+    fn test_conflict_resolution_from_str() {
+        assert_eq!(
+            ConflictResolution::NewerWins,
+            ConflictResolution::from_str("newer-wins").unwrap()
+        );
+        assert_eq!(
+            ConflictResolution::default(),
+            ConflictResolution::from_str("keep-both").unwrap()
+        );
+        assert_eq!(
+            ConflictResolution::Fail,
+            ConflictResolution::from_str("fail").unwrap()
+        );
+        assert!(ConflictResolution::from_str("oldest").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bisync() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        source.insert("a.txt", "a", mtime(1)).unwrap();
+        source.insert("both.txt", "both", mtime(1)).unwrap();
+        dest.insert("b.txt", "b", mtime(1)).unwrap();
+        dest.insert("both.txt", "both", mtime(2)).unwrap();
+        let rsync = rsync(&source, &dest);
+
+        let report = bisync(&rsync, &BisyncState::default()).await;
+        assert!(report.failures.is_empty());
+        assert_eq!(
+            vec![
+                BisyncStatus::ToDest(created("a.txt")),
+                BisyncStatus::ToSource(created("b.txt")),
+                BisyncStatus::AlreadySynced(path("both.txt")),
+            ],
+            report.statuses
+        );
+        assert_eq!(source.paths(), dest.paths());
+
+        let report = bisync(&rsync, &report.state).await;
+        assert_eq!(
+            vec![
+                BisyncStatus::AlreadySynced(path("a.txt")),
+                BisyncStatus::AlreadySynced(path("b.txt")),
+                BisyncStatus::AlreadySynced(path("both.txt")),
+            ],
+            report.statuses
+        );
+
+        source.insert("a.txt", "a2", mtime(3)).unwrap();
+        dest.remove("b.txt").unwrap();
+        dest.insert("c.txt", "c", mtime(3)).unwrap();
+        let state = serde_json::from_str(&serde_json::to_string(&report.state).unwrap()).unwrap();
+        let report = bisync(&rsync, &state).await;
+        assert!(report.failures.is_empty());
+        assert_eq!(
+            vec![
+                BisyncStatus::ToDest(updated("changed on source", "a.txt")),
+                BisyncStatus::ToSource(created("c.txt")),
+                BisyncStatus::Deleted {
+                    side: BisyncSide::Source,
+                    path: path("b.txt")
+                },
+                BisyncStatus::AlreadySynced(path("both.txt")),
+            ],
+            report.statuses
+        );
+        assert_eq!(
+            vec![path("a.txt"), path("both.txt"), path("c.txt")],
+            source.paths()
+        );
+        assert_eq!(source.paths(), dest.paths());
+        assert_eq!(Some("a2".into()), content(&dest, "a.txt"));
+        assert_eq!(
+            vec![path("a.txt"), path("both.txt"), path("c.txt")],
+            report.state.entries.into_keys().collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bisync_dry_run() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        source.insert("a.txt", "a", mtime(1)).unwrap();
+        let rsync = rsync(&source, &dest).with_dry_run(true);
+
+        let report = bisync(&rsync, &BisyncState::default()).await;
+        assert_eq!(
+            vec![BisyncStatus::ToDest(RSyncStatus::WouldCreate(path(
+                "a.txt"
+            )))],
+            report.statuses
+        );
+        assert_eq!(BisyncState::default(), report.state);
+        assert!(dest.paths().is_empty());
+    }
+
+    async fn setup_bisync_conflict(
+        resolution: ConflictResolution,
+    ) -> (MemoryStore, MemoryStore, BisyncReport) {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        source.insert("notes.txt", "v1", mtime(1)).unwrap();
+        source.insert("deleted.txt", "v1", mtime(1)).unwrap();
+        let rsync = rsync(&source, &dest).with_conflict_resolution(resolution);
+        let state = bisync(&rsync, &BisyncState::default()).await.state;
+
+        source.insert("notes.txt", "source v2", mtime(2)).unwrap();
+        dest.insert("notes.txt", "dest v2", mtime(3)).unwrap();
+        source.remove("deleted.txt").unwrap();
+        dest.insert("deleted.txt", "dest v2", mtime(2)).unwrap();
+        let report = bisync(&rsync, &state).await;
+        (source, dest, report)
+    }
+
+    #[tokio::test]
+    async fn test_bisync_conflict_newer_wins() {
+        let (source, dest, report) = setup_bisync_conflict(ConflictResolution::NewerWins).await;
+        assert!(report.failures.is_empty());
+        assert_eq!(
+            vec![
+                BisyncStatus::ToSource(created("deleted.txt")),
+                BisyncStatus::ToSource(updated("conflict", "notes.txt")),
+                BisyncStatus::Conflict {
+                    path: path("deleted.txt"),
+                    kept: BisyncSide::Dest,
+                    copy: None
+                },
+                BisyncStatus::Conflict {
+                    path: path("notes.txt"),
+                    kept: BisyncSide::Dest,
+                    copy: None
+                },
+            ],
+            report.statuses
+        );
+        assert_eq!(Some("dest v2".into()), content(&source, "notes.txt"));
+        assert_eq!(Some("dest v2".into()), content(&source, "deleted.txt"));
+        assert_eq!(source.paths(), dest.paths());
+    }
+
+    #[tokio::test]
+    async fn test_bisync_conflict_keep_both() {
+        let (source, dest, report) = setup_bisync_conflict(ConflictResolution::default()).await;
+        assert!(report.failures.is_empty());
+        assert_eq!(
+            vec![
+                BisyncStatus::ToDest(updated("conflict", "notes.txt")),
+                BisyncStatus::ToSource(created("deleted.txt")),
+                BisyncStatus::Conflict {
+                    path: path("deleted.txt"),
+                    kept: BisyncSide::Dest,
+                    copy: None
+                },
+                BisyncStatus::Conflict {
+                    path: path("notes.txt"),
+                    kept: BisyncSide::Source,
+                    copy: Some(path("notes.conflict.txt"))
+                },
+            ],
+            report.statuses
+        );
+        for store in [&source, &dest] {
+            assert_eq!(Some("source v2".into()), content(store, "notes.txt"));
+            assert_eq!(Some("dest v2".into()), content(store, "notes.conflict.txt"));
+        }
+        assert!(report
+            .state
+            .entries
+            .contains_key(&path("notes.conflict.txt")));
+    }
+
+    #[tokio::test]
+    async fn test_bisync_conflict_fail() {
+        let (source, dest, report) = setup_bisync_conflict(ConflictResolution::Fail).await;
+        assert!(report.statuses.is_empty());
+        assert_eq!(
+            vec![path("deleted.txt"), path("notes.txt")],
+            report
+                .failures
+                .iter()
+                .map(|(path, _)| path.to_owned())
+                .collect::<Vec<_>>()
+        );
+        assert!(report
+            .failures
+            .iter()
+            .all(|(_, e)| matches!(e, RSyncError::BisyncConflict(_))));
+        assert_eq!(Some("source v2".into()), content(&source, "notes.txt"));
+        assert_eq!(Some("dest v2".into()), content(&dest, "notes.txt"));
+        assert_eq!(
+            Some(&mtime(1)),
+            report
+                .state
+                .entries
+                .get(&path("notes.txt"))
+                .map(|x| &x.source.mtime),
+            "failed entries keep their previous state"
+        );
+    }
+}
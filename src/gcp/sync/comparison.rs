@@ -0,0 +1,468 @@
+use std::{ops::Not, str::FromStr};
+
+use super::{EntryState, RSync, RSyncResult, RelativePath, SyncAction};
+
+/// How [`RSync`] decides whether an existing destination entry is already synced
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ComparisonStrategy {
+    /// Same mtime and size means synced, crc32c is compared when either side lacks them
+    #[default]
+    Default,
+    /// Always compare crc32c, mtime and size are ignored
+    Checksum,
+    /// Same size means synced, crc32c is compared when either side lacks it
+    SizeOnly,
+    /// Same mtime means synced, crc32c is compared when either side lacks it
+    MtimeOnly,
+    /// Always compare md5 when both sides know it, crc32c otherwise (ex: gcs composite objects)
+    Md5,
+}
+
+impl FromStr for ComparisonStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(Self::Default),
+            "checksum" => Ok(Self::Checksum),
+            "size-only" => Ok(Self::SizeOnly),
+            "mtime-only" => Ok(Self::MtimeOnly),
+            "md5" => Ok(Self::Md5),
+            _ => Err(format!(
+                "unknown comparison {s}, expected default, checksum, size-only, mtime-only or md5"
+            )),
+        }
+    }
+}
+
+type Mtime = chrono::DateTime<chrono::Utc>;
+
+/// Both mtimes in nanoseconds, truncated to whole seconds when either side only carries whole seconds (see [`EntryState::mtime_in_seconds`])
+fn at_common_precision(source: &Mtime, dest: &Mtime, in_seconds: bool) -> (i128, i128) {
+    let precision = if in_seconds { 1_000_000_000 } else { 1 };
+    let truncate = |mtime: &Mtime| {
+        let nanos = i128::from(mtime.timestamp_subsec_nanos());
+        i128::from(mtime.timestamp()) * 1_000_000_000 + nanos / precision * precision
+    };
+    (truncate(source), truncate(dest))
+}
+
+fn compare<T: PartialEq>(source: Option<T>, dest: Option<T>, what: &str) -> Option<SyncAction> {
+    match (source, dest) {
+        (Some(source), Some(dest)) if source == dest => {
+            Some(SyncAction::skip(&format!("same {what}")))
+        }
+        (Some(_), Some(_)) => Some(SyncAction::update(&format!("different {what}"))),
+        _ => None,
+    }
+}
+
+impl RSync {
+    /// Set the [`ComparisonStrategy`] of sync, mirror and plan, [`ComparisonStrategy::Default`] by default
+    pub fn with_comparison_strategy(mut self, comparison_strategy: ComparisonStrategy) -> Self {
+        self.comparison_strategy = comparison_strategy;
+        self
+    }
+
+    /// Like rsync `--update`, skip destination entries newer than the source whatever their content
+    pub fn with_update_only(mut self, update_only: bool) -> Self {
+        self.update_only = update_only;
+        self
+    }
+
+    /// Like rsync `--ignore-existing`, never overwrite an existing destination entry and only create missing ones
+    pub fn with_ignore_existing(mut self, ignore_existing: bool) -> Self {
+        self.ignore_existing = ignore_existing;
+        self
+    }
+
+    /// Like rsync `--modify-window`, mtimes within `window` count as equal (ex: 2 seconds for FAT file systems)
+    pub fn with_modify_window(mut self, window: std::time::Duration) -> Self {
+        self.modify_window = window;
+        self
+    }
+
+    fn same_mtime(&self, source: &Mtime, dest: &Mtime, in_seconds: bool) -> bool {
+        let (source, dest) = at_common_precision(source, dest, in_seconds);
+        source.abs_diff(dest) <= self.modify_window.as_nanos()
+    }
+
+    fn newer_dest(&self, source: &Mtime, dest: &Mtime, in_seconds: bool) -> bool {
+        let (source_ns, dest_ns) = at_common_precision(source, dest, in_seconds);
+        dest_ns > source_ns && self.same_mtime(source, dest, in_seconds).not()
+    }
+
+    /// Metadata rule of the [`ComparisonStrategy`] for an existing destination, `None` when crc32c has to be compared
+    pub(super) fn compare_metadata(
+        &self,
+        source: &EntryState,
+        dest: &EntryState,
+    ) -> Option<SyncAction> {
+        if self.ignore_existing {
+            return Some(SyncAction::SkipExisting);
+        }
+        let mtimes = source.mtime.as_ref().zip(dest.mtime.as_ref());
+        let in_seconds = source.mtime_in_seconds || dest.mtime_in_seconds;
+        if self.update_only && mtimes.is_some_and(|(s, d)| self.newer_dest(s, d, in_seconds)) {
+            return Some(SyncAction::SkipNewerDest);
+        }
+        match self.comparison_strategy {
+            ComparisonStrategy::Default => match (mtimes, source.size, dest.size) {
+                (Some((source_mtime, dest_mtime)), Some(source_size), Some(dest_size)) => Some(
+                    if self.same_mtime(source_mtime, dest_mtime, in_seconds)
+                        && source_size == dest_size
+                    {
+                        SyncAction::skip("same mtime and size")
+                    } else {
+                        SyncAction::update("different size or mtime")
+                    },
+                ),
+                _ => None,
+            },
+            ComparisonStrategy::Checksum | ComparisonStrategy::Md5 => None,
+            ComparisonStrategy::SizeOnly => compare(source.size, dest.size, "size"),
+            ComparisonStrategy::MtimeOnly => mtimes.map(|(s, d)| {
+                if self.same_mtime(s, d, in_seconds) {
+                    SyncAction::skip("same mtime")
+                } else {
+                    SyncAction::update("different mtime")
+                }
+            }),
+        }
+    }
+
+    /// md5 rule of [`ComparisonStrategy::Md5`], `None` when crc32c has to be compared.
+    ///
+    /// A destination listed with its crc32c but without md5 is a composite gcs object, its md5 is not requested since gcs does not know it either
+    pub(super) async fn compare_md5(
+        &self,
+        path: &RelativePath,
+        source: &mut EntryState,
+        dest: &mut EntryState,
+    ) -> RSyncResult<Option<SyncAction>> {
+        if self.comparison_strategy != ComparisonStrategy::Md5 {
+            return Ok(None);
+        }
+        if dest.md5.is_none() && dest.crc32c.is_none() {
+            dest.md5 = Self::md5(self.dest.as_ref(), path).await?;
+        }
+        if dest.md5.is_some() && source.md5.is_none() {
+            source.md5 = Self::md5(self.source.as_ref(), path).await?;
+        }
+        Ok(compare(source.md5.as_ref(), dest.md5.as_ref(), "md5"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::{atomic::AtomicUsize, Arc};
+    use std::time::Duration;
+
+    use chrono::TimeZone;
+
+    use crate::sync::testing::{
+        already_synced, created, mtime, path, rsync, sync, updated, CountingStore,
+    };
+    use crate::sync::{
+        EntryState, MemoryStore, RSync, RSyncStatus, ReaderWriter, RelativePath, SyncAction,
+    };
+
+    use super::{at_common_precision, ComparisonStrategy};
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!(
+            Ok(ComparisonStrategy::Checksum),
+            ComparisonStrategy::from_str("checksum")
+        );
+        assert_eq!(
+            Ok(ComparisonStrategy::SizeOnly),
+            ComparisonStrategy::from_str("size-only")
+        );
+        assert!(ComparisonStrategy::from_str("crc32c").is_err());
+    }
+
+    #[test]
+    fn test_at_common_precision() {
+        let mtime = |secs, nanos| chrono::Utc.timestamp_opt(secs, nanos).unwrap();
+        assert_eq!(
+            (10_000_000_000, 10_000_000_000),
+            at_common_precision(&mtime(10, 123_456_789), &mtime(10, 0), true),
+            "whole seconds (ex: gsutil mtime)"
+        );
+        assert_eq!(
+            (10_123_456_789, 10_000_000_000),
+            at_common_precision(&mtime(10, 123_456_789), &mtime(10, 0), false)
+        );
+        assert_eq!(
+            (10_120_000_000, 10_100_000_000),
+            at_common_precision(&mtime(10, 120_000_000), &mtime(10, 100_000_000), false),
+            "round nanos are not a precision"
+        );
+    }
+
+    #[test]
+    fn test_compare_metadata_sub_second_mtime() {
+        let store = MemoryStore::new();
+        let rsync = RSync::new(ReaderWriter::memory(&store), ReaderWriter::memory(&store));
+        let state = |nanos, mtime_in_seconds| EntryState {
+            mtime_in_seconds,
+            ..EntryState::new(chrono::Utc.timestamp_opt(10, nanos).single(), Some(1))
+        };
+        assert_eq!(
+            Some(SyncAction::update("different size or mtime")),
+            rsync.compare_metadata(&state(123_456_789, false), &state(123_456_788, false))
+        );
+        assert_eq!(
+            Some(SyncAction::update("different size or mtime")),
+            rsync.compare_metadata(&state(120_000_000, false), &state(100_000_000, false))
+        );
+        assert_eq!(
+            Some(SyncAction::skip("same mtime and size")),
+            rsync.compare_metadata(&state(123_456_789, false), &state(0, true)),
+            "whole seconds destination"
+        );
+        assert_eq!(
+            Some(SyncAction::skip("same mtime and size")),
+            rsync.compare_metadata(&state(0, true), &state(987_654_321, false)),
+            "whole seconds source"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compare_md5_of_listed_composite_object() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        source.insert("a.txt", "a", None).unwrap();
+        dest.insert("a.txt", "a", None).unwrap();
+        let rsync = RSync::new(ReaderWriter::memory(&source), ReaderWriter::memory(&dest))
+            .with_comparison_strategy(ComparisonStrategy::Md5);
+        let path = RelativePath::new("a.txt").unwrap();
+
+        let listed = |crc32c| EntryState {
+            crc32c,
+            ..EntryState::new(None, Some(1))
+        };
+        let (mut source, mut dest) = (listed(None), listed(Some(1)));
+        assert_eq!(
+            None,
+            rsync
+                .compare_md5(&path, &mut source, &mut dest)
+                .await
+                .unwrap(),
+            "md5 missing from the listing falls back to crc32c without requesting it"
+        );
+        assert_eq!((None, None), (source.md5, dest.md5));
+
+        let (mut source, mut dest) = (listed(None), listed(None));
+        assert_eq!(
+            Some(SyncAction::skip("same md5")),
+            rsync
+                .compare_md5(&path, &mut source, &mut dest)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_md5() {
+        fn stores() -> (MemoryStore, MemoryStore) {
+            let source = MemoryStore::new();
+            let dest = MemoryStore::new();
+            source.insert("same.txt", "same", mtime(2)).unwrap();
+            source.insert("changed.txt", "abc", mtime(1)).unwrap();
+            dest.insert("same.txt", "same", mtime(1)).unwrap();
+            dest.insert("changed.txt", "xyz", mtime(1)).unwrap();
+            (source, dest)
+        }
+
+        for listing_comparison in [false, true] {
+            let (source, dest) = stores();
+            let rsync = rsync(&source, &dest)
+                .with_comparison_strategy(ComparisonStrategy::Md5)
+                .with_listing_comparison(listing_comparison);
+            assert_eq!(
+                vec![
+                    updated("different md5", "changed.txt"),
+                    already_synced("same md5", "same.txt"),
+                ],
+                sync(&rsync).await
+            );
+        }
+
+        let (source, dest) = stores();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let without_md5 = RSync::new(
+            ReaderWriter::memory(&source),
+            CountingStore::reader_writer(&dest, false, &requests),
+        )
+        .with_comparison_strategy(ComparisonStrategy::Md5);
+        assert_eq!(
+            vec![
+                updated("different crc32c", "changed.txt"),
+                already_synced("same crc32c", "same.txt"),
+            ],
+            sync(&without_md5).await,
+            "falls back to crc32c when a side has no md5"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_update_only_and_ignore_existing() {
+        fn stores() -> (MemoryStore, MemoryStore) {
+            let source = MemoryStore::new();
+            let dest = MemoryStore::new();
+            source.insert("new.txt", "new", mtime(2)).unwrap();
+            source
+                .insert("newer_source.txt", "source", mtime(3))
+                .unwrap();
+            source.insert("newer_dest.txt", "source", mtime(1)).unwrap();
+            dest.insert("newer_source.txt", "dest", mtime(2)).unwrap();
+            dest.insert("newer_dest.txt", "dest", mtime(2)).unwrap();
+            (source, dest)
+        }
+
+        for listing_comparison in [false, true] {
+            let (source, dest) = stores();
+            let update_only = rsync(&source, &dest)
+                .with_update_only(true)
+                .with_listing_comparison(listing_comparison);
+            assert_eq!(
+                vec![
+                    created("new.txt"),
+                    updated("different size or mtime", "newer_source.txt"),
+                    RSyncStatus::SkippedNewerDest(path("newer_dest.txt")),
+                ],
+                sync(&update_only).await
+            );
+            assert_eq!(dest.get("newer_dest.txt").unwrap().content, "dest");
+
+            let (source, dest) = stores();
+            let ignore_existing = rsync(&source, &dest)
+                .with_ignore_existing(true)
+                .with_listing_comparison(listing_comparison);
+            assert_eq!(
+                vec![
+                    created("new.txt"),
+                    RSyncStatus::SkippedExisting(path("newer_dest.txt")),
+                    RSyncStatus::SkippedExisting(path("newer_source.txt")),
+                ],
+                sync(&ignore_existing).await
+            );
+            assert_eq!(dest.get("newer_source.txt").unwrap().content, "dest");
+            assert_eq!(3, ignore_existing.sync_report().await.skipped);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_comparison_strategy() {
+        async fn run(strategy: ComparisonStrategy, listing_comparison: bool) -> Vec<RSyncStatus> {
+            let source = MemoryStore::new();
+            let dest = MemoryStore::new();
+            source.insert("regenerated.txt", "same", mtime(2)).unwrap();
+            source.insert("same_size.txt", "abc", mtime(1)).unwrap();
+            dest.insert("regenerated.txt", "same", mtime(1)).unwrap();
+            dest.insert("same_size.txt", "xyz", mtime(1)).unwrap();
+            let rsync = rsync(&source, &dest)
+                .with_comparison_strategy(strategy)
+                .with_listing_comparison(listing_comparison);
+            sync(&rsync).await
+        }
+
+        for listing_comparison in [false, true] {
+            assert_eq!(
+                vec![
+                    updated("different size or mtime", "regenerated.txt"),
+                    already_synced("same mtime and size", "same_size.txt"),
+                ],
+                run(ComparisonStrategy::Default, listing_comparison).await
+            );
+            assert_eq!(
+                vec![
+                    updated("different crc32c", "same_size.txt"),
+                    already_synced("same crc32c", "regenerated.txt"),
+                ],
+                run(ComparisonStrategy::Checksum, listing_comparison).await
+            );
+            assert_eq!(
+                vec![
+                    already_synced("same size", "regenerated.txt"),
+                    already_synced("same size", "same_size.txt"),
+                ],
+                run(ComparisonStrategy::SizeOnly, listing_comparison).await
+            );
+            assert_eq!(
+                vec![
+                    updated("different mtime", "regenerated.txt"),
+                    already_synced("same mtime", "same_size.txt"),
+                ],
+                run(ComparisonStrategy::MtimeOnly, listing_comparison).await
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_modify_window() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        source.insert("rounded.txt", "same", mtime(11)).unwrap();
+        source.insert("changed.txt", "abcd", mtime(20)).unwrap();
+        dest.insert("rounded.txt", "same", mtime(10)).unwrap();
+        dest.insert("changed.txt", "efgh", mtime(10)).unwrap();
+
+        let within_window = rsync(&source, &dest)
+            .with_modify_window(Duration::from_secs(2))
+            .with_update_only(true);
+        assert_eq!(
+            vec![
+                updated("different size or mtime", "changed.txt"),
+                already_synced("same mtime and size", "rounded.txt"),
+            ],
+            sync(&within_window).await
+        );
+
+        source.insert("rounded.txt", "same", mtime(9)).unwrap();
+        assert_eq!(
+            vec![
+                already_synced("same mtime and size", "changed.txt"),
+                already_synced("same mtime and size", "rounded.txt"),
+            ],
+            sync(&within_window).await,
+            "a newer destination within the window is not skipped by update only"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_sub_second_mtime() {
+        let nanos = |secs, nanos| chrono::Utc.timestamp_opt(secs, nanos).single();
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        source
+            .insert("round.txt", "same", nanos(10, 120_000_000))
+            .unwrap();
+        source
+            .insert("nanos.txt", "same", nanos(10, 123_456_789))
+            .unwrap();
+        source
+            .insert("same.txt", "same", nanos(10, 123_456_789))
+            .unwrap();
+        dest.insert("round.txt", "same", nanos(10, 100_000_000))
+            .unwrap();
+        dest.insert("nanos.txt", "same", nanos(10, 223_000_000))
+            .unwrap();
+        dest.insert("same.txt", "same", nanos(10, 123_456_789))
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                updated("different size or mtime", "nanos.txt"),
+                updated("different size or mtime", "round.txt"),
+                already_synced("same mtime and size", "same.txt"),
+            ],
+            sync(&rsync(&source, &dest)).await,
+            "round sub-second mtimes are still compared to the nanosecond"
+        );
+        assert_eq!(nanos(10, 123_456_789), dest.get("nanos.txt").unwrap().mtime);
+    }
+}
@@ -0,0 +1,632 @@
+use std::{
+    ops::Not,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use futures::{stream::BoxStream, Stream, StreamExt, TryStreamExt};
+use tokio::sync::Notify;
+
+use super::{
+    ByteStream, EntryState, ListScope, ListedEntry, PartialContent, RMirrorStatus, RSync,
+    RSyncResult, RSyncStatus, ReaderWriterBackend, RelativePath, Size, WriteChecks,
+};
+
+/// Concurrency limits of [`RSync::with_concurrency`], [`RSync::run_sync`] and [`RSync::run_mirror`].
+///
+/// - `listing`: listing requests in flight (ex: source and destination listings of a mirror)
+/// - `metadata`: metadata requests in flight (size, mtime, crc32c, exists and delete)
+/// - `transfers`: data transfers in flight, when `adaptive` it is halved on transient errors (ex: 429) and slowly grows back on success
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Concurrency {
+    pub listing: usize,
+    pub metadata: usize,
+    pub transfers: usize,
+    pub adaptive: bool,
+}
+
+impl Default for Concurrency {
+    fn default() -> Self {
+        Self {
+            listing: 2,
+            metadata: 32,
+            transfers: 8,
+            adaptive: true,
+        }
+    }
+}
+
+impl Concurrency {
+    /// Number of entries processed at the same time by the runners
+    fn window(&self) -> usize {
+        self.metadata.max(1) + self.transfers.max(1)
+    }
+}
+
+struct LimitState {
+    max: usize,
+    adaptive: bool,
+    limit: usize,
+    in_flight: usize,
+    successes: usize,
+}
+
+/// Async counting limit, an adaptive limit follows an additive increase / multiplicative decrease rule
+pub(super) struct Limit {
+    state: Mutex<LimitState>,
+    released: Notify,
+}
+
+impl Limit {
+    fn new(max: usize, adaptive: bool) -> Arc<Self> {
+        let max = max.max(1);
+        Arc::new(Self {
+            state: Mutex::new(LimitState {
+                max,
+                adaptive,
+                limit: max,
+                in_flight: 0,
+                successes: 0,
+            }),
+            released: Notify::new(),
+        })
+    }
+
+    fn state(&self) -> MutexGuard<'_, LimitState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Replace the limit, permits already acquired are kept
+    fn set(&self, max: usize, adaptive: bool) {
+        let max = max.max(1);
+        {
+            let mut state = self.state();
+            state.max = max;
+            state.adaptive = adaptive;
+            state.limit = max;
+            state.successes = 0;
+        }
+        self.released.notify_waiters();
+    }
+
+    pub(super) async fn acquire(self: &Arc<Self>) -> Permit {
+        loop {
+            // registered before checking so that a release in between is not missed
+            let released = self.released.notified();
+            {
+                let mut state = self.state();
+                if state.in_flight < state.limit {
+                    state.in_flight += 1;
+                    return Permit(self.clone());
+                }
+            }
+            released.await;
+        }
+    }
+
+    /// Adapt the limit to the result of an operation done with a permit
+    pub(super) fn record<T>(&self, result: &RSyncResult<T>) {
+        let mut state = self.state();
+        if state.adaptive.not() {
+            return;
+        }
+        match result {
+            Ok(_) => {
+                state.successes += 1;
+                if state.successes >= state.limit && state.limit < state.max {
+                    state.limit += 1;
+                    state.successes = 0;
+                }
+            }
+            Err(e) if e.is_retryable() => {
+                state.limit = (state.limit / 2).max(1);
+                state.successes = 0;
+            }
+            Err(_) => (),
+        }
+    }
+
+    #[cfg(test)]
+    fn limit(&self) -> usize {
+        self.state().limit
+    }
+}
+
+pub(super) struct Permit(Arc<Limit>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.state().in_flight -= 1;
+        self.0.released.notify_waiters();
+    }
+}
+
+pub(super) struct Limits {
+    listing: Arc<Limit>,
+    metadata: Arc<Limit>,
+    pub(super) transfers: Arc<Limit>,
+}
+
+impl Limits {
+    fn new(concurrency: &Concurrency) -> Arc<Self> {
+        Arc::new(Self {
+            listing: Limit::new(concurrency.listing, false),
+            metadata: Limit::new(concurrency.metadata, false),
+            transfers: Limit::new(concurrency.transfers, concurrency.adaptive),
+        })
+    }
+
+    fn set(&self, concurrency: &Concurrency) {
+        self.listing.set(concurrency.listing, false);
+        self.metadata.set(concurrency.metadata, false);
+        self.transfers
+            .set(concurrency.transfers, concurrency.adaptive);
+    }
+}
+
+/// Each pull may fetch a new page, so a permit is only held while pulling to let merge-joined listings progress together
+fn limited<'a, T>(stream: BoxStream<'a, T>, limit: &Arc<Limit>) -> BoxStream<'a, T>
+where
+    T: Send + 'a,
+{
+    let limit = limit.clone();
+    futures::stream::unfold(stream, move |mut stream| {
+        let limit = limit.clone();
+        async move {
+            let _permit = limit.acquire().await;
+            stream.next().await.map(|x| (x, stream))
+        }
+    })
+    .boxed()
+}
+
+/// Backend wrapper applying the listing and metadata limits, transfers are limited by [`RSync`] itself
+struct LimitedBackend {
+    inner: Box<dyn ReaderWriterBackend>,
+    limits: Arc<Limits>,
+}
+
+#[async_trait::async_trait]
+impl ReaderWriterBackend for LimitedBackend {
+    async fn is_valid(&self) -> RSyncResult<()> {
+        let _permit = self.limits.metadata.acquire().await;
+        self.inner.is_valid().await
+    }
+
+    async fn list(&self) -> BoxStream<'_, RSyncResult<RelativePath>> {
+        limited(self.inner.list().await, &self.limits.listing)
+    }
+
+    async fn list_with_metadata(&self) -> BoxStream<'_, RSyncResult<ListedEntry>> {
+        limited(self.inner.list_with_metadata().await, &self.limits.listing)
+    }
+
+    async fn list_in_scope(&self, scope: ListScope) -> BoxStream<'_, RSyncResult<RelativePath>> {
+        limited(self.inner.list_in_scope(scope).await, &self.limits.listing)
+    }
+
+    async fn list_with_metadata_in_scope(
+        &self,
+        scope: ListScope,
+    ) -> BoxStream<'_, RSyncResult<ListedEntry>> {
+        limited(
+            self.inner.list_with_metadata_in_scope(scope).await,
+            &self.limits.listing,
+        )
+    }
+
+    async fn read(&self, path: &RelativePath) -> ByteStream {
+        self.inner.read(path).await
+    }
+
+    async fn read_with_crc32c(&self, path: &RelativePath) -> (ByteStream, Option<u32>) {
+        self.inner.read_with_crc32c(path).await
+    }
+
+    async fn read_from(&self, path: &RelativePath, offset: Size) -> (ByteStream, Option<u32>) {
+        self.inner.read_from(path, offset).await
+    }
+
+    async fn get_crc32c(&self, path: &RelativePath) -> RSyncResult<Option<u32>> {
+        let _permit = self.limits.metadata.acquire().await;
+        self.inner.get_crc32c(path).await
+    }
+
+    async fn get_md5(&self, path: &RelativePath) -> RSyncResult<Option<String>> {
+        let _permit = self.limits.metadata.acquire().await;
+        self.inner.get_md5(path).await
+    }
+
+    async fn write(
+        &self,
+        mtime: Option<chrono::DateTime<chrono::Utc>>,
+        restore_fs_mtime: bool,
+        path: &RelativePath,
+        stream: ByteStream,
+    ) -> RSyncResult<()> {
+        self.inner
+            .write(mtime, restore_fs_mtime, path, stream)
+            .await
+    }
+
+    async fn write_checked(
+        &self,
+        mtime: Option<chrono::DateTime<chrono::Utc>>,
+        restore_fs_mtime: bool,
+        path: &RelativePath,
+        checks: WriteChecks,
+        stream: ByteStream,
+    ) -> RSyncResult<()> {
+        self.inner
+            .write_checked(mtime, restore_fs_mtime, path, checks, stream)
+            .await
+    }
+
+    fn supports_generation_match(&self) -> bool {
+        self.inner.supports_generation_match()
+    }
+
+    async fn resumable(&self, path: &RelativePath) -> RSyncResult<Option<PartialContent>> {
+        let _permit = self.limits.metadata.acquire().await;
+        self.inner.resumable(path).await
+    }
+
+    async fn delete(&self, path: &RelativePath) -> RSyncResult<()> {
+        let _permit = self.limits.metadata.acquire().await;
+        self.inner.delete(path).await
+    }
+
+    async fn delete_checked(
+        &self,
+        path: &RelativePath,
+        if_generation_match: Option<i64>,
+    ) -> RSyncResult<()> {
+        let _permit = self.limits.metadata.acquire().await;
+        self.inner.delete_checked(path, if_generation_match).await
+    }
+
+    async fn backup(&self, path: &RelativePath, backup_path: &RelativePath) -> RSyncResult<bool> {
+        let _permit = self.limits.metadata.acquire().await;
+        self.inner.backup(path, backup_path).await
+    }
+
+    async fn backup_before_delete(
+        &self,
+        path: &RelativePath,
+        backup_path: &RelativePath,
+    ) -> RSyncResult<bool> {
+        let _permit = self.limits.metadata.acquire().await;
+        self.inner.backup_before_delete(path, backup_path).await
+    }
+
+    async fn exists(&self, path: &RelativePath) -> RSyncResult<bool> {
+        let _permit = self.limits.metadata.acquire().await;
+        self.inner.exists(path).await
+    }
+
+    async fn size_and_mt(
+        &self,
+        path: &RelativePath,
+    ) -> RSyncResult<(Option<chrono::DateTime<chrono::Utc>>, Option<Size>)> {
+        let _permit = self.limits.metadata.acquire().await;
+        self.inner.size_and_mt(path).await
+    }
+
+    async fn entry_state(&self, path: &RelativePath) -> RSyncResult<EntryState> {
+        let _permit = self.limits.metadata.acquire().await;
+        self.inner.entry_state(path).await
+    }
+
+    fn is_remote(&self) -> bool {
+        self.inner.is_remote()
+    }
+}
+
+impl RSync {
+    /// Apply separate listing, metadata and transfer limits (see [`Concurrency`]) shared by all the operations of this client.
+    ///
+    /// The limits also apply when the streams of [`RSync::sync`] or [`RSync::mirror`] are driven by the caller, the last call wins
+    pub fn with_concurrency(mut self, concurrency: Concurrency) -> Self {
+        match &self.limits {
+            // the backends are only wrapped once, so that the limits of several calls never stack
+            Some(limits) => limits.set(&concurrency),
+            None => {
+                let limits = Limits::new(&concurrency);
+                self.source = Box::new(LimitedBackend {
+                    inner: self.source,
+                    limits: limits.clone(),
+                });
+                self.dest = Box::new(LimitedBackend {
+                    inner: self.dest,
+                    limits: limits.clone(),
+                });
+                self.limits = Some(limits);
+            }
+        }
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// Run [`RSync::sync`] within the [`Concurrency`] limits, results are yielded as soon as entries are done.
+    ///
+    /// The stream ends early when the [`super::ErrorPolicy`] gives up
+    ///
+    /// Example
+    /// ```rust
+    /// use futures::StreamExt;
+    /// use gcs_rsync::sync::{Concurrency, MemoryStore, RSync, ReaderWriter};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let source = MemoryStore::new();
+    ///     source.insert("hello.txt", "hello", None).unwrap();
+    ///     let concurrency = Concurrency {
+    ///         transfers: 4,
+    ///         ..Concurrency::default()
+    ///     };
+    ///     let rsync = RSync::new(ReaderWriter::memory(&source), ReaderWriter::memory(&MemoryStore::new()))
+    ///         .with_concurrency(concurrency);
+    ///
+    ///     rsync
+    ///         .run_sync()
+    ///         .await
+    ///         .for_each(|x| {
+    ///             println!("{:?}", x);
+    ///             futures::future::ready(())
+    ///         })
+    ///         .await;
+    /// }
+    /// ```
+    pub async fn run_sync(&self) -> impl Stream<Item = RSyncResult<RSyncStatus>> + '_ {
+        let statuses = self
+            .sync()
+            .await
+            .try_buffer_unordered(self.concurrency.window());
+        self.error_policy.apply(statuses, Result::is_err)
+    }
+
+    /// Run [`RSync::mirror`] within the [`Concurrency`] limits, results are yielded as soon as entries are done.
+    ///
+    /// The stream ends early when the [`super::ErrorPolicy`] gives up
+    pub async fn run_mirror(
+        &self,
+    ) -> RSyncResult<impl Stream<Item = RSyncResult<RMirrorStatus>> + '_> {
+        let statuses = self
+            .mirror()
+            .await?
+            .try_buffer_unordered(self.concurrency.window());
+        Ok(self.error_policy.apply(statuses, Result::is_err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use std::time::Duration;
+
+    use futures::stream::BoxStream;
+    use futures::TryStreamExt;
+
+    use crate::sync::testing::mtime;
+    use crate::sync::{
+        ByteStream, MemoryStore, RMirrorStatus, RSync, RSyncError, RSyncResult, ReaderWriter,
+        ReaderWriterBackend, RelativePath, Size,
+    };
+
+    use super::{Concurrency, Limit};
+
+    fn transient() -> RSyncResult<()> {
+        Err(RSyncError::StorageError(
+            crate::storage::Error::GcsTransientResponse {
+                url: "url".to_owned(),
+                status: 429,
+                value: "rate limit".to_owned(),
+            },
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_limit_waits_for_release() {
+        let limit = Limit::new(1, false);
+        let permit = limit.acquire().await;
+        let waiting = tokio::time::timeout(Duration::from_millis(20), limit.acquire()).await;
+        assert!(waiting.is_err(), "second permit should wait");
+        drop(permit);
+        let acquired = tokio::time::timeout(Duration::from_millis(20), limit.acquire()).await;
+        assert!(acquired.is_ok());
+    }
+
+    #[test]
+    fn test_adaptive_limit() {
+        let limit = Limit::new(8, true);
+        limit.record(&transient());
+        assert_eq!(4, limit.limit());
+        limit.record(&transient());
+        limit.record(&transient());
+        limit.record(&transient());
+        assert_eq!(1, limit.limit(), "never below 1");
+
+        limit.record(&Ok(()));
+        assert_eq!(2, limit.limit());
+        limit.record(&Ok(()));
+        assert_eq!(2, limit.limit(), "grows by one per limit successes");
+        limit.record(&Ok(()));
+        assert_eq!(3, limit.limit());
+
+        limit.record(&Err::<(), _>(RSyncError::EmptyRelativePathError));
+        assert_eq!(3, limit.limit(), "only transient errors back off");
+
+        let fixed = Limit::new(8, false);
+        fixed.record(&transient());
+        assert_eq!(8, fixed.limit());
+
+        fixed.set(2, true);
+        assert_eq!(2, fixed.limit());
+        fixed.record(&transient());
+        assert_eq!(1, fixed.limit(), "adaptive once set");
+    }
+
+    #[derive(Default)]
+    struct InFlight {
+        current: AtomicUsize,
+        max: AtomicUsize,
+    }
+
+    impl InFlight {
+        async fn run<T>(&self, f: impl std::future::Future<Output = T>) -> T {
+            let current = self.current.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max.fetch_max(current, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            let result = f.await;
+            self.current.fetch_sub(1, Ordering::SeqCst);
+            result
+        }
+    }
+
+    /// Destination recording the max number of metadata requests and writes in flight
+    struct InFlightStore {
+        store: MemoryStore,
+        metadata: Arc<InFlight>,
+        writes: Arc<InFlight>,
+    }
+
+    #[async_trait::async_trait]
+    impl ReaderWriterBackend for InFlightStore {
+        async fn is_valid(&self) -> RSyncResult<()> {
+            self.store.is_valid().await
+        }
+
+        async fn list(&self) -> BoxStream<'_, RSyncResult<RelativePath>> {
+            self.store.list().await
+        }
+
+        async fn read(&self, path: &RelativePath) -> ByteStream {
+            self.store.read(path).await
+        }
+
+        async fn get_crc32c(&self, path: &RelativePath) -> RSyncResult<Option<u32>> {
+            self.metadata.run(self.store.get_crc32c(path)).await
+        }
+
+        async fn write(
+            &self,
+            mtime: Option<chrono::DateTime<chrono::Utc>>,
+            restore_fs_mtime: bool,
+            path: &RelativePath,
+            stream: ByteStream,
+        ) -> RSyncResult<()> {
+            self.writes
+                .run(self.store.write(mtime, restore_fs_mtime, path, stream))
+                .await
+        }
+
+        async fn delete(&self, path: &RelativePath) -> RSyncResult<()> {
+            self.metadata.run(self.store.delete(path)).await
+        }
+
+        async fn exists(&self, path: &RelativePath) -> RSyncResult<bool> {
+            self.metadata.run(self.store.exists(path)).await
+        }
+
+        async fn size_and_mt(
+            &self,
+            path: &RelativePath,
+        ) -> RSyncResult<(Option<chrono::DateTime<chrono::Utc>>, Option<Size>)> {
+            self.metadata.run(self.store.size_and_mt(path)).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_mirror_concurrency_limits() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        for i in 0..20 {
+            source
+                .insert(&format!("file_{i:02}.txt"), format!("{i}"), mtime(1))
+                .unwrap();
+            dest.insert(&format!("old_{i:02}.txt"), "old", mtime(1))
+                .unwrap();
+        }
+        let (metadata, writes) = (Arc::new(InFlight::default()), Arc::new(InFlight::default()));
+        let in_flight = InFlightStore {
+            store: dest.clone(),
+            metadata: metadata.clone(),
+            writes: writes.clone(),
+        };
+        let rsync = RSync::new(ReaderWriter::memory(&source), ReaderWriter::new(in_flight))
+            .with_concurrency(Concurrency {
+                listing: 1,
+                metadata: 2,
+                transfers: 3,
+                adaptive: true,
+            });
+
+        let statuses = rsync
+            .run_mirror()
+            .await
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        let deleted = statuses
+            .iter()
+            .filter(|x| matches!(x, RMirrorStatus::Deleted(_)))
+            .count();
+        assert_eq!(20, deleted);
+        assert_eq!(20, dest.paths().len());
+        let (metadata, writes) = (
+            metadata.max.load(Ordering::SeqCst),
+            writes.max.load(Ordering::SeqCst),
+        );
+        assert!((1..=2).contains(&metadata), "{metadata} metadata requests");
+        assert!((2..=3).contains(&writes), "{writes} writes");
+    }
+
+    #[tokio::test]
+    async fn test_with_concurrency_limits_do_not_stack() {
+        let source = MemoryStore::new();
+        for i in 0..20 {
+            source
+                .insert(&format!("file_{i:02}.txt"), format!("{i}"), mtime(1))
+                .unwrap();
+        }
+        let (metadata, writes) = (Arc::new(InFlight::default()), Arc::new(InFlight::default()));
+        let in_flight = InFlightStore {
+            store: MemoryStore::new(),
+            metadata: metadata.clone(),
+            writes: writes.clone(),
+        };
+        let single = Concurrency {
+            listing: 1,
+            metadata: 1,
+            transfers: 1,
+            adaptive: false,
+        };
+        let rsync = RSync::new(ReaderWriter::memory(&source), ReaderWriter::new(in_flight))
+            .with_concurrency(single)
+            .with_concurrency(Concurrency {
+                listing: 1,
+                metadata: 4,
+                transfers: 4,
+                adaptive: false,
+            });
+
+        let statuses = rsync
+            .run_sync()
+            .await
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        assert_eq!(20, statuses.len());
+        let (metadata, writes) = (
+            metadata.max.load(Ordering::SeqCst),
+            writes.max.load(Ordering::SeqCst),
+        );
+        assert!((2..=4).contains(&metadata), "{metadata} metadata requests");
+        assert!((2..=4).contains(&writes), "{writes} writes");
+    }
+}
@@ -0,0 +1,200 @@
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
+
+use super::{EntryState, ListedEntry, RSync, RSyncResult, RelativePath, Size};
+
+/// Size and modification time bounds of the synced source entries, see [`RSync::with_min_size`], [`RSync::with_max_size`], [`RSync::with_newer_than`] and [`RSync::with_older_than`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(super) struct EntryFilter {
+    min_size: Option<Size>,
+    max_size: Option<Size>,
+    newer_than: Option<chrono::DateTime<chrono::Utc>>,
+    older_than: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl EntryFilter {
+    fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// An entry missing a bounded value (ex: no mtime with `newer_than`) is left out
+    fn is_included(&self, state: &EntryState) -> bool {
+        let size_ok = match state.size {
+            Some(size) => {
+                self.min_size.is_none_or(|min| size >= min)
+                    && self.max_size.is_none_or(|max| size <= max)
+            }
+            None => self.min_size.is_none() && self.max_size.is_none(),
+        };
+        let mtime_ok = match state.mtime {
+            Some(mtime) => {
+                self.newer_than.is_none_or(|t| mtime > t)
+                    && self.older_than.is_none_or(|t| mtime < t)
+            }
+            None => self.newer_than.is_none() && self.older_than.is_none(),
+        };
+        size_ok && mtime_ok
+    }
+}
+
+impl RSync {
+    /// Only sync source entries of at least `size` bytes
+    pub fn with_min_size(mut self, size: Size) -> Self {
+        self.entry_filter.min_size = Some(size);
+        self
+    }
+
+    /// Only sync source entries of at most `size` bytes
+    pub fn with_max_size(mut self, size: Size) -> Self {
+        self.entry_filter.max_size = Some(size);
+        self
+    }
+
+    /// Only sync source entries modified after `mtime` (ex: `Utc::now() - Duration::days(1)`)
+    pub fn with_newer_than(mut self, mtime: chrono::DateTime<chrono::Utc>) -> Self {
+        self.entry_filter.newer_than = Some(mtime);
+        self
+    }
+
+    /// Only sync source entries modified before `mtime` (ex: `Utc::now() - Duration::hours(1)`)
+    pub fn with_older_than(mut self, mtime: chrono::DateTime<chrono::Utc>) -> Self {
+        self.entry_filter.older_than = Some(mtime);
+        self
+    }
+
+    /// Check the size and age of a source entry, listed metadata is used when available and the entry is only requested otherwise
+    pub(super) async fn in_range(&self, source: &ListedEntry) -> RSyncResult<bool> {
+        if self.entry_filter.is_empty() {
+            return Ok(true);
+        }
+        Ok(match &source.metadata {
+            Some(state) => self.entry_filter.is_included(state),
+            None => {
+                let state = Self::entry_state(self.source.as_ref(), &source.path).await?;
+                self.entry_filter.is_included(&state)
+            }
+        })
+    }
+
+    /// Included source paths, the source is listed with its metadata when sizes or ages are filtered
+    pub(super) async fn source_paths(&self) -> BoxStream<'_, RSyncResult<RelativePath>> {
+        if self.entry_filter.is_empty() {
+            return self
+                .source
+                .list_in_scope(self.list_scope())
+                .await
+                .try_filter(|x| futures::future::ready(self.filter(x)))
+                .boxed();
+        }
+
+        self.source
+            .list_with_metadata_in_scope(self.list_scope())
+            .await
+            .try_filter(|x| futures::future::ready(self.filter(&x.path)))
+            .try_filter_map(move |entry| async move {
+                Ok(self.in_range(&entry).await?.then_some(entry.path))
+            })
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use chrono::TimeZone;
+
+    use crate::sync::testing::{
+        already_synced, created, mirror, mtime, not_deleted, rsync, sync, synced, updated,
+        CountingStore,
+    };
+    use crate::sync::{EntryState, MemoryStore, RSync};
+
+    use super::EntryFilter;
+
+    fn state(mtime: Option<i64>, size: Option<u64>) -> EntryState {
+        EntryState::new(
+            mtime.map(|x| chrono::Utc.timestamp_opt(x, 0).unwrap()),
+            size,
+        )
+    }
+
+    #[test]
+    fn test_entry_filter() {
+        let filter = EntryFilter {
+            min_size: Some(10),
+            max_size: Some(100),
+            newer_than: Some(chrono::Utc.timestamp_opt(1000, 0).unwrap()),
+            older_than: Some(chrono::Utc.timestamp_opt(2000, 0).unwrap()),
+        };
+        assert!(filter.is_included(&state(Some(1500), Some(10))));
+        assert!(filter.is_included(&state(Some(1500), Some(100))));
+        assert!(!filter.is_included(&state(Some(1500), Some(9))));
+        assert!(!filter.is_included(&state(Some(1500), Some(101))));
+        assert!(!filter.is_included(&state(Some(1000), Some(50))));
+        assert!(!filter.is_included(&state(Some(2000), Some(50))));
+        assert!(!filter.is_included(&state(None, Some(50))), "unknown mtime");
+
+        let size_only = EntryFilter {
+            max_size: Some(100),
+            ..EntryFilter::default()
+        };
+        assert!(size_only.is_included(&state(None, Some(50))));
+        assert!(EntryFilter::default().is_empty());
+        assert!(!size_only.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sync_size_and_age() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        source.insert("small.txt", "a", mtime(100)).unwrap();
+        source.insert("big.log", "0123456789!", mtime(100)).unwrap();
+        source.insert("old.log", "old", mtime(100)).unwrap();
+        source.insert("recent.log", "recent", mtime(5000)).unwrap();
+        dest.insert("big.log", "old big", mtime(1)).unwrap();
+
+        let rsync = rsync(&source, &dest)
+            .with_max_size(10)
+            .with_older_than(mtime(1000).unwrap());
+        assert_eq!(
+            vec![created("old.log"), created("small.txt")],
+            sync(&rsync).await
+        );
+        assert_eq!(dest.get("big.log").unwrap().content, "old big");
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let rsync = RSync::new(
+            CountingStore::reader_writer(&source, true, &requests),
+            CountingStore::reader_writer(&dest, true, &requests),
+        )
+        .with_min_size(2)
+        .with_older_than(mtime(1000).unwrap())
+        .with_listing_comparison(true);
+        assert_eq!(
+            vec![
+                synced(updated("different size or mtime", "big.log")),
+                synced(already_synced("same mtime and size", "old.log")),
+                not_deleted("big.log"),
+                not_deleted("old.log"),
+                not_deleted("small.txt"),
+            ],
+            mirror(&rsync).await
+        );
+        assert_eq!(0, requests.load(Ordering::SeqCst), "filtered from listings");
+
+        let rsync = rsync.with_listing_comparison(false);
+        assert_eq!(
+            vec![
+                synced(already_synced("same mtime and size", "big.log")),
+                synced(already_synced("same mtime and size", "old.log")),
+                not_deleted("big.log"),
+                not_deleted("old.log"),
+                not_deleted("small.txt"),
+            ],
+            mirror(&rsync).await
+        );
+    }
+}
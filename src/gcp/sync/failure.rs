@@ -0,0 +1,228 @@
+use std::{ops::Not, str::FromStr};
+
+use futures::{Stream, StreamExt};
+
+use super::{RSync, RSyncError, RelativePath};
+
+/// Backend operation of a failed entry
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum Operation {
+    /// size, mtime, crc32c or existence of the entry
+    Stat,
+    Read,
+    Write,
+    Delete,
+    /// copy or move of the destination entry before it is deleted or overwritten
+    Backup,
+    /// crc32c verification of the transferred content
+    Verify,
+}
+
+/// Entry failing with the error of a backend operation, see [`RSyncError::EntryFailed`]
+#[derive(Debug)]
+pub struct FailedEntry {
+    pub path: RelativePath,
+    pub operation: Operation,
+    pub error: RSyncError,
+}
+
+impl RSyncError {
+    /// Attach the entry path and operation to a backend error, errors already attached to an entry are kept as is
+    pub(super) fn entry_failed(self, path: &RelativePath, operation: Operation) -> Self {
+        match self {
+            Self::EntryFailed(_) => self,
+            error => Self::EntryFailed(Box::new(FailedEntry {
+                path: path.to_owned(),
+                operation,
+                error,
+            })),
+        }
+    }
+
+    /// Path of the entry the error relates to, `None` for errors not bound to an entry (ex: listing errors)
+    pub fn path(&self) -> Option<&RelativePath> {
+        match self {
+            Self::EntryFailed(entry) => Some(&entry.path),
+            Self::MemoryObjectNotFound(path) | Self::BisyncConflict(path) => Some(path),
+            Self::PlanOutdated { path, .. }
+            | Self::Crc32cMismatch { path, .. }
+            | Self::UnexpectedSyncAction { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+
+    pub fn operation(&self) -> Option<Operation> {
+        match self {
+            Self::EntryFailed(entry) => Some(entry.operation),
+            _ => None,
+        }
+    }
+}
+
+/// What the runners ([`RSync::run_sync`], [`RSync::run_mirror`] and [`RSync::bisync`]) do when entries fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorPolicy {
+    /// Stop at the first error, entries in flight are cancelled
+    FailFast,
+    /// Go on with the other entries and report every error
+    #[default]
+    Continue,
+    /// Stop once this number of errors is reached
+    AbortAfter(usize),
+}
+
+impl ErrorPolicy {
+    fn max_errors(self) -> usize {
+        match self {
+            Self::FailFast => 1,
+            Self::Continue => usize::MAX,
+            Self::AbortAfter(n) => n.max(1),
+        }
+    }
+
+    /// End the stream right after the item making the error count reach the limit
+    pub(super) fn apply<S, T>(self, stream: S, is_err: fn(&T) -> bool) -> impl Stream<Item = T>
+    where
+        S: Stream<Item = T>,
+    {
+        let max_errors = self.max_errors();
+        stream.scan(0, move |errors, item| {
+            if *errors >= max_errors {
+                return futures::future::ready(None);
+            }
+            if is_err(&item) {
+                *errors += 1;
+            }
+            futures::future::ready(Some(item))
+        })
+    }
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "fail-fast" => Ok(Self::FailFast),
+            None if s == "continue" => Ok(Self::Continue),
+            Some(("abort-after", n)) => n
+                .parse()
+                .map(Self::AbortAfter)
+                .map_err(|_| format!("invalid number of errors {n}")),
+            _ => Err(format!(
+                "unknown error policy {s}, expected fail-fast, continue or abort-after:<errors>"
+            )),
+        }
+    }
+}
+
+impl RSync {
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    /// Only sync (and delete for mirror) these paths, ex: to retry the failed paths of a previous run (see [`super::SyncReport::failed_paths`])
+    pub fn with_only_paths<I>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = RelativePath>,
+    {
+        self.only_paths = Some(paths.into_iter().collect());
+        self
+    }
+
+    /// Entries under the [`super::Backup`] directory are never selected
+    pub(super) fn selected(&self, path: &RelativePath) -> bool {
+        self.is_backup(path).not()
+            && self
+                .only_paths
+                .as_ref()
+                .is_none_or(|paths| paths.contains(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use futures::StreamExt;
+
+    use crate::sync::testing::{created, flaky_rsync, mtime, rsync, sync};
+    use crate::sync::MemoryStore;
+
+    use super::{ErrorPolicy, Operation};
+
+    async fn run(policy: ErrorPolicy) -> Vec<Result<u8, u8>> {
+        let items = vec![Ok(1), Err(2), Ok(3), Err(4), Err(5), Ok(6)];
+        policy
+            .apply(futures::stream::iter(items), Result::is_err)
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_error_policy() {
+        assert_eq!(vec![Ok(1), Err(2)], run(ErrorPolicy::FailFast).await);
+        assert_eq!(
+            vec![Ok(1), Err(2), Ok(3), Err(4)],
+            run(ErrorPolicy::AbortAfter(2)).await
+        );
+        assert_eq!(6, run(ErrorPolicy::Continue).await.len());
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!(
+            Ok(ErrorPolicy::FailFast),
+            ErrorPolicy::from_str("fail-fast")
+        );
+        assert_eq!(Ok(ErrorPolicy::Continue), ErrorPolicy::from_str("continue"));
+        assert_eq!(
+            Ok(ErrorPolicy::AbortAfter(10)),
+            ErrorPolicy::from_str("abort-after:10")
+        );
+        assert!(ErrorPolicy::from_str("abort-after:x").is_err());
+        assert!(ErrorPolicy::from_str("never").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sync_error_policy() {
+        let source = MemoryStore::new();
+        source.insert("a.txt", "a", mtime(1)).unwrap();
+        source.insert("b.txt", "b", mtime(1)).unwrap();
+        source.insert("c.txt", "c", mtime(1)).unwrap();
+
+        let (flaky, _) = flaky_rsync(&source, &MemoryStore::new(), 3, 1);
+        let flaky = flaky.with_error_policy(ErrorPolicy::FailFast);
+        let statuses = flaky.run_sync().await.collect::<Vec<_>>().await;
+        assert_eq!(1, statuses.len());
+        let error = statuses.into_iter().next().unwrap().unwrap_err();
+        assert_eq!(Some(Operation::Write), error.operation());
+        assert!(error.path().is_some());
+        assert!(error.is_retryable());
+
+        let (flaky, _) = flaky_rsync(&source, &MemoryStore::new(), 3, 1);
+        let flaky = flaky.with_error_policy(ErrorPolicy::AbortAfter(2));
+        let statuses = flaky.run_sync().await.collect::<Vec<_>>().await;
+        assert_eq!(2, statuses.len());
+        assert!(statuses.iter().all(Result::is_err));
+
+        let dest = MemoryStore::new();
+        let (flaky, _) = flaky_rsync(&source, &dest, 2, 1);
+        let report = flaky.sync_report().await;
+        assert_eq!((1, 2), (report.created, report.failed));
+        let failed = report.failed_paths();
+        assert_eq!(2, failed.len());
+
+        let retry = rsync(&source, &dest).with_only_paths(failed.to_owned());
+        let expected = failed
+            .iter()
+            .map(|x| created(x.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(expected, sync(&retry).await);
+        assert_eq!(3, dest.paths().len());
+    }
+}
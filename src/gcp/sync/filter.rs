@@ -0,0 +1,610 @@
+use std::{ops::Not, sync::Arc};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+use super::{RSync, RSyncError, RSyncResult, RelativePath};
+
+fn glob_error(error: globset::Error) -> RSyncError {
+    RSyncError::GlobError(error.to_string())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterAction {
+    Include,
+    Exclude,
+}
+
+#[derive(Debug, Clone)]
+struct FilterRule {
+    action: FilterAction,
+    matcher: GlobSet,
+    files: bool,
+    dirs: bool,
+}
+
+impl FilterRule {
+    /// rsync pattern: a leading `/` anchors it to the root, otherwise it matches the end of the path,
+    /// a trailing `/` only matches directories and a trailing `/***` matches a directory and all its content
+    fn pattern(action: FilterAction, pattern: &str) -> RSyncResult<Self> {
+        let (pattern, dirs_only) = match pattern.strip_suffix('/') {
+            Some(pattern) => (pattern, true),
+            None => (pattern, false),
+        };
+        let (pattern, with_content) = match pattern.strip_suffix("/***") {
+            Some(pattern) => (pattern, true),
+            None => (pattern, false),
+        };
+        let pattern = match pattern.strip_prefix('/') {
+            Some(anchored) => anchored.to_owned(),
+            None => format!("**/{pattern}"),
+        };
+        if pattern.is_empty() || pattern == "**/" {
+            return Err(RSyncError::InvalidFilterRule("empty pattern".to_owned()));
+        }
+
+        let mut builder = GlobSetBuilder::new();
+        let mut add = |glob: &str| -> RSyncResult<()> {
+            let glob = GlobBuilder::new(glob)
+                .literal_separator(true)
+                .build()
+                .map_err(glob_error)?;
+            builder.add(glob);
+            Ok(())
+        };
+        add(&pattern)?;
+        if with_content {
+            add(&format!("{pattern}/**"))?;
+        }
+        Ok(Self {
+            action,
+            matcher: builder.build().map_err(glob_error)?,
+            files: dirs_only.not() || with_content,
+            dirs: true,
+        })
+    }
+
+    /// Full path globs of [`RSync::with_includes`] and [`RSync::with_excludes`], only matching files
+    fn globs(action: FilterAction, globs: &[&str]) -> RSyncResult<Option<Self>> {
+        if globs.is_empty() {
+            return Ok(None);
+        }
+        let mut builder = GlobSetBuilder::new();
+        for glob in globs {
+            builder.add(globset::Glob::new(glob).map_err(glob_error)?);
+        }
+        Ok(Some(Self {
+            action,
+            matcher: builder.build().map_err(glob_error)?,
+            files: true,
+            dirs: false,
+        }))
+    }
+
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        (if is_dir { self.dirs } else { self.files }) && self.matcher.is_match(path)
+    }
+}
+
+/// Ordered rsync style filter rules, the first matching rule decides whether a path is included.
+///
+/// Paths matching no rule are included. Like rsync, a file is excluded when one of its parent directories is excluded,
+/// rules ending with `/` only match directories. See [`FilterRules::parse`] for the supported syntax.
+///
+/// Example
+/// ```rust
+/// use gcs_rsync::sync::{FilterRules, RelativePath};
+///
+/// let rules = FilterRules::parse("+ */keep/**\n- *.tmp\n- /cache/\n").unwrap();
+/// let included = |path: &str| rules.is_included(&RelativePath::new(path).unwrap());
+///
+/// assert!(included("a/keep/b.tmp"));
+/// assert!(!included("a/b.tmp"));
+/// assert!(!included("cache/index.json"));
+/// assert!(included("a/cache/index.json"));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct FilterRules {
+    rules: Vec<FilterRule>,
+}
+
+impl FilterRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn include(mut self, pattern: &str) -> RSyncResult<Self> {
+        self.rules
+            .push(FilterRule::pattern(FilterAction::Include, pattern)?);
+        Ok(self)
+    }
+
+    pub fn exclude(mut self, pattern: &str) -> RSyncResult<Self> {
+        self.rules
+            .push(FilterRule::pattern(FilterAction::Exclude, pattern)?);
+        Ok(self)
+    }
+
+    /// Append the rules of `other` after the current ones
+    pub fn extend(mut self, other: FilterRules) -> Self {
+        self.rules.extend(other.rules);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    fn parse_lines<F>(content: &str, parse_line: F) -> RSyncResult<Self>
+    where
+        F: Fn(Self, &str) -> RSyncResult<Self>,
+    {
+        let lines = content
+            .lines()
+            .map(|line| line.trim_end_matches('\r'))
+            .filter(|line| line.trim().is_empty().not())
+            .filter(|line| line.starts_with('#').not() && line.starts_with(';').not());
+        let mut rules = Self::new();
+        for line in lines {
+            rules = match line.trim() {
+                "!" | "clear" => Self::new(),
+                _ => parse_line(rules, line)?,
+            };
+        }
+        Ok(rules)
+    }
+
+    /// Parse rsync filter rules (`--filter-from`), one rule per line:
+    /// - `+ PATTERN` or `include PATTERN`
+    /// - `- PATTERN` or `exclude PATTERN`
+    /// - `!` or `clear` to drop the previous rules
+    ///
+    /// Empty lines and lines starting with `#` or `;` are ignored, rule modifiers and merge rules are not supported
+    pub fn parse(content: &str) -> RSyncResult<Self> {
+        Self::parse_lines(content, |rules, line| match line.split_once(' ') {
+            Some(("+" | "include", pattern)) => rules.include(pattern),
+            Some(("-" | "exclude", pattern)) => rules.exclude(pattern),
+            _ => Err(RSyncError::InvalidFilterRule(format!(
+                "unsupported filter rule {line}"
+            ))),
+        })
+    }
+
+    /// Parse an rsync exclude file (`--exclude-from`), one pattern per line, lines starting with `+ ` or `- ` are include or exclude rules
+    pub fn parse_excludes(content: &str) -> RSyncResult<Self> {
+        Self::parse_lines(content, |rules, line| {
+            if let Some(pattern) = line.strip_prefix("+ ") {
+                rules.include(pattern)
+            } else {
+                rules.exclude(line.strip_prefix("- ").unwrap_or(line))
+            }
+        })
+    }
+
+    fn has_include_rules(&self) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.action == FilterAction::Include)
+    }
+
+    fn first_match(&self, path: &str, is_dir: bool) -> Option<FilterAction> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(path, is_dir))
+            .map(|rule| rule.action)
+    }
+
+    /// A directory is excluded when the first matching rule excludes it, its content is then excluded too
+    fn is_dir_excluded(&self, dir: &str) -> bool {
+        self.first_match(dir, true) == Some(FilterAction::Exclude)
+    }
+
+    fn is_parent_excluded(&self, path: &str) -> bool {
+        path.match_indices('/')
+            .any(|(i, _)| self.is_dir_excluded(&path[..i]))
+    }
+
+    pub fn is_included(&self, path: &RelativePath) -> bool {
+        let path = path.as_str();
+        self.is_parent_excluded(path).not()
+            && self.first_match(path, false) != Some(FilterAction::Exclude)
+    }
+}
+
+/// Filter of a [`RSync`] client: the [`RSync::with_excludes`] globs, then the [`FilterRules`] in order
+/// and last the [`RSync::with_includes`] globs (everything else is excluded when there are includes)
+#[derive(Debug, Clone, Default)]
+pub(super) struct Filter {
+    excludes: Option<FilterRule>,
+    rules: FilterRules,
+    includes: Option<FilterRule>,
+    /// Directories whose content is excluded by the `dir/**` exclude globs
+    excluded_dirs: Option<GlobSet>,
+    /// Literal prefixes of the include globs, an included path starts with one of them
+    included_prefixes: Vec<String>,
+}
+
+/// Part of a glob before any special character, every path matching the glob starts with it
+fn literal_prefix(glob: &str) -> &str {
+    let end = glob.find(['*', '?', '[', '{', '\\']).unwrap_or(glob.len());
+    &glob[..end]
+}
+
+impl Filter {
+    /// No file under this directory can be included, listings can skip it
+    fn is_dir_excluded(&self, dir: &str) -> bool {
+        if self.rules.is_dir_excluded(dir) || self.rules.is_parent_excluded(dir) {
+            return true;
+        }
+        if self.excluded_dirs.as_ref().is_some_and(|x| x.is_match(dir)) {
+            return true;
+        }
+        if self.includes.is_none() || self.rules.has_include_rules() {
+            return false;
+        }
+        let dir = format!("{dir}/");
+        self.included_prefixes
+            .iter()
+            .all(|prefix| prefix.starts_with(&dir).not() && dir.starts_with(prefix.as_str()).not())
+    }
+
+    /// Prefix shared by all the included paths, `None` when any path can be included
+    fn prefix(&self) -> Option<&str> {
+        if self.includes.is_none() || self.rules.has_include_rules() {
+            return None;
+        }
+        fn common_len(x: &str, y: &str) -> usize {
+            x.char_indices()
+                .zip(y.chars())
+                .find(|((_, x), y)| x != y)
+                .map_or(x.len().min(y.len()), |((i, _), _)| i)
+        }
+
+        let (first, others) = self.included_prefixes.split_first()?;
+        let len = others
+            .iter()
+            .fold(first.len(), |len, prefix| common_len(&first[..len], prefix));
+        let prefix = &first[..len];
+        prefix.is_empty().not().then_some(prefix)
+    }
+
+    pub(super) fn is_included(&self, path: &RelativePath) -> bool {
+        let path = path.as_str();
+        if self.rules.is_parent_excluded(path) {
+            return false;
+        }
+        if self
+            .excludes
+            .as_ref()
+            .is_some_and(|x| x.matches(path, false))
+        {
+            return false;
+        }
+        match self.rules.first_match(path, false) {
+            Some(action) => action == FilterAction::Include,
+            None => self
+                .includes
+                .as_ref()
+                .is_none_or(|includes| includes.matches(path, false)),
+        }
+    }
+}
+
+impl RSync {
+    /// Include only the paths matching one of these globs (full path), they are checked after the [`FilterRules`]
+    pub fn with_includes(mut self, includes: &[&str]) -> RSyncResult<Self> {
+        let filter = Arc::make_mut(&mut self.filter);
+        filter.includes = FilterRule::globs(FilterAction::Include, includes)?;
+        filter.included_prefixes = includes
+            .iter()
+            .map(|glob| literal_prefix(glob).to_owned())
+            .collect();
+        Ok(self)
+    }
+
+    /// Exclude the paths matching one of these globs (full path), they are checked before the [`FilterRules`]
+    pub fn with_excludes(mut self, excludes: &[&str]) -> RSyncResult<Self> {
+        let filter = Arc::make_mut(&mut self.filter);
+        filter.excludes = FilterRule::globs(FilterAction::Exclude, excludes)?;
+        let dirs = excludes
+            .iter()
+            .filter_map(|glob| glob.strip_suffix("/**"))
+            .collect::<Vec<_>>();
+        filter.excluded_dirs = FilterRule::globs(FilterAction::Exclude, &dirs)?.map(|x| x.matcher);
+        Ok(self)
+    }
+
+    /// Append ordered rsync style filter rules, they are checked after the [`RSync::with_excludes`] globs and before the [`RSync::with_includes`] globs
+    pub fn with_filter_rules(mut self, rules: FilterRules) -> Self {
+        let filter = Arc::make_mut(&mut self.filter);
+        filter.rules = std::mem::take(&mut filter.rules).extend(rules);
+        self
+    }
+
+    /// Like rsync `--delete-excluded`, mirror also deletes the excluded destination entries.
+    ///
+    /// By default excluded destination entries are protected and reported as [`super::RMirrorStatus::Excluded`].
+    /// Excluded directories pruned from the destination listing (see [`ListScope`]) are not enumerated, so only the
+    /// protected entries that are actually listed are reported
+    pub fn with_delete_excluded(mut self, delete_excluded: bool) -> Self {
+        self.delete_excluded = delete_excluded;
+        self
+    }
+
+    /// Scope of the listings that are only used for included paths
+    pub(super) fn list_scope(&self) -> ListScope {
+        ListScope {
+            filter: Some(self.filter.clone()),
+            leftovers: false,
+        }
+    }
+
+    /// Scope of the mirror destination listing, excluded entries are only needed when they are deleted and leftovers are deleted
+    pub(super) fn dest_list_scope(&self) -> ListScope {
+        let scope = if self.delete_excluded {
+            ListScope::all()
+        } else {
+            self.list_scope()
+        };
+        scope.with_leftovers()
+    }
+
+    /// Excluded destination entry kept by mirror, see [`RSync::with_delete_excluded`]
+    pub(super) fn is_protected(&self, path: &RelativePath) -> bool {
+        self.delete_excluded.not() && self.filter.is_included(path).not()
+    }
+}
+
+/// What a listing can skip because it cannot be included (see [`super::ReaderWriterBackend::list_in_scope`])
+#[derive(Debug, Clone, Default)]
+pub struct ListScope {
+    filter: Option<Arc<Filter>>,
+    leftovers: bool,
+}
+
+impl ListScope {
+    /// Nothing is skipped
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    /// No path under this directory (relative path without trailing `/`) can be included, it does not have to be listed
+    pub fn is_dir_excluded(&self, dir: &str) -> bool {
+        self.filter.as_ref().is_some_and(|x| x.is_dir_excluded(dir))
+    }
+
+    /// Every included path starts with this prefix, it can be used to narrow a prefix listing
+    pub fn prefix(&self) -> Option<&str> {
+        self.filter.as_ref().and_then(|x| x.prefix())
+    }
+
+    /// Also list the leftovers of interrupted writes so that mirror deletes them
+    pub fn with_leftovers(mut self) -> Self {
+        self.leftovers = true;
+        self
+    }
+
+    /// Leftovers of interrupted writes (ex: fs partial files) are listed, they are skipped by default
+    pub fn lists_leftovers(&self) -> bool {
+        self.leftovers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sync::testing::{
+        already_synced, created, deleted, excluded, mirror, mtime, not_deleted, path, rsync, sync,
+        synced,
+    };
+    use crate::sync::{MemoryStore, RSync, ReaderWriter, RelativePath};
+
+    use super::{Filter, FilterAction, FilterRule, FilterRules, ListScope};
+
+    fn included(rules: &FilterRules, path: &str) -> bool {
+        rules.is_included(&RelativePath::new(path).unwrap())
+    }
+
+    #[test]
+    fn test_first_match_wins() {
+        let rules = FilterRules::new()
+            .include("*/keep/**")
+            .unwrap()
+            .exclude("*.tmp")
+            .unwrap()
+            .include("*")
+            .unwrap();
+        assert!(included(&rules, "a/keep/b.tmp"));
+        assert!(included(&rules, "a/keep/c/d.tmp"));
+        assert!(!included(&rules, "a/b.tmp"));
+        assert!(!included(&rules, "b.tmp"));
+        assert!(included(&rules, "a/b.txt"));
+    }
+
+    #[test]
+    fn test_anchored_and_directory_patterns() {
+        let rules = FilterRules::new()
+            .exclude("/build/")
+            .unwrap()
+            .exclude("node_modules")
+            .unwrap()
+            .exclude("logs/")
+            .unwrap();
+        assert!(!included(&rules, "build/out.o"));
+        assert!(included(&rules, "src/build/out.o"), "anchored to the root");
+        assert!(!included(&rules, "web/node_modules/x/index.js"));
+        assert!(!included(&rules, "node_modules"));
+        assert!(!included(&rules, "a/logs/today.log"));
+        assert!(included(&rules, "a/logs"), "a file is not a directory");
+        assert!(included(&rules, "a/logs.txt"));
+    }
+
+    #[test]
+    fn test_star_does_not_cross_directories() {
+        let rules = FilterRules::new()
+            .exclude("/*.json")
+            .unwrap()
+            .exclude("/data/*/raw/***")
+            .unwrap();
+        assert!(!included(&rules, "a.json"));
+        assert!(included(&rules, "a/b.json"));
+        assert!(!included(&rules, "data/2024/raw/x/y.csv"));
+        assert!(included(&rules, "data/2024/01/raw/y.csv"));
+    }
+
+    #[test]
+    fn test_excluded_parent_cannot_be_included_back() {
+        let rules = FilterRules::new()
+            .exclude("/a/")
+            .unwrap()
+            .include("*.txt")
+            .unwrap();
+        assert!(!included(&rules, "a/b.txt"));
+    }
+
+    #[test]
+    fn test_parse() {
+        let content =
+            "# comment\n; comment\n\n+ *.rs\r\ninclude Cargo.toml\n- *\n!\nexclude *.tmp\n";
+        let rules = FilterRules::parse(content).unwrap();
+        assert!(included(&rules, "src/main.rs"));
+        assert!(!included(&rules, "a.tmp"), "rules before ! are cleared");
+
+        assert!(FilterRules::parse("P protected").is_err());
+        assert!(FilterRules::parse("+").is_err());
+
+        let rules = FilterRules::parse_excludes("*.tmp\n+ keep.tmp\n- *.log\n").unwrap();
+        assert!(!included(&rules, "a.tmp"));
+        assert!(!included(&rules, "a.log"));
+        assert!(included(&rules, "a.txt"));
+    }
+
+    #[test]
+    fn test_filter_with_globs() {
+        let filter = Filter {
+            excludes: FilterRule::globs(FilterAction::Exclude, &["*.json"]).unwrap(),
+            rules: FilterRules::new().include("/a/**").unwrap(),
+            includes: FilterRule::globs(FilterAction::Include, &["b/*"]).unwrap(),
+            ..Filter::default()
+        };
+        let included = |path: &str| filter.is_included(&RelativePath::new(path).unwrap());
+        assert!(!included("a/x.json"), "excludes come first");
+        assert!(included("a/x.txt"));
+        assert!(included("b/c/x.txt"), "globs are full path globs");
+        assert!(!included("c/x.txt"), "not in includes");
+    }
+
+    fn memory_rsync() -> RSync {
+        let store = MemoryStore::new();
+        RSync::new(ReaderWriter::memory(&store), ReaderWriter::memory(&store))
+    }
+
+    #[test]
+    fn test_list_scope_excluded_dirs() {
+        let rsync = memory_rsync()
+            .with_excludes(&["**/node_modules/**", ".git/**", "*.tmp"])
+            .unwrap()
+            .with_filter_rules(FilterRules::new().exclude("/target/").unwrap());
+        let scope = rsync.list_scope();
+        assert!(scope.is_dir_excluded("node_modules"));
+        assert!(scope.is_dir_excluded("web/node_modules"));
+        assert!(scope.is_dir_excluded(".git"));
+        assert!(scope.is_dir_excluded("target"));
+        assert!(scope.is_dir_excluded("target/debug"));
+        assert!(!scope.is_dir_excluded("src/.git"));
+        assert!(!scope.is_dir_excluded("src/target"));
+        assert!(!scope.is_dir_excluded("a.tmp"), "only dir/** globs prune");
+        assert_eq!(None, scope.prefix());
+        assert!(!ListScope::all().is_dir_excluded("node_modules"));
+    }
+
+    #[test]
+    fn test_list_scope_includes() {
+        let rsync = memory_rsync()
+            .with_includes(&["data/2024/**/*.csv", "data/2023-*/**"])
+            .unwrap();
+        let scope = rsync.list_scope();
+        assert_eq!(Some("data/202"), scope.prefix());
+        assert!(!scope.is_dir_excluded("data"));
+        assert!(!scope.is_dir_excluded("data/2024"));
+        assert!(!scope.is_dir_excluded("data/2024/01"));
+        assert!(!scope.is_dir_excluded("data/2023-01"));
+        assert!(scope.is_dir_excluded("data/1999"));
+        assert!(scope.is_dir_excluded("logs"));
+
+        let with_rules = rsync.with_filter_rules(FilterRules::new().include("*.md").unwrap());
+        let scope = with_rules.list_scope();
+        assert_eq!(None, scope.prefix(), "include rules can add any path");
+        assert!(!scope.is_dir_excluded("logs"));
+
+        let any_dir = memory_rsync().with_includes(&["**/*.json"]).unwrap();
+        assert_eq!(None, any_dir.list_scope().prefix());
+    }
+
+    #[tokio::test]
+    async fn test_sync_filter_rules() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        for p in [
+            "a/keep/b.tmp",
+            "a/b.tmp",
+            "a/b.txt",
+            "cache/c.bin",
+            "a/cache/c.bin",
+        ] {
+            source.insert(p, p, mtime(1)).unwrap();
+        }
+        let rules = FilterRules::parse("+ */keep/**\n- *.tmp\n- /cache/\n").unwrap();
+        let rsync = rsync(&source, &dest)
+            .with_excludes(&["**/*.bin"])
+            .unwrap()
+            .with_filter_rules(rules.to_owned());
+        assert_eq!(
+            vec![created("a/b.txt"), created("a/keep/b.tmp")],
+            sync(&rsync).await
+        );
+
+        let rsync = rsync.with_excludes(&[]).unwrap();
+        assert_eq!(
+            vec![
+                synced(created("a/cache/c.bin")),
+                synced(already_synced("same mtime and size", "a/b.txt")),
+                synced(already_synced("same mtime and size", "a/keep/b.tmp")),
+                not_deleted("a/b.txt"),
+                not_deleted("a/keep/b.tmp"),
+            ],
+            mirror(&rsync).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mirror_protects_excluded_entries() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        for p in ["a.txt", "b.json"] {
+            source.insert(p, p, mtime(1)).unwrap();
+            dest.insert(p, p, mtime(1)).unwrap();
+        }
+
+        let rsync = rsync(&source, &dest).with_excludes(&["*.json"]).unwrap();
+        assert_eq!(
+            vec![
+                synced(already_synced("same mtime and size", "a.txt")),
+                not_deleted("a.txt"),
+                excluded("b.json"),
+            ],
+            mirror(&rsync).await,
+            "excluded files are protected by default"
+        );
+        assert_eq!(vec![path("a.txt"), path("b.json")], dest.paths());
+
+        let rsync = rsync.with_delete_excluded(true);
+        assert_eq!(
+            vec![
+                synced(already_synced("same mtime and size", "a.txt")),
+                deleted("b.json"),
+                not_deleted("a.txt"),
+            ],
+            mirror(&rsync).await
+        );
+        assert_eq!(vec![path("a.txt")], dest.paths());
+    }
+}
@@ -1,16 +1,26 @@
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashSet,
+    ops::Not,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
 
 use bytes::Bytes;
-use futures::{Stream, TryStream, TryStreamExt};
+use futures::{stream::BoxStream, Stream, StreamExt, TryStream, TryStreamExt};
 use tokio::{
     fs,
     io::{AsyncWriteExt, BufWriter},
 };
 use tokio_util::codec::{BytesCodec, FramedRead};
 
-use crate::sync::RSyncError;
+use md5::Digest;
+
+use crate::{storage::MD5, sync::RSyncError};
 
-use super::{Entry, RSyncResult, RelativePath};
+use super::{
+    ByteStream, EntryState, ListScope, ListedEntry, PartialContent, RSyncResult,
+    ReaderWriterBackend, RelativePath, Size, WriteChecks,
+};
 
 struct FsPrefix {
     base_path: PathBuf,
@@ -37,12 +47,53 @@ impl FsPrefix {
     }
 }
 
+const PARTIAL_SUFFIX: &str = ".gcs-rsync-partial";
+
+/// Longest file name most file systems accept (`NAME_MAX`)
+const MAX_NAME_LEN: usize = 255;
+
+/// Temporary file a write goes through before being renamed to `file_path`, in the same directory so that the rename is atomic.
+///
+/// Names too long once prefixed and suffixed are replaced by their md5
+fn partial_path(file_path: &Path) -> PathBuf {
+    let name = file_path.file_name().unwrap_or_default().to_string_lossy();
+    let partial = format!(".{name}{PARTIAL_SUFFIX}");
+    if partial.len() <= MAX_NAME_LEN {
+        return file_path.with_file_name(partial);
+    }
+    let md5 = MD5::new(md5::Md5::digest(name.as_bytes()).into()).to_hex();
+    file_path.with_file_name(format!(".{md5}{PARTIAL_SUFFIX}"))
+}
+
+fn is_partial(name: &std::ffi::OsStr) -> bool {
+    let name = name.to_string_lossy();
+    name.starts_with('.') && name.ends_with(PARTIAL_SUFFIX)
+}
+
+/// Partial file registered while it is written so that mirror never deletes it, see [`FsClient::is_writing`]
+struct Writing<'a> {
+    writing: &'a Mutex<HashSet<PathBuf>>,
+    partial_path: PathBuf,
+}
+
+impl Drop for Writing<'_> {
+    fn drop(&mut self) {
+        lock(self.writing).remove(&self.partial_path);
+    }
+}
+
+fn lock(writing: &Mutex<HashSet<PathBuf>>) -> MutexGuard<'_, HashSet<PathBuf>> {
+    writing
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
 pub(super) struct FsClient {
     prefix: FsPrefix,
+    keep_partial: bool,
+    writing: Mutex<HashSet<PathBuf>>,
 }
 
-type Size = u64;
-
 impl FsClient {
     pub(super) async fn is_valid(&self) -> RSyncResult<()> {
         let path = self.prefix.base_path.as_path();
@@ -62,42 +113,132 @@ impl FsClient {
 
     pub(super) fn new(base_path: &Path) -> Self {
         let prefix = FsPrefix::new(base_path);
-        Self { prefix }
+        Self {
+            prefix,
+            keep_partial: false,
+            writing: Mutex::default(),
+        }
     }
 
-    pub(super) async fn list(&self) -> impl Stream<Item = RSyncResult<RelativePath>> + '_ {
-        futures::stream::try_unfold(
-            vec![self.prefix.base_path.to_owned()],
-            move |mut state| async move {
+    /// Keep the partial file of a failed write instead of removing it so that the next write of the file can resume it (see [`FsClient::resumable`])
+    pub(super) fn with_keep_partial(mut self, keep_partial: bool) -> Self {
+        self.keep_partial = keep_partial;
+        self
+    }
+
+    /// Partial file of a write in progress of this client
+    fn is_writing(&self, file_path: &Path) -> bool {
+        lock(&self.writing).contains(file_path)
+    }
+
+    /// Directories out of the scope are not read at all.
+    ///
+    /// Partial files are skipped unless the scope lists leftovers, partial files of writes in progress are always skipped.
+    /// Entries removed while walking (ex: partial file renamed) are skipped
+    fn walk(
+        &self,
+        scope: ListScope,
+    ) -> impl Stream<Item = RSyncResult<(RelativePath, std::fs::Metadata)>> + '_ {
+        let base_path = self.prefix.base_path.to_owned();
+        futures::stream::try_unfold(vec![base_path.to_owned()], move |mut state| {
+            let scope = scope.clone();
+            let base_path = base_path.to_owned();
+            async move {
                 match state.pop() {
                     None => Ok(None),
                     Some(path) => {
                         let path = path.as_path();
-                        let mut read_dir = tokio::fs::read_dir(path)
-                            .await
-                            .map_err(|err| RSyncError::fs_io_error("read dir failed", path, err))?;
+                        let mut read_dir = match tokio::fs::read_dir(path).await {
+                            Ok(read_dir) => read_dir,
+                            Err(err)
+                                if err.kind() == std::io::ErrorKind::NotFound
+                                    && path != base_path =>
+                            {
+                                return Ok(Some((futures::stream::iter(Vec::new()), state)))
+                            }
+                            Err(err) => {
+                                return Err(RSyncError::fs_io_error("read dir failed", path, err))
+                            }
+                        };
                         let mut files = Vec::new();
                         while let Some(entry) = read_dir.next_entry().await.map_err(|err| {
                             RSyncError::fs_io_error("next entry failed", path, err)
                         })? {
-                            let metadata = entry.metadata().await.map_err(|err| {
-                                RSyncError::fs_io_error(
-                                    "reading metadata failed",
-                                    entry.path(),
-                                    err,
-                                )
-                            })?;
+                            let partial = is_partial(entry.file_name().as_os_str());
+                            if partial
+                                && (scope.lists_leftovers().not() || self.is_writing(&entry.path()))
+                            {
+                                continue;
+                            }
+                            let metadata = match entry.metadata().await {
+                                Ok(metadata) => metadata,
+                                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                                Err(err) => {
+                                    return Err(RSyncError::fs_io_error(
+                                        "reading metadata failed",
+                                        entry.path(),
+                                        err,
+                                    ))
+                                }
+                            };
                             if metadata.is_dir() {
-                                state.push(entry.path());
+                                let dir = self.prefix.as_relative_path(entry.path().as_path())?;
+                                if !scope.is_dir_excluded(dir.path.as_str()) {
+                                    state.push(entry.path());
+                                }
                             } else {
-                                files.push(self.prefix.as_relative_path(entry.path().as_path()));
+                                files.push(
+                                    self.prefix
+                                        .as_relative_path(entry.path().as_path())
+                                        .map(|path| (path, metadata)),
+                                );
                             }
                         }
                         Ok(Some((futures::stream::iter(files), state)))
                     }
                 }
-            },
-        )
+            }
+        })
+        .try_flatten()
+    }
+
+    pub(super) async fn list(&self) -> impl Stream<Item = RSyncResult<RelativePath>> + '_ {
+        self.list_in_scope(ListScope::all()).await
+    }
+
+    pub(super) async fn list_in_scope(
+        &self,
+        scope: ListScope,
+    ) -> impl Stream<Item = RSyncResult<RelativePath>> + '_ {
+        self.walk(scope).map_ok(|(path, _)| path)
+    }
+
+    pub(super) async fn list_with_metadata(
+        &self,
+    ) -> impl Stream<Item = RSyncResult<ListedEntry>> + '_ {
+        self.list_with_metadata_in_scope(ListScope::all()).await
+    }
+
+    /// The file system is not listed in order, all entries are sorted in memory
+    pub(super) async fn list_with_metadata_in_scope(
+        &self,
+        scope: ListScope,
+    ) -> impl Stream<Item = RSyncResult<ListedEntry>> + '_ {
+        futures::stream::once(async move {
+            let mut entries = self
+                .walk(scope)
+                .map_ok(|(path, metadata)| {
+                    let state = metadata
+                        .modified()
+                        .ok()
+                        .map(|mtime| EntryState::new(Some(mtime.into()), Some(metadata.len())));
+                    ListedEntry::new(path, state)
+                })
+                .try_collect::<Vec<_>>()
+                .await?;
+            entries.sort_by(|x, y| x.path.cmp(&y.path));
+            Ok(futures::stream::iter(entries.into_iter().map(Ok)))
+        })
         .try_flatten()
     }
 
@@ -119,23 +260,66 @@ impl FsClient {
         .try_flatten()
     }
 
-    pub(super) async fn get_crc32c(&self, path: &RelativePath) -> RSyncResult<Option<Entry>> {
+    pub(super) async fn get_crc32c(&self, path: &RelativePath) -> RSyncResult<Option<u32>> {
         let file_path = self.prefix.as_file_path(path);
+        Ok(Self::crc32c_and_size(file_path.as_path())
+            .await?
+            .map(|(crc32c, _)| crc32c))
+    }
 
-        if let Ok(file) = fs::File::open(file_path.as_path()).await {
+    async fn crc32c_and_size(file_path: &Path) -> RSyncResult<Option<(u32, Size)>> {
+        if let Ok(file) = fs::File::open(file_path).await {
             let mut frame =
                 FramedRead::with_capacity(file, BytesCodec::new(), crate::DEFAULT_BUF_SIZE);
 
-            let mut crc32c: u32 = 0;
+            let (mut crc32c, mut size): (u32, Size) = (0, 0);
             while let Some(data) = frame
                 .try_next()
                 .await
-                .map_err(|e| RSyncError::fs_io_error("crc32c failed", file_path.as_path(), e))?
+                .map_err(|e| RSyncError::fs_io_error("crc32c failed", file_path, e))?
             {
                 crc32c = crc32c::crc32c_append(crc32c, &data);
+                size += data.len() as Size;
             }
 
-            Ok(Some(Entry::new(path, crc32c)))
+            Ok(Some((crc32c, size)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Non empty partial file kept by a failed write of the entry, unless a write of this client is in progress
+    pub(super) async fn resumable(
+        &self,
+        path: &RelativePath,
+    ) -> RSyncResult<Option<PartialContent>> {
+        let partial_path = partial_path(self.prefix.as_file_path(path).as_path());
+        if self.keep_partial.not() || self.is_writing(partial_path.as_path()) {
+            return Ok(None);
+        }
+        let partial = Self::crc32c_and_size(partial_path.as_path()).await?;
+        Ok(partial
+            .filter(|(_, size)| *size > 0)
+            .map(|(crc32c, size)| PartialContent { size, crc32c }))
+    }
+
+    pub(super) async fn get_md5(&self, path: &RelativePath) -> RSyncResult<Option<String>> {
+        let file_path = self.prefix.as_file_path(path);
+
+        if let Ok(file) = fs::File::open(file_path.as_path()).await {
+            let mut frame =
+                FramedRead::with_capacity(file, BytesCodec::new(), crate::DEFAULT_BUF_SIZE);
+
+            let mut md5 = md5::Md5::new();
+            while let Some(data) = frame
+                .try_next()
+                .await
+                .map_err(|e| RSyncError::fs_io_error("md5 failed", file_path.as_path(), e))?
+            {
+                md5.update(&data);
+            }
+
+            Ok(Some(MD5::new(md5.finalize().into()).to_hex()))
         } else {
             Ok(None)
         }
@@ -163,14 +347,141 @@ impl FsClient {
         }
     }
 
+    /// Partial files of writes in progress are left as is
     pub(super) async fn delete(&self, path: &RelativePath) -> RSyncResult<()> {
         let file_path = self.prefix.as_file_path(path);
-        fs::remove_file(file_path.as_path())
+        let removed = match file_path.file_name().is_some_and(is_partial) {
+            true => {
+                let writing = lock(&self.writing);
+                match writing.contains(&file_path) {
+                    true => Ok(()),
+                    false => std::fs::remove_file(file_path.as_path()),
+                }
+            }
+            false => fs::remove_file(file_path.as_path()).await,
+        };
+        match removed {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(RSyncError::fs_io_error(
+                "remove file failed",
+                file_path.as_path(),
+                e,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    async fn backup_file_path(&self, backup_path: &RelativePath) -> RSyncResult<PathBuf> {
+        let backup_file_path = self.prefix.as_file_path(backup_path);
+        if let Some(parent) = backup_file_path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .map_err(|e| RSyncError::fs_io_error("create dir all failed", parent, e))?
+        }
+        Ok(backup_file_path)
+    }
+
+    fn backup_exists_error(backup_file_path: &Path) -> RSyncError {
+        RSyncError::fs_io_error(
+            "backup file already exists",
+            backup_file_path,
+            std::io::ErrorKind::AlreadyExists.into(),
+        )
+    }
+
+    /// Copy the file to a new file, an existing file is never overwritten
+    async fn copy_new(
+        file_path: &Path,
+        backup_file_path: &Path,
+        metadata: &std::fs::Metadata,
+    ) -> RSyncResult<()> {
+        let copy_error = |e| RSyncError::fs_io_error("copy to backup failed", file_path, e);
+        let mut source = fs::File::open(file_path).await.map_err(copy_error)?;
+        let mut backup = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(backup_file_path)
+            .await
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::AlreadyExists => Self::backup_exists_error(backup_file_path),
+                _ => RSyncError::fs_io_error("create backup file failed", backup_file_path, e),
+            })?;
+        tokio::io::copy(&mut source, &mut backup)
+            .await
+            .map_err(copy_error)?;
+        backup
+            .set_permissions(metadata.permissions())
+            .await
+            .map_err(copy_error)?;
+        Ok(())
+    }
+
+    /// The file is hard linked to the backup path, or copied with its mtime when it cannot be linked (ex: other file system), and left in place.
+    ///
+    /// An existing backup file is never overwritten, the backup fails instead
+    pub(super) async fn backup(
+        &self,
+        path: &RelativePath,
+        backup_path: &RelativePath,
+    ) -> RSyncResult<bool> {
+        let file_path = self.prefix.as_file_path(path);
+        let metadata = match fs::metadata(file_path.as_path()).await {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(false),
+        };
+        let backup_file_path = self.backup_file_path(backup_path).await?;
+        match fs::hard_link(file_path.as_path(), backup_file_path.as_path()).await {
+            Ok(()) => return Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                return Err(Self::backup_exists_error(backup_file_path.as_path()))
+            }
+            Err(_) => {}
+        }
+        Self::copy_new(file_path.as_path(), backup_file_path.as_path(), &metadata).await?;
+        if let Ok(mtime) = metadata.modified() {
+            Self::set_mtime(backup_file_path.as_path(), mtime.into())?;
+        }
+        Ok(true)
+    }
+
+    /// The file is deleted afterwards so it is moved to the backup path, partial files of writes in progress are not.
+    ///
+    /// An existing backup file is never overwritten, the backup fails instead
+    pub(super) async fn backup_before_delete(
+        &self,
+        path: &RelativePath,
+        backup_path: &RelativePath,
+    ) -> RSyncResult<bool> {
+        let file_path = self.prefix.as_file_path(path);
+        if fs::metadata(file_path.as_path()).await.is_err() || self.is_writing(&file_path) {
+            return Ok(false);
+        }
+        let backup_file_path = self.backup_file_path(backup_path).await?;
+        if fs::symlink_metadata(backup_file_path.as_path())
             .await
-            .map_err(|e| RSyncError::fs_io_error("remove file failed", file_path.as_path(), e))
+            .is_ok()
+        {
+            return Err(Self::backup_exists_error(backup_file_path.as_path()));
+        }
+        fs::rename(file_path.as_path(), backup_file_path.as_path())
+            .await
+            .map_err(|e| {
+                RSyncError::fs_io_error("rename to backup failed", file_path.as_path(), e)
+            })?;
+        Ok(true)
     }
 
-    async fn write_internal<S>(&self, file_path: &Path, stream: S) -> RSyncResult<()>
+    /// Write to a partial file, then fsync it, set its mtime and rename it over `file_path` so that a failed write never leaves a truncated file.
+    ///
+    /// The partial file is removed on failure unless partial files are kept, a partial file left by an interrupted run is
+    /// overwritten by the next write of the file and deleted by mirror otherwise (see [`ListScope::lists_leftovers`]).
+    /// With `resume_from` the stream is appended to the kept partial file instead, which must still hold that many bytes
+    async fn write_internal<S>(
+        &self,
+        file_path: &Path,
+        mtime: Option<chrono::DateTime<chrono::Utc>>,
+        resume_from: Option<Size>,
+        stream: S,
+    ) -> RSyncResult<()>
     where
         S: TryStream<Ok = Bytes, Error = RSyncError>,
     {
@@ -180,30 +491,102 @@ impl FsClient {
                 .map_err(|e| RSyncError::fs_io_error("create dir all failed", parent, e))?
         }
 
-        let file = fs::File::create(file_path)
-            .await
-            .map_err(|e| RSyncError::fs_io_error("create file failed", file_path, e))?;
+        let partial_path = partial_path(file_path);
+        lock(&self.writing).insert(partial_path.to_owned());
+        let _writing = Writing {
+            writing: &self.writing,
+            partial_path: partial_path.to_owned(),
+        };
+        let result = Self::write_partial(partial_path.as_path(), mtime, resume_from, stream).await;
+        let result = match result {
+            Ok(()) => fs::rename(partial_path.as_path(), file_path)
+                .await
+                .map_err(|e| RSyncError::fs_io_error("rename partial file failed", file_path, e)),
+            Err(e) => Err(e),
+        };
+        // a partial file failing the crc32c verification cannot be resumed
+        let corrupted = matches!(result, Err(RSyncError::Crc32cMismatch { .. }));
+        if result.is_err() && (!self.keep_partial || corrupted) {
+            let _ = fs::remove_file(partial_path.as_path()).await;
+        }
+        result
+    }
+
+    async fn write_partial<S>(
+        file_path: &Path,
+        mtime: Option<chrono::DateTime<chrono::Utc>>,
+        resume_from: Option<Size>,
+        stream: S,
+    ) -> RSyncResult<()>
+    where
+        S: TryStream<Ok = Bytes, Error = RSyncError>,
+    {
+        let file = match resume_from {
+            Some(size) => Self::open_resumed(file_path, size).await?,
+            None => fs::File::create(file_path)
+                .await
+                .map_err(|e| RSyncError::fs_io_error("create file failed", file_path, e))?,
+        };
 
-        let mut buf_writer = stream
-            .try_fold(
-                BufWriter::with_capacity(crate::DEFAULT_BUF_SIZE, file),
-                |mut buf_writer, data| async move {
-                    buf_writer.write_all(&data).await.map_err(|e| {
-                        RSyncError::fs_io_error("buffered write to file failed", file_path, e)
-                    })?;
-                    Ok(buf_writer)
-                },
-            )
-            .await?;
+        let mut buf_writer = BufWriter::with_capacity(crate::DEFAULT_BUF_SIZE, file);
+        let stream = stream.into_stream();
+        futures::pin_mut!(stream);
+        // received data is flushed before a stream error is returned so that a kept partial file holds it
+        let mut stream_error = None;
+        while let Some(data) = stream.next().await {
+            match data {
+                Ok(data) => buf_writer.write_all(&data).await.map_err(|e| {
+                    RSyncError::fs_io_error("buffered write to file failed", file_path, e)
+                })?,
+                Err(e) => {
+                    stream_error = Some(e);
+                    break;
+                }
+            }
+        }
 
         buf_writer
             .flush()
             .await
             .map_err(|e| RSyncError::fs_io_error("buffer flush to file failed", file_path, e))?;
+        buf_writer
+            .into_inner()
+            .sync_all()
+            .await
+            .map_err(|e| RSyncError::fs_io_error("fsync failed", file_path, e))?;
+        if let Some(e) = stream_error {
+            return Err(e);
+        }
 
+        if let Some(mtime) = mtime {
+            Self::set_mtime(file_path, mtime)?;
+        }
         Ok(())
     }
 
+    /// Open the partial file to append to it, it must still hold the `size` bytes the write resumes from
+    async fn open_resumed(file_path: &Path, size: Size) -> RSyncResult<fs::File> {
+        let file = fs::OpenOptions::new()
+            .append(true)
+            .open(file_path)
+            .await
+            .map_err(|e| RSyncError::fs_io_error("open partial file failed", file_path, e))?;
+        let len = file
+            .metadata()
+            .await
+            .map_err(|e| RSyncError::fs_io_error("partial file metadata failed", file_path, e))?
+            .len();
+        if len != size {
+            let error = std::io::Error::other(format!("{len} bytes instead of {size}"));
+            return Err(RSyncError::fs_io_error(
+                "partial file changed",
+                file_path,
+                error,
+            ));
+        }
+        Ok(file)
+    }
+
     fn set_mtime(path: &Path, mtime: chrono::DateTime<chrono::Utc>) -> RSyncResult<()> {
         filetime::set_file_mtime(path, filetime::FileTime::from_system_time(mtime.into()))
             .map_err(|e| RSyncError::fs_io_error("set_mtime failed", path, e))
@@ -214,7 +597,8 @@ impl FsClient {
         S: TryStream<Ok = Bytes, Error = RSyncError>,
     {
         let file_path = self.prefix.as_file_path(path);
-        self.write_internal(file_path.as_path(), stream).await
+        self.write_internal(file_path.as_path(), None, None, stream)
+            .await
     }
 
     pub(super) async fn write_mtime<S>(
@@ -227,10 +611,305 @@ impl FsClient {
         S: TryStream<Ok = Bytes, Error = RSyncError>,
     {
         let file_path = self.prefix.as_file_path(path);
-        let file_path = file_path.as_path();
-        self.write_internal(file_path, stream).await?;
-        Self::set_mtime(file_path, mtime)?;
+        self.write_internal(file_path.as_path(), Some(mtime), None, stream)
+            .await
+    }
+}
 
-        Ok(())
+#[async_trait::async_trait]
+impl ReaderWriterBackend for FsClient {
+    async fn is_valid(&self) -> RSyncResult<()> {
+        self.is_valid().await
+    }
+
+    async fn list(&self) -> BoxStream<'_, RSyncResult<RelativePath>> {
+        self.list().await.boxed()
+    }
+
+    async fn list_with_metadata(&self) -> BoxStream<'_, RSyncResult<ListedEntry>> {
+        self.list_with_metadata().await.boxed()
+    }
+
+    async fn list_in_scope(&self, scope: ListScope) -> BoxStream<'_, RSyncResult<RelativePath>> {
+        self.list_in_scope(scope).await.boxed()
+    }
+
+    async fn list_with_metadata_in_scope(
+        &self,
+        scope: ListScope,
+    ) -> BoxStream<'_, RSyncResult<ListedEntry>> {
+        self.list_with_metadata_in_scope(scope).await.boxed()
+    }
+
+    async fn read(&self, path: &RelativePath) -> ByteStream {
+        self.read(path).await.boxed()
+    }
+
+    async fn get_crc32c(&self, path: &RelativePath) -> RSyncResult<Option<u32>> {
+        self.get_crc32c(path).await
+    }
+
+    async fn get_md5(&self, path: &RelativePath) -> RSyncResult<Option<String>> {
+        self.get_md5(path).await
+    }
+
+    async fn write(
+        &self,
+        mtime: Option<chrono::DateTime<chrono::Utc>>,
+        restore_fs_mtime: bool,
+        path: &RelativePath,
+        stream: ByteStream,
+    ) -> RSyncResult<()> {
+        match (mtime, restore_fs_mtime) {
+            (Some(mtime), true) => self.write_mtime(mtime, path, stream).await,
+            _ => self.write(path, stream).await,
+        }
+    }
+
+    async fn write_checked(
+        &self,
+        mtime: Option<chrono::DateTime<chrono::Utc>>,
+        restore_fs_mtime: bool,
+        path: &RelativePath,
+        checks: WriteChecks,
+        stream: ByteStream,
+    ) -> RSyncResult<()> {
+        let mtime = mtime.filter(|_| restore_fs_mtime);
+        let file_path = self.prefix.as_file_path(path);
+        self.write_internal(file_path.as_path(), mtime, checks.resume_from, stream)
+            .await
+    }
+
+    async fn resumable(&self, path: &RelativePath) -> RSyncResult<Option<PartialContent>> {
+        self.resumable(path).await
+    }
+
+    async fn delete(&self, path: &RelativePath) -> RSyncResult<()> {
+        self.delete(path).await
+    }
+
+    async fn backup(&self, path: &RelativePath, backup_path: &RelativePath) -> RSyncResult<bool> {
+        self.backup(path, backup_path).await
+    }
+
+    async fn backup_before_delete(
+        &self,
+        path: &RelativePath,
+        backup_path: &RelativePath,
+    ) -> RSyncResult<bool> {
+        self.backup_before_delete(path, backup_path).await
+    }
+
+    async fn exists(&self, path: &RelativePath) -> RSyncResult<bool> {
+        self.exists(path).await
+    }
+
+    async fn size_and_mt(
+        &self,
+        path: &RelativePath,
+    ) -> RSyncResult<(Option<chrono::DateTime<chrono::Utc>>, Option<Size>)> {
+        self.size_and_mt(path).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use chrono::TimeZone;
+    use futures::{StreamExt, TryStreamExt};
+
+    use crate::sync::{ListScope, RSyncError, RSyncResult, RelativePath};
+
+    use super::{is_partial, partial_path, FsClient, MAX_NAME_LEN};
+
+    fn stream(chunks: Vec<RSyncResult<&'static str>>) -> super::ByteStream {
+        futures::stream::iter(chunks.into_iter().map(|x| x.map(Bytes::from))).boxed()
+    }
+
+    fn broken() -> super::ByteStream {
+        stream(vec![Ok("new"), Err(RSyncError::EmptyRelativePathError)])
+    }
+
+    #[tokio::test]
+    async fn test_atomic_write() {
+        let mut base_path = std::env::temp_dir();
+        base_path.push("rsync_fs_tests");
+        base_path.push(uuid::Uuid::new_v4().hyphenated().to_string());
+        let path = RelativePath::new("a/b.txt").unwrap();
+        let file_path = base_path.join("a/b.txt");
+        let partial = partial_path(file_path.as_path());
+
+        let client = FsClient::new(base_path.as_path());
+        let mtime = chrono::Utc.timestamp_opt(1706745599, 123_456_789).unwrap();
+        client
+            .write_mtime(mtime, &path, stream(vec![Ok("old")]))
+            .await
+            .unwrap();
+        assert_eq!(
+            (Some(mtime), Some(3)),
+            client.size_and_mt(&path).await.unwrap()
+        );
+        assert!(!partial.exists());
+
+        assert!(client.write(&path, broken()).await.is_err());
+        assert_eq!("old", std::fs::read_to_string(&file_path).unwrap());
+        assert!(!partial.exists(), "partial file is removed");
+
+        let client = client.with_keep_partial(true);
+        assert!(client.write(&path, broken()).await.is_err());
+        assert_eq!("old", std::fs::read_to_string(&file_path).unwrap());
+        assert_eq!("new", std::fs::read_to_string(&partial).unwrap());
+        assert_eq!(
+            vec![path.to_owned()],
+            client.list().await.try_collect::<Vec<_>>().await.unwrap(),
+            "partial files are not listed"
+        );
+
+        client
+            .write(&path, stream(vec![Ok("newer")]))
+            .await
+            .unwrap();
+        assert_eq!("newer", std::fs::read_to_string(&file_path).unwrap());
+        assert!(!partial.exists(), "partial file is renamed");
+        std::fs::remove_dir_all(base_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_backup_keeps_the_file_until_overwritten() {
+        let mut base_path = std::env::temp_dir();
+        base_path.push("rsync_fs_tests");
+        base_path.push(uuid::Uuid::new_v4().hyphenated().to_string());
+        let path = RelativePath::new("a.txt").unwrap();
+        let backup = RelativePath::new("backup/a.txt").unwrap();
+        let deleted_backup = RelativePath::new("backup/deleted/a.txt").unwrap();
+
+        let client = FsClient::new(base_path.as_path());
+        let mtime = chrono::Utc.timestamp_opt(1706745599, 0).unwrap();
+        client
+            .write_mtime(mtime, &path, stream(vec![Ok("old")]))
+            .await
+            .unwrap();
+        assert!(client.backup(&path, &backup).await.unwrap());
+        assert!(client.write(&path, broken()).await.is_err());
+        assert_eq!(
+            "old",
+            std::fs::read_to_string(base_path.join("a.txt")).unwrap(),
+            "a failed overwrite keeps the file"
+        );
+
+        client.write(&path, stream(vec![Ok("new")])).await.unwrap();
+        assert_eq!(
+            "new",
+            std::fs::read_to_string(base_path.join("a.txt")).unwrap()
+        );
+        assert_eq!(
+            (Some(mtime), Some(3)),
+            client.size_and_mt(&backup).await.unwrap()
+        );
+        assert_eq!(
+            "old",
+            std::fs::read_to_string(base_path.join("backup/a.txt")).unwrap()
+        );
+
+        assert!(client
+            .backup_before_delete(&path, &deleted_backup)
+            .await
+            .unwrap());
+        assert!(!client.exists(&path).await.unwrap(), "moved before delete");
+        assert!(!client.backup(&path, &backup).await.unwrap());
+        std::fs::remove_dir_all(base_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_backup_never_overwrites_a_backup_file() {
+        let mut base_path = std::env::temp_dir();
+        base_path.push("rsync_fs_tests");
+        base_path.push(uuid::Uuid::new_v4().hyphenated().to_string());
+        let path = RelativePath::new("a.txt").unwrap();
+        let backup = RelativePath::new("backup/a.txt").unwrap();
+        let backup_file_path = base_path.join("backup/a.txt");
+
+        let client = FsClient::new(base_path.as_path());
+        client.write(&path, stream(vec![Ok("new")])).await.unwrap();
+        std::fs::create_dir_all(base_path.join("backup")).unwrap();
+        std::fs::write(backup_file_path.as_path(), "old").unwrap();
+
+        assert!(client.backup(&path, &backup).await.is_err());
+        assert!(FsClient::copy_new(
+            base_path.join("a.txt").as_path(),
+            backup_file_path.as_path(),
+            &std::fs::metadata(base_path.join("a.txt")).unwrap()
+        )
+        .await
+        .is_err());
+        assert!(client.backup_before_delete(&path, &backup).await.is_err());
+        assert_eq!(
+            "old",
+            std::fs::read_to_string(backup_file_path.as_path()).unwrap()
+        );
+        assert!(client.exists(&path).await.unwrap());
+
+        std::fs::remove_file(backup_file_path.as_path()).unwrap();
+        assert!(client.backup(&path, &backup).await.unwrap());
+        std::fs::remove_file(backup_file_path.as_path()).unwrap();
+        let copy = RelativePath::new("copy/a.txt").unwrap();
+        let copy_file_path = client.backup_file_path(&copy).await.unwrap();
+        FsClient::copy_new(
+            base_path.join("a.txt").as_path(),
+            copy_file_path.as_path(),
+            &std::fs::metadata(base_path.join("a.txt")).unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!("new", std::fs::read_to_string(copy_file_path).unwrap());
+        std::fs::remove_dir_all(base_path).unwrap();
+    }
+
+    #[test]
+    fn test_partial_path() {
+        let dir = std::path::Path::new("/tmp/a");
+        assert_eq!(
+            dir.join(".b.txt.gcs-rsync-partial"),
+            partial_path(dir.join("b.txt").as_path())
+        );
+
+        let long = partial_path(dir.join("x".repeat(MAX_NAME_LEN)).as_path());
+        let name = long.file_name().unwrap();
+        assert!(name.len() <= MAX_NAME_LEN);
+        assert!(is_partial(name));
+        assert_eq!(Some(dir), long.parent());
+        assert_ne!(
+            long,
+            partial_path(dir.join("y".repeat(MAX_NAME_LEN)).as_path())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_leftover_partial_files() {
+        let mut base_path = std::env::temp_dir();
+        base_path.push("rsync_fs_tests");
+        base_path.push(uuid::Uuid::new_v4().hyphenated().to_string());
+        let path = RelativePath::new("a.txt").unwrap();
+        let leftover = RelativePath::new(".a.txt.gcs-rsync-partial").unwrap();
+
+        let client = FsClient::new(base_path.as_path()).with_keep_partial(true);
+        assert!(client.write(&path, broken()).await.is_err());
+        let list = |scope| async {
+            client
+                .list_in_scope(scope)
+                .await
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap()
+        };
+        assert!(list(ListScope::all()).await.is_empty());
+        assert_eq!(
+            vec![leftover.to_owned()],
+            list(ListScope::all().with_leftovers()).await
+        );
+
+        client.delete(&leftover).await.unwrap();
+        assert!(list(ListScope::all().with_leftovers()).await.is_empty());
+        std::fs::remove_dir_all(base_path).unwrap();
     }
 }
@@ -1,9 +1,11 @@
-use crate::storage::{Error as StorageError, Metadata, ObjectMetadata};
+use crate::storage::{Error as StorageError, MtimeMetadata, ObjectWithMtime, CRC32C};
 use bytes::Bytes;
-use chrono::TimeZone;
-use futures::{Stream, StreamExt, TryStreamExt};
+use futures::{stream::BoxStream, Stream, StreamExt, TryStreamExt};
 
-use super::{Entry, RSyncError, RelativePath};
+use super::{
+    integrity, resume, ByteStream, EntryState, ListScope, ListedEntry, RSyncError,
+    ReaderWriterBackend, RelativePath, Size, WriteChecks,
+};
 use crate::{
     gcp::sync::RSyncResult,
     oauth2::token::TokenGenerator,
@@ -20,6 +22,7 @@ struct ObjectPrefix {
     bucket: String,
     prefix: String,
     objects_list_request: ObjectsListRequest,
+    objects_list_with_metadata_request: ObjectsListRequest,
 }
 
 impl ObjectPrefix {
@@ -33,10 +36,18 @@ impl ObjectPrefix {
             ..Default::default()
         };
 
+        let objects_list_with_metadata_request = ObjectsListRequest {
+            fields: Some(format!(
+                "items(name,size,generation,crc32c,md5Hash,{MTIME_FIELDS}),nextPageToken"
+            )),
+            ..objects_list_request.to_owned()
+        };
+
         Self {
             bucket,
             prefix,
             objects_list_request,
+            objects_list_with_metadata_request,
         }
     }
 
@@ -44,6 +55,34 @@ impl ObjectPrefix {
         prefix.rfind('/').map(|pos| prefix[..pos + 1].to_owned())
     }
 
+    /// Listing prefix narrowed to the common prefix of the included paths, `None` when no listed object can be included
+    fn list_prefix(&self, included_prefix: Option<&str>) -> Option<String> {
+        let Some(included_prefix) = included_prefix else {
+            return Some(self.prefix.to_owned());
+        };
+        let folder = Self::try_get_folder(&self.prefix).unwrap_or_default();
+        let narrowed = format!("{folder}{included_prefix}");
+        if narrowed.starts_with(self.prefix.as_str()) {
+            Some(narrowed)
+        } else if self.prefix.starts_with(narrowed.as_str()) {
+            Some(self.prefix.to_owned())
+        } else {
+            None
+        }
+    }
+
+    fn list_request(
+        &self,
+        request: &ObjectsListRequest,
+        scope: &ListScope,
+    ) -> Option<ObjectsListRequest> {
+        self.list_prefix(scope.prefix())
+            .map(|prefix| ObjectsListRequest {
+                prefix: Some(prefix),
+                ..request.to_owned()
+            })
+    }
+
     fn as_object(&self, name: &RelativePath) -> RSyncResult<Object> {
         let name = name.path.as_str();
         let name = {
@@ -67,7 +106,17 @@ impl ObjectPrefix {
     }
 }
 
-type Size = u64;
+const MTIME_FIELDS: &str = "metadata/goog-reserved-file-mtime,metadata/gcs-rsync-file-mtime-nanos";
+
+/// State of an object from its metadata, objects without the nanoseconds metadata (ex: written by gsutil) have a whole seconds mtime
+fn object_state(metadata: Option<MtimeMetadata>, size: Option<Size>) -> EntryState {
+    let mtime_in_seconds = metadata.as_ref().is_some_and(MtimeMetadata::is_in_seconds);
+    let mtime = metadata.and_then(|x| x.modification_date_time());
+    EntryState {
+        mtime_in_seconds,
+        ..EntryState::new(mtime, size)
+    }
+}
 
 impl GcsClient {
     pub(super) async fn new(
@@ -86,10 +135,13 @@ impl GcsClient {
     }
 
     pub(super) fn no_auth(bucket: &str, prefix: &str) -> Self {
-        let object_client = ObjectClient::no_auth();
+        Self::with_client(ObjectClient::no_auth(), bucket, prefix)
+    }
+
+    pub(super) fn with_client(client: ObjectClient, bucket: &str, prefix: &str) -> Self {
         let object_prefix = ObjectPrefix::new(bucket, prefix);
         Self {
-            client: object_client,
+            client,
             object_prefix,
         }
     }
@@ -101,38 +153,138 @@ impl GcsClient {
             .map_err(RSyncError::StorageError)
     }
 
+    /// Objects listed with `request` narrowed to the scope
+    async fn list_objects(
+        &self,
+        request: &ObjectsListRequest,
+        scope: ListScope,
+    ) -> impl Stream<Item = RSyncResult<ObjectWithMtime>> + '_ {
+        let request = self.object_prefix.list_request(request, &scope);
+        futures::stream::iter(request)
+            .then(move |request| async move {
+                self.client
+                    .list_as(&self.object_prefix.bucket, &request)
+                    .await
+                    .map_err(RSyncError::StorageError)
+            })
+            .flatten()
+    }
+
     pub(super) async fn list(&self) -> impl Stream<Item = RSyncResult<RelativePath>> + '_ {
-        self.client
-            .list(
-                &self.object_prefix.bucket,
-                &self.object_prefix.objects_list_request,
-            )
+        self.list_in_scope(ListScope::all()).await
+    }
+
+    pub(super) async fn list_in_scope(
+        &self,
+        scope: ListScope,
+    ) -> impl Stream<Item = RSyncResult<RelativePath>> + '_ {
+        self.list_objects(&self.object_prefix.objects_list_request, scope)
             .await
-            .map_err(RSyncError::StorageError)
             .map(move |r| {
-                r.and_then(|po| {
-                    po.name
+                r.and_then(|x| {
+                    x.object
+                        .name
                         .ok_or_else(|| RSyncError::MissingFieldsInGcsResponse("name".to_owned()))
                         .and_then(|name| self.object_prefix.as_relative_path(&name))
                 })
             })
     }
 
+    pub(super) async fn list_with_metadata(
+        &self,
+    ) -> impl Stream<Item = RSyncResult<ListedEntry>> + '_ {
+        self.list_with_metadata_in_scope(ListScope::all()).await
+    }
+
+    pub(super) async fn list_with_metadata_in_scope(
+        &self,
+        scope: ListScope,
+    ) -> impl Stream<Item = RSyncResult<ListedEntry>> + '_ {
+        self.list_objects(
+            &self.object_prefix.objects_list_with_metadata_request,
+            scope,
+        )
+        .await
+        .map(move |r| {
+            r.and_then(
+                |ObjectWithMtime {
+                     object: po,
+                     metadata,
+                 }| {
+                    let name = po
+                        .name
+                        .ok_or_else(|| RSyncError::MissingFieldsInGcsResponse("name".to_owned()))?;
+                    let state = EntryState {
+                        crc32c: po.crc32c.map(|x| x.to_u32()),
+                        md5: po.md5_hash.map(|x| x.to_hex()),
+                        generation: po.generation,
+                        ..object_state(metadata, po.size)
+                    };
+                    let path = self.object_prefix.as_relative_path(&name)?;
+                    Ok(ListedEntry::new(path, Some(state)))
+                },
+            )
+        })
+    }
+
     pub(super) async fn read(&self, path: &RelativePath) -> impl Stream<Item = RSyncResult<Bytes>> {
+        self.read_with_crc32c(path).await.0
+    }
+
+    /// The crc32c is the one gcs sends along with the content, see [`ObjectClient::download_with_crc32c`]
+    pub(super) async fn read_with_crc32c(
+        &self,
+        path: &RelativePath,
+    ) -> (impl Stream<Item = RSyncResult<Bytes>>, Option<u32>) {
         let download_result = async {
             let o = self.object_prefix.as_object(path)?;
             self.client
-                .download(&o)
+                .download_with_crc32c(&o)
                 .await
-                .map(|x| x.map_err(RSyncError::StorageError))
+                .map(|(crc32c, x)| (crc32c, x.map_err(RSyncError::StorageError)))
                 .map_err(RSyncError::StorageError)
         }
         .await;
 
-        futures::stream::once(futures::future::ready(download_result)).try_flatten()
+        let (crc32c, download_result) = match download_result {
+            Ok((crc32c, stream)) => (crc32c.map(|x| x.to_u32()), Ok(stream)),
+            Err(e) => (None, Err(e)),
+        };
+        let stream = futures::stream::once(futures::future::ready(download_result)).try_flatten();
+        (stream, crc32c)
     }
 
-    pub(super) async fn get_crc32c(&self, path: &RelativePath) -> RSyncResult<Option<Entry>> {
+    /// [`GcsClient::read_with_crc32c`] from `offset` with a range download, see [`ObjectClient::download_range_with_crc32c`]
+    pub(super) async fn read_from(
+        &self,
+        path: &RelativePath,
+        offset: Size,
+    ) -> (ByteStream, Option<u32>) {
+        let download_result = async {
+            let o = self.object_prefix.as_object(path)?;
+            self.client
+                .download_range_with_crc32c(&o, offset)
+                .await
+                .map_err(RSyncError::StorageError)
+        }
+        .await;
+
+        match download_result {
+            Ok((crc32c, start, stream)) => {
+                let stream = stream.map_err(RSyncError::StorageError).boxed();
+                (
+                    resume::skip_bytes(stream, offset - start),
+                    crc32c.map(|x| x.to_u32()),
+                )
+            }
+            Err(e) => (
+                futures::stream::once(futures::future::ready(Err(e))).boxed(),
+                None,
+            ),
+        }
+    }
+
+    pub(super) async fn get_crc32c(&self, path: &RelativePath) -> RSyncResult<Option<u32>> {
         fn to_crc32c(po: PartialObject) -> RSyncResult<u32> {
             po.crc32c
                 .map(|x| x.to_u32())
@@ -145,11 +297,10 @@ impl GcsClient {
             .get(o, "crc32c")
             .await
             .map_err(RSyncError::StorageError)
-            .and_then(to_crc32c)
-            .map(|crc32c| Entry::new(path, crc32c));
+            .and_then(to_crc32c);
 
         match entry {
-            Ok(e) => Ok(Some(e)),
+            Ok(crc32c) => Ok(Some(crc32c)),
             Err(RSyncError::StorageError(StorageError::GcsResourceNotFound { .. })) => Ok(None),
             Err(e) => Err(e),
         }
@@ -169,78 +320,251 @@ impl GcsClient {
         }
     }
 
+    /// `None` for composite objects, they have no md5
+    pub(super) async fn get_md5(&self, path: &RelativePath) -> RSyncResult<Option<String>> {
+        let o = &self.object_prefix.as_object(path)?;
+        let entry = self
+            .client
+            .get(o, "md5Hash")
+            .await
+            .map_err(RSyncError::StorageError);
+
+        match entry {
+            Ok(po) => Ok(po.md5_hash.map(|x| x.to_hex())),
+            Err(RSyncError::StorageError(StorageError::GcsResourceNotFound { .. })) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
     pub(super) async fn size_and_mt(
         &self,
         path: &RelativePath,
     ) -> RSyncResult<(Option<chrono::DateTime<chrono::Utc>>, Option<Size>)> {
+        let state = self.entry_state(path).await?;
+        Ok((state.mtime, state.size))
+    }
+
+    pub(super) async fn entry_state(&self, path: &RelativePath) -> RSyncResult<EntryState> {
         let o = &self.object_prefix.as_object(path)?;
         let entry = self
             .client
-            .get(o, "size,metadata/goog-reserved-file-mtime")
+            .get_as::<ObjectWithMtime>(o, &format!("size,generation,{MTIME_FIELDS}"))
             .await
             .map_err(RSyncError::StorageError);
 
         match entry {
-            Ok(entry) => {
-                let size = entry.size;
-                let date_time = entry
-                    .metadata
-                    .and_then(|x| x.modification_time)
-                    .and_then(|mtime| chrono::offset::Utc.timestamp_opt(mtime, 0).single());
-                Ok((date_time, size))
-            }
+            Ok(ObjectWithMtime { object, metadata }) => Ok(EntryState {
+                generation: object.generation,
+                ..object_state(metadata, object.size)
+            }),
             Err(RSyncError::StorageError(StorageError::GcsResourceNotFound { .. })) => {
-                Ok((None, None))
+                Ok(EntryState::default())
             }
             Err(err) => Err(err),
         }
     }
 
-    pub(super) async fn delete(&self, path: &RelativePath) -> RSyncResult<()> {
+    /// Only `generation` is deleted so that the delete can be retried, the current one is fetched first when unknown.
+    ///
+    /// An object replaced in the meantime is left as is and fails with [`StorageError::GcsPreconditionFailed`]
+    pub(super) async fn delete(
+        &self,
+        path: &RelativePath,
+        generation: Option<i64>,
+    ) -> RSyncResult<()> {
         let o = self.object_prefix.as_object(path)?;
-        let delete_result = self.client.delete(&o).await;
+        let generation = match generation {
+            Some(generation) => generation,
+            None => match self.client.get(&o, "generation").await {
+                Ok(po) => po.generation.ok_or_else(|| {
+                    RSyncError::MissingFieldsInGcsResponse("generation".to_owned())
+                })?,
+                Err(StorageError::GcsResourceNotFound { .. }) => return Ok(()),
+                Err(e) => return Err(RSyncError::StorageError(e)),
+            },
+        };
+        let delete_result = self.client.delete_if_generation_match(&o, generation).await;
         match delete_result {
             Ok(_) | Err(StorageError::GcsResourceNotFound { .. }) => Ok(()),
             Err(e) => Err(RSyncError::StorageError(e)),
         }
     }
 
-    /// The crc32 comparison is done outside to avoid crc32c calculation when remote is not found
-    pub(super) async fn write<S>(&self, path: &RelativePath, stream: S) -> RSyncResult<()>
-    where
-        S: futures::TryStream<Ok = bytes::Bytes, Error = RSyncError> + Send + Sync + 'static,
-    {
-        let o = &self.object_prefix.as_object(path)?;
-        self.client
-            .upload(o, stream)
-            .await
-            .map_err(RSyncError::StorageError)
-            .map(|_| ())
+    /// The object is copied server-side, it is left as is
+    pub(super) async fn backup(
+        &self,
+        path: &RelativePath,
+        backup_path: &RelativePath,
+    ) -> RSyncResult<bool> {
+        let o = self.object_prefix.as_object(path)?;
+        let backup = self.object_prefix.as_object(backup_path)?;
+        match self.client.rewrite(&o, &backup).await {
+            Ok(()) => Ok(true),
+            Err(StorageError::GcsResourceNotFound { .. }) => Ok(false),
+            Err(e) => Err(RSyncError::StorageError(e)),
+        }
     }
 
-    pub(super) async fn write_mtime<S>(
+    /// Upload with the modification time, the crc32c and the generation precondition checked by gcs when known.
+    ///
+    /// The crc32c has to be sent before the content, when it is not known beforehand the crc32c computed while uploading is
+    /// compared to the one of the uploaded object instead: a corrupted upload is reported but it has already replaced the object
+    pub(super) async fn write_with_metadata(
         &self,
-        mtime: chrono::DateTime<chrono::Utc>,
+        mtime: Option<chrono::DateTime<chrono::Utc>>,
+        checks: WriteChecks,
         path: &RelativePath,
-        stream: S,
-    ) -> RSyncResult<()>
-    where
-        S: futures::TryStream<Ok = bytes::Bytes, Error = RSyncError> + Send + Sync + 'static,
-    {
+        stream: ByteStream,
+    ) -> RSyncResult<()> {
         let o = &self.object_prefix.as_object(path)?;
-        let mtime = mtime.timestamp();
-        let m = ObjectMetadata {
-            metadata: {
-                Metadata {
-                    modification_time: Some(mtime),
-                }
-            },
+        let m = mtime
+            .map(MtimeMetadata::with_modification_date_time)
+            .unwrap_or_default();
+        let crc32c = checks.crc32c.map(CRC32C::new);
+        let (stream, computed) = match checks.crc32c {
+            Some(_) => (stream, None),
+            None => {
+                let (stream, computed) = integrity::compute_crc32c(stream);
+                (stream, Some(computed))
+            }
         };
-        self.client
-            .upload_with_metadata(&m, o, stream)
+        let uploaded = self
+            .client
+            .upload_with_mtime_fields(
+                &m,
+                o,
+                crc32c.as_ref(),
+                checks.if_generation_match,
+                "crc32c",
+                stream,
+            )
             .await
-            .map_err(RSyncError::StorageError)
-            .map(|_| ())
+            .map_err(RSyncError::StorageError)?;
+        let expected = computed.as_ref().and_then(|x| x.get().copied());
+        match (expected, uploaded.crc32c.map(|x| x.to_u32())) {
+            (Some(expected), Some(actual)) if expected != actual => {
+                Err(RSyncError::Crc32cMismatch {
+                    path: path.to_owned(),
+                    expected,
+                    actual,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ReaderWriterBackend for GcsClient {
+    async fn is_valid(&self) -> RSyncResult<()> {
+        self.is_valid().await
+    }
+
+    async fn list(&self) -> BoxStream<'_, RSyncResult<RelativePath>> {
+        self.list().await.boxed()
+    }
+
+    async fn list_with_metadata(&self) -> BoxStream<'_, RSyncResult<ListedEntry>> {
+        self.list_with_metadata().await.boxed()
+    }
+
+    async fn list_in_scope(&self, scope: ListScope) -> BoxStream<'_, RSyncResult<RelativePath>> {
+        self.list_in_scope(scope).await.boxed()
+    }
+
+    async fn list_with_metadata_in_scope(
+        &self,
+        scope: ListScope,
+    ) -> BoxStream<'_, RSyncResult<ListedEntry>> {
+        self.list_with_metadata_in_scope(scope).await.boxed()
+    }
+
+    async fn read(&self, path: &RelativePath) -> ByteStream {
+        self.read(path).await.boxed()
+    }
+
+    async fn read_with_crc32c(&self, path: &RelativePath) -> (ByteStream, Option<u32>) {
+        let (stream, crc32c) = self.read_with_crc32c(path).await;
+        (stream.boxed(), crc32c)
+    }
+
+    async fn read_from(&self, path: &RelativePath, offset: Size) -> (ByteStream, Option<u32>) {
+        self.read_from(path, offset).await
+    }
+
+    async fn get_crc32c(&self, path: &RelativePath) -> RSyncResult<Option<u32>> {
+        self.get_crc32c(path).await
+    }
+
+    async fn get_md5(&self, path: &RelativePath) -> RSyncResult<Option<String>> {
+        self.get_md5(path).await
+    }
+
+    async fn write(
+        &self,
+        mtime: Option<chrono::DateTime<chrono::Utc>>,
+        restore_fs_mtime: bool,
+        path: &RelativePath,
+        stream: ByteStream,
+    ) -> RSyncResult<()> {
+        self.write_checked(
+            mtime,
+            restore_fs_mtime,
+            path,
+            WriteChecks::default(),
+            stream,
+        )
+        .await
+    }
+
+    async fn write_checked(
+        &self,
+        mtime: Option<chrono::DateTime<chrono::Utc>>,
+        _restore_fs_mtime: bool,
+        path: &RelativePath,
+        checks: WriteChecks,
+        stream: ByteStream,
+    ) -> RSyncResult<()> {
+        self.write_with_metadata(mtime, checks, path, stream).await
+    }
+
+    fn supports_generation_match(&self) -> bool {
+        true
+    }
+
+    async fn delete(&self, path: &RelativePath) -> RSyncResult<()> {
+        self.delete(path, None).await
+    }
+
+    async fn delete_checked(
+        &self,
+        path: &RelativePath,
+        if_generation_match: Option<i64>,
+    ) -> RSyncResult<()> {
+        self.delete(path, if_generation_match).await
+    }
+
+    async fn backup(&self, path: &RelativePath, backup_path: &RelativePath) -> RSyncResult<bool> {
+        self.backup(path, backup_path).await
+    }
+
+    async fn exists(&self, path: &RelativePath) -> RSyncResult<bool> {
+        self.exists(path).await
+    }
+
+    async fn size_and_mt(
+        &self,
+        path: &RelativePath,
+    ) -> RSyncResult<(Option<chrono::DateTime<chrono::Utc>>, Option<Size>)> {
+        self.size_and_mt(path).await
+    }
+
+    async fn entry_state(&self, path: &RelativePath) -> RSyncResult<EntryState> {
+        self.entry_state(path).await
+    }
+
+    fn is_remote(&self) -> bool {
+        true
     }
 }
 
@@ -381,4 +705,25 @@ mod tests {
                 .unwrap()
         );
     }
+
+    #[test]
+    fn test_object_prefix_list_prefix() {
+        let folder = ObjectPrefix::new("bucket", "/prefix/");
+        assert_eq!(Some("prefix/".to_owned()), folder.list_prefix(None));
+        assert_eq!(
+            Some("prefix/data/202".to_owned()),
+            folder.list_prefix(Some("data/202"))
+        );
+
+        let partial = ObjectPrefix::new("bucket", "prefix/da");
+        assert_eq!(
+            Some("prefix/data/".to_owned()),
+            partial.list_prefix(Some("data/"))
+        );
+        assert_eq!(Some("prefix/da".to_owned()), partial.list_prefix(Some("d")));
+        assert_eq!(None, partial.list_prefix(Some("logs/")));
+
+        let bucket = ObjectPrefix::new("bucket", "");
+        assert_eq!(Some("logs/".to_owned()), bucket.list_prefix(Some("logs/")));
+    }
 }
@@ -0,0 +1,230 @@
+use std::sync::{Arc, OnceLock};
+
+use futures::StreamExt;
+
+use super::{ByteStream, RSyncError, RelativePath};
+
+/// Compute the crc32c of the stream on the fly and end it with [`RSyncError::Crc32cMismatch`] when it differs from `expected`.
+///
+/// `initial` is the crc32c of the content already written before the stream (see [`super::PartialContent`]), `0` otherwise.
+///
+/// The writer fails on the stream error so that the destination is not written, the actual crc32c is also kept
+/// aside for writers not returning the stream error as is (ex: gcs uploads)
+pub(super) fn verify_crc32c(
+    stream: ByteStream,
+    path: &RelativePath,
+    expected: u32,
+    initial: u32,
+) -> (ByteStream, Arc<OnceLock<u32>>) {
+    let mismatch = Arc::new(OnceLock::new());
+    let found = mismatch.clone();
+    let path = path.to_owned();
+    let state = (stream, initial, false);
+    let stream = futures::stream::unfold(state, move |(mut stream, crc32c, done)| {
+        let (path, found) = (path.clone(), found.clone());
+        async move {
+            if done {
+                return None;
+            }
+            match stream.next().await {
+                Some(Ok(data)) => {
+                    let crc32c = crc32c::crc32c_append(crc32c, &data);
+                    Some((Ok(data), (stream, crc32c, false)))
+                }
+                Some(Err(e)) => Some((Err(e), (stream, crc32c, false))),
+                None if crc32c == expected => None,
+                None => {
+                    let _ = found.set(crc32c);
+                    let error = RSyncError::Crc32cMismatch {
+                        path,
+                        expected,
+                        actual: crc32c,
+                    };
+                    Some((Err(error), (stream, crc32c, true)))
+                }
+            }
+        }
+    })
+    .boxed();
+    (stream, mismatch)
+}
+
+/// Compute the crc32c of the stream on the fly, it is known once the stream is fully read
+pub(super) fn compute_crc32c(stream: ByteStream) -> (ByteStream, Arc<OnceLock<u32>>) {
+    let computed = Arc::new(OnceLock::new());
+    let found = computed.clone();
+    let stream = futures::stream::unfold((stream, 0), move |(mut stream, crc32c)| {
+        let found = found.clone();
+        async move {
+            match stream.next().await {
+                Some(Ok(data)) => {
+                    let crc32c = crc32c::crc32c_append(crc32c, &data);
+                    Some((Ok(data), (stream, crc32c)))
+                }
+                Some(Err(e)) => Some((Err(e), (stream, crc32c))),
+                None => {
+                    let _ = found.set(crc32c);
+                    None
+                }
+            }
+        }
+    })
+    .boxed();
+    (stream, computed)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::stream::BoxStream;
+    use futures::{StreamExt, TryStreamExt};
+
+    use crate::sync::testing::{mtime, path, CONCURRENCY_LEVEL};
+    use crate::sync::{
+        ListedEntry, MemoryStore, Operation, RSync, RSyncError, RSyncResult, ReaderWriter,
+        ReaderWriterBackend, RelativePath, Size,
+    };
+
+    use super::{compute_crc32c, verify_crc32c, ByteStream};
+
+    fn stream(chunks: &[&'static str]) -> ByteStream {
+        let chunks = chunks.iter().map(|x| Ok(Bytes::from(*x)));
+        futures::stream::iter(chunks.collect::<Vec<_>>()).boxed()
+    }
+
+    #[tokio::test]
+    async fn test_verify_crc32c() {
+        let path = RelativePath::new("a.txt").unwrap();
+        let expected = crc32c::crc32c(b"hello world");
+
+        let (verified, mismatch) = verify_crc32c(stream(&["hello", " world"]), &path, expected, 0);
+        let chunks = verified.try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(2, chunks.len());
+        assert_eq!(None, mismatch.get());
+
+        let initial = crc32c::crc32c(b"hello");
+        let (verified, mismatch) = verify_crc32c(stream(&[" world"]), &path, expected, initial);
+        verified.try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(
+            None,
+            mismatch.get(),
+            "verified along with the initial content"
+        );
+
+        let (verified, mismatch) = verify_crc32c(stream(&["hello", " World"]), &path, expected, 0);
+        let results = verified.collect::<Vec<_>>().await;
+        assert_eq!(3, results.len(), "chunks are forwarded before the error");
+        let actual = crc32c::crc32c(b"hello World");
+        assert!(matches!(
+            results.last(),
+            Some(Err(RSyncError::Crc32cMismatch { expected: e, actual: a, .. })) if *e == expected && *a == actual
+        ));
+        assert_eq!(Some(&actual), mismatch.get());
+    }
+
+    #[tokio::test]
+    async fn test_compute_crc32c() {
+        let (computed_stream, computed) = compute_crc32c(stream(&["hello", " world"]));
+        assert_eq!(None, computed.get(), "known once the stream is read");
+        let chunks = computed_stream.try_collect::<Vec<_>>().await.unwrap();
+        assert_eq!(2, chunks.len());
+        assert_eq!(Some(&crc32c::crc32c(b"hello world")), computed.get());
+    }
+
+    /// Source corrupting the content it reads, its listing still reports the crc32c of the stored content
+    struct CorruptedStore {
+        store: MemoryStore,
+    }
+
+    #[async_trait::async_trait]
+    impl ReaderWriterBackend for CorruptedStore {
+        async fn is_valid(&self) -> RSyncResult<()> {
+            self.store.is_valid().await
+        }
+
+        async fn list(&self) -> BoxStream<'_, RSyncResult<RelativePath>> {
+            self.store.list().await
+        }
+
+        async fn list_with_metadata(&self) -> BoxStream<'_, RSyncResult<ListedEntry>> {
+            self.store.list_with_metadata().await
+        }
+
+        async fn read(&self, path: &RelativePath) -> ByteStream {
+            self.store
+                .read(path)
+                .await
+                .map_ok(|x| bytes::Bytes::from(x.to_ascii_uppercase()))
+                .boxed()
+        }
+
+        async fn read_with_crc32c(&self, path: &RelativePath) -> (ByteStream, Option<u32>) {
+            let crc32c = self.store.get_crc32c(path).await.ok().flatten();
+            (self.read(path).await, crc32c)
+        }
+
+        async fn get_crc32c(&self, path: &RelativePath) -> RSyncResult<Option<u32>> {
+            self.store.get_crc32c(path).await
+        }
+
+        async fn write(
+            &self,
+            mtime: Option<chrono::DateTime<chrono::Utc>>,
+            restore_fs_mtime: bool,
+            path: &RelativePath,
+            stream: ByteStream,
+        ) -> RSyncResult<()> {
+            self.store
+                .write(mtime, restore_fs_mtime, path, stream)
+                .await
+        }
+
+        async fn delete(&self, path: &RelativePath) -> RSyncResult<()> {
+            self.store.delete(path).await
+        }
+
+        async fn exists(&self, path: &RelativePath) -> RSyncResult<bool> {
+            self.store.exists(path).await
+        }
+
+        async fn size_and_mt(
+            &self,
+            path: &RelativePath,
+        ) -> RSyncResult<(Option<chrono::DateTime<chrono::Utc>>, Option<Size>)> {
+            self.store.size_and_mt(path).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_crc32c_mismatch() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        source.insert("a.txt", "hello", mtime(1)).unwrap();
+        let corrupted = || {
+            ReaderWriter::new(CorruptedStore {
+                store: source.clone(),
+            })
+        };
+
+        for listing_comparison in [true, false] {
+            let rsync = RSync::new(corrupted(), ReaderWriter::memory(&dest))
+                .with_listing_comparison(listing_comparison);
+            let error = rsync
+                .sync()
+                .await
+                .try_buffer_unordered(CONCURRENCY_LEVEL)
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap_err();
+            assert_eq!(Some(Operation::Verify), error.operation());
+            assert_eq!(Some(&path("a.txt")), error.path());
+            assert!(!error.is_retryable());
+            assert!(error.to_string().contains("Crc32cMismatch"), "{}", error);
+            assert!(
+                dest.paths().is_empty(),
+                "nothing is written, listing comparison: {}",
+                listing_comparison
+            );
+        }
+    }
+}
@@ -0,0 +1,533 @@
+use std::{cmp::Ordering, ops::Not};
+
+use futures::{
+    future::Either,
+    stream::{BoxStream, Fuse},
+    Future, Stream, StreamExt, TryStreamExt,
+};
+
+use super::{
+    EntryState, PlannedEntry, RMirrorStatus, RSync, RSyncError, RSyncResult, RSyncStatus,
+    RelativePath, SyncAction,
+};
+
+/// Entry returned by [`super::ReaderWriterBackend::list_with_metadata`].
+///
+/// `metadata` is `None` when the listing does not carry any metadata.
+/// Otherwise `mtime` and `size` are trusted as listed while a missing `crc32c` or `md5` is fetched per entry when needed,
+/// except a missing `md5` next to a listed `crc32c` which means the md5 is unknown (ex: gcs composite objects).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListedEntry {
+    pub path: RelativePath,
+    pub metadata: Option<EntryState>,
+}
+
+impl ListedEntry {
+    pub fn new(path: RelativePath, metadata: Option<EntryState>) -> Self {
+        Self { path, metadata }
+    }
+}
+
+enum Joined {
+    Source(ListedEntry),
+    Dest(ListedEntry),
+    Both(ListedEntry, ListedEntry),
+}
+
+struct Side<'a> {
+    name: &'static str,
+    stream: Fuse<BoxStream<'a, RSyncResult<ListedEntry>>>,
+    head: Option<ListedEntry>,
+    last: Option<RelativePath>,
+}
+
+impl<'a> Side<'a> {
+    fn new(name: &'static str, stream: BoxStream<'a, RSyncResult<ListedEntry>>) -> Self {
+        Self {
+            name,
+            stream: stream.fuse(),
+            head: None,
+            last: None,
+        }
+    }
+
+    async fn fill(&mut self) -> RSyncResult<()> {
+        if self.head.is_some() {
+            return Ok(());
+        }
+        self.head = self.stream.try_next().await?;
+        if let Some(head) = &self.head {
+            if self.last.as_ref().is_some_and(|last| last >= &head.path) {
+                return Err(RSyncError::UnsortedListing {
+                    side: self.name.to_owned(),
+                    path: head.path.to_owned(),
+                });
+            }
+            self.last = Some(head.path.to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// Merge-join two listings sorted by path
+fn merge_join<'a>(
+    source: BoxStream<'a, RSyncResult<ListedEntry>>,
+    dest: BoxStream<'a, RSyncResult<ListedEntry>>,
+) -> impl Stream<Item = RSyncResult<Joined>> + 'a {
+    futures::stream::try_unfold(
+        (Side::new("source", source), Side::new("dest", dest)),
+        |(mut source, mut dest)| async move {
+            source.fill().await?;
+            dest.fill().await?;
+            let joined = match (source.head.take(), dest.head.take()) {
+                (None, None) => return Ok(None),
+                (Some(s), None) => Joined::Source(s),
+                (None, Some(d)) => Joined::Dest(d),
+                (Some(s), Some(d)) => match s.path.cmp(&d.path) {
+                    Ordering::Less => {
+                        dest.head = Some(d);
+                        Joined::Source(s)
+                    }
+                    Ordering::Greater => {
+                        source.head = Some(s);
+                        Joined::Dest(d)
+                    }
+                    Ordering::Equal => Joined::Both(s, d),
+                },
+            };
+            Ok(Some((joined, (source, dest))))
+        },
+    )
+}
+
+enum MirrorOp {
+    Sync(ListedEntry, Option<ListedEntry>),
+    /// Destination entry to delete, its listed generation is deleted when known
+    Delete(ListedEntry),
+    Keep(RelativePath),
+    Protect(RelativePath),
+}
+
+impl RSync {
+    /// Same rules as `plan_entry` but based on listed metadata, per entry requests are only done when data is missing
+    async fn plan_listed_entry(
+        &self,
+        source: &ListedEntry,
+        dest: Option<&ListedEntry>,
+    ) -> RSyncResult<PlannedEntry> {
+        let path = &source.path;
+        let source_state = match &source.metadata {
+            Some(x) => x,
+            None => return self.plan_entry(path).await,
+        };
+        let dest_state = match dest.map(|x| &x.metadata) {
+            None => {
+                return Ok(PlannedEntry::new(
+                    path,
+                    SyncAction::Create,
+                    source_state.to_owned(),
+                    EntryState::default(),
+                ))
+            }
+            Some(Some(x)) => x,
+            Some(None) => return self.plan_entry(path).await,
+        };
+
+        if let Some(action) = self.compare_metadata(source_state, dest_state) {
+            return Ok(PlannedEntry::new(
+                path,
+                action,
+                source_state.to_owned(),
+                dest_state.to_owned(),
+            ));
+        }
+
+        self.plan_entry_checksum(path, source_state.to_owned(), dest_state.to_owned())
+            .await
+    }
+
+    async fn sync_listed_entry(
+        &self,
+        source: ListedEntry,
+        dest: Option<ListedEntry>,
+    ) -> RSyncResult<RSyncStatus> {
+        let entry = self.plan_listed_entry(&source, dest.as_ref()).await?;
+        self.apply_sync_action(&entry).await
+    }
+
+    pub(super) async fn sync_listing(
+        &self,
+    ) -> impl Stream<Item = RSyncResult<impl Future<Output = RSyncResult<RSyncStatus>> + '_>> + '_
+    {
+        merge_join(
+            self.source
+                .list_with_metadata_in_scope(self.list_scope())
+                .await,
+            self.dest
+                .list_with_metadata_in_scope(self.list_scope())
+                .await,
+        )
+        .try_filter_map(move |joined| async move {
+            let (s, d) = match joined {
+                Joined::Source(s) => (s, None),
+                Joined::Both(s, d) => (s, Some(d)),
+                Joined::Dest(_) => return Ok(None),
+            };
+            let included = self.filter(&s.path) && self.in_range(&s).await?;
+            Ok(included.then_some((s, d)))
+        })
+        .map_ok(move |(s, d)| self.sync_listed_entry(s, d))
+    }
+
+    async fn mirror_op(&self, op: MirrorOp) -> RSyncResult<RMirrorStatus> {
+        match op {
+            MirrorOp::Sync(s, d) => self
+                .sync_listed_entry(s, d)
+                .await
+                .map(RMirrorStatus::Synced),
+            MirrorOp::Delete(d) => {
+                let generation = d.metadata.and_then(|x| x.generation);
+                self.delete_entry(&d.path, generation).await
+            }
+            MirrorOp::Keep(path) => Ok(RMirrorStatus::NotDeleted(path)),
+            MirrorOp::Protect(path) => Ok(RMirrorStatus::Excluded(path)),
+        }
+    }
+
+    /// Sync, delete or keep operations of a mirror, computed while merge-joining the listings
+    async fn mirror_ops(&self) -> impl Stream<Item = RSyncResult<MirrorOp>> + '_ {
+        merge_join(
+            self.source
+                .list_with_metadata_in_scope(self.list_scope())
+                .await,
+            self.dest
+                .list_with_metadata_in_scope(self.dest_list_scope())
+                .await,
+        )
+        .and_then(move |joined| async move {
+            let in_range = match &joined {
+                Joined::Source(s) | Joined::Both(s, _) if self.filter(&s.path) => {
+                    self.in_range(s).await?
+                }
+                _ => false,
+            };
+            let ops = match joined {
+                Joined::Source(s) if in_range => vec![MirrorOp::Sync(s, None)],
+                Joined::Source(_) => vec![],
+                Joined::Dest(d) if self.selected(&d.path).not() => vec![],
+                Joined::Dest(d) if self.is_protected(&d.path) => vec![MirrorOp::Protect(d.path)],
+                Joined::Dest(d) => vec![MirrorOp::Delete(d)],
+                Joined::Both(s, _) if self.selected(&s.path).not() => vec![],
+                Joined::Both(s, _) if self.is_protected(&s.path) => {
+                    vec![MirrorOp::Protect(s.path)]
+                }
+                Joined::Both(s, d) if self.filter(&s.path).not() => {
+                    vec![MirrorOp::Delete(d)]
+                }
+                Joined::Both(s, d) if in_range => {
+                    let path = s.path.to_owned();
+                    vec![MirrorOp::Sync(s, Some(d)), MirrorOp::Keep(path)]
+                }
+                Joined::Both(s, _) => vec![MirrorOp::Keep(s.path)],
+            };
+            Ok(futures::stream::iter(ops.into_iter().map(Ok)))
+        })
+        .try_flatten()
+    }
+
+    pub(super) async fn mirror_listing(
+        &self,
+    ) -> RSyncResult<
+        impl Stream<Item = RSyncResult<impl Future<Output = RSyncResult<RMirrorStatus>> + '_>> + '_,
+    > {
+        let ops = match self.max_delete {
+            None => Either::Left(self.mirror_ops().await),
+            Some(_) => {
+                let ops = self.mirror_ops().await.try_collect::<Vec<_>>().await?;
+                // every deletable destination entry is either deleted or kept, protected ones are not counted
+                let deletions = ops
+                    .iter()
+                    .filter(|x| matches!(x, MirrorOp::Delete(_)))
+                    .count();
+                let kept = ops
+                    .iter()
+                    .filter(|x| matches!(x, MirrorOp::Keep(_)))
+                    .count();
+                self.check_max_delete(deletions, deletions + kept)?;
+                Either::Right(futures::stream::iter(ops.into_iter().map(Ok)))
+            }
+        };
+        Ok(ops.map_ok(move |op| self.mirror_op(op)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    };
+
+    use futures::stream::BoxStream;
+    use futures::{StreamExt, TryStreamExt};
+
+    use crate::storage::Error as StorageError;
+    use crate::sync::testing::{
+        already_synced, created, deleted, excluded, mirror, mtime, not_deleted, path, rsync,
+        synced, updated, CountingStore, CONCURRENCY_LEVEL,
+    };
+    use crate::sync::{
+        ByteStream, EntryState, MemoryStore, Operation, RMirrorStatus, RSync, RSyncError,
+        RSyncResult, ReaderWriter, ReaderWriterBackend, RelativePath, Size,
+    };
+
+    use super::{merge_join, Joined, ListedEntry};
+
+    fn listing(paths: &[&str]) -> futures::stream::BoxStream<'static, RSyncResult<ListedEntry>> {
+        let entries = paths
+            .iter()
+            .map(|x| Ok(ListedEntry::new(RelativePath::new(x).unwrap(), None)))
+            .collect::<Vec<_>>();
+        futures::stream::iter(entries).boxed()
+    }
+
+    fn describe(joined: Joined) -> String {
+        match joined {
+            Joined::Source(s) => format!("source:{}", s.path.as_str()),
+            Joined::Dest(d) => format!("dest:{}", d.path.as_str()),
+            Joined::Both(s, _) => format!("both:{}", s.path.as_str()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_merge_join() {
+        let actual = merge_join(listing(&["a", "b/c", "d"]), listing(&["b", "b/c", "e"]))
+            .map_ok(describe)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            vec!["source:a", "dest:b", "both:b/c", "source:d", "dest:e"],
+            actual
+        );
+    }
+
+    #[tokio::test]
+    async fn test_merge_join_unsorted_listing() {
+        let actual = merge_join(listing(&["a"]), listing(&["c", "b"]))
+            .map_ok(describe)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            actual,
+            RSyncError::UnsortedListing { side, path } if side == "dest" && path.as_str() == "b"
+        ));
+    }
+
+    fn setup_listing_stores() -> (MemoryStore, MemoryStore) {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        source.insert("a/new.txt", "new", mtime(1)).unwrap();
+        source.insert("b/same.txt", "same", mtime(1)).unwrap();
+        source.insert("c/same_crc32c.txt", "same", None).unwrap();
+        source.insert("d/updated.txt", "updated", mtime(2)).unwrap();
+        source.insert("e/excluded.json", "{}", mtime(1)).unwrap();
+        dest.insert("b/same.txt", "same", mtime(1)).unwrap();
+        dest.insert("c/same_crc32c.txt", "same", mtime(1)).unwrap();
+        dest.insert("d/updated.txt", "old", mtime(1)).unwrap();
+        dest.insert("e/excluded.json", "{}", mtime(1)).unwrap();
+        dest.insert("f/extra.txt", "extra", mtime(1)).unwrap();
+        (source, dest)
+    }
+
+    fn expected_listing_mirror() -> Vec<RMirrorStatus> {
+        vec![
+            synced(created("a/new.txt")),
+            synced(updated("different size or mtime", "d/updated.txt")),
+            synced(already_synced("same crc32c", "c/same_crc32c.txt")),
+            synced(already_synced("same mtime and size", "b/same.txt")),
+            deleted("f/extra.txt"),
+            not_deleted("b/same.txt"),
+            not_deleted("c/same_crc32c.txt"),
+            not_deleted("d/updated.txt"),
+            excluded("e/excluded.json"),
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_listing_comparison_without_metadata_requests() {
+        let (source, dest) = setup_listing_stores();
+        let expected = {
+            let rsync = rsync(&source, &dest).with_excludes(&["*.json"]).unwrap();
+            let mut expected = mirror(&rsync).await;
+            expected.retain(|x| !matches!(x, RMirrorStatus::NotDeleted(_)));
+            expected
+        };
+
+        let (source, dest) = setup_listing_stores();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let rsync = RSync::new(
+            CountingStore::reader_writer(&source, true, &requests),
+            CountingStore::reader_writer(&dest, true, &requests),
+        )
+        .with_excludes(&["*.json"])
+        .unwrap()
+        .with_listing_comparison(true);
+
+        let mut actual = mirror(&rsync).await;
+        assert_eq!(expected_listing_mirror(), actual);
+        assert_eq!(0, requests.load(Ordering::SeqCst));
+
+        actual.retain(|x| !matches!(x, RMirrorStatus::NotDeleted(_)));
+        assert_eq!(expected, actual, "same result as per entry comparison");
+    }
+
+    #[tokio::test]
+    async fn test_listing_comparison_falls_back_to_entry_requests() {
+        let (source, dest) = setup_listing_stores();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let rsync = RSync::new(
+            CountingStore::reader_writer(&source, false, &requests),
+            CountingStore::reader_writer(&dest, true, &requests),
+        )
+        .with_excludes(&["*.json"])
+        .unwrap()
+        .with_listing_comparison(true);
+
+        assert_eq!(expected_listing_mirror(), mirror(&rsync).await);
+        assert!(requests.load(Ordering::SeqCst) > 0);
+        assert_eq!(
+            vec![
+                path("a/new.txt"),
+                path("b/same.txt"),
+                path("c/same_crc32c.txt"),
+                path("d/updated.txt"),
+                path("e/excluded.json"),
+            ],
+            dest.paths()
+        );
+    }
+
+    /// Destination whose entries are all replaced (generation 2) after being listed (generation 1)
+    struct ReplacedStore {
+        store: MemoryStore,
+        deletes: Arc<Mutex<Vec<Option<i64>>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl ReaderWriterBackend for ReplacedStore {
+        async fn is_valid(&self) -> RSyncResult<()> {
+            self.store.is_valid().await
+        }
+
+        async fn list(&self) -> BoxStream<'_, RSyncResult<RelativePath>> {
+            self.store.list().await
+        }
+
+        async fn list_with_metadata(&self) -> BoxStream<'_, RSyncResult<ListedEntry>> {
+            self.store
+                .list_with_metadata()
+                .await
+                .map_ok(|x| {
+                    let metadata = x.metadata.map(|m| EntryState {
+                        generation: Some(1),
+                        ..m
+                    });
+                    ListedEntry::new(x.path, metadata)
+                })
+                .boxed()
+        }
+
+        async fn read(&self, path: &RelativePath) -> ByteStream {
+            self.store.read(path).await
+        }
+
+        async fn get_crc32c(&self, path: &RelativePath) -> RSyncResult<Option<u32>> {
+            self.store.get_crc32c(path).await
+        }
+
+        async fn write(
+            &self,
+            mtime: Option<chrono::DateTime<chrono::Utc>>,
+            restore_fs_mtime: bool,
+            path: &RelativePath,
+            stream: ByteStream,
+        ) -> RSyncResult<()> {
+            self.store
+                .write(mtime, restore_fs_mtime, path, stream)
+                .await
+        }
+
+        fn supports_generation_match(&self) -> bool {
+            true
+        }
+
+        async fn delete(&self, path: &RelativePath) -> RSyncResult<()> {
+            self.delete_checked(path, None).await
+        }
+
+        async fn delete_checked(
+            &self,
+            path: &RelativePath,
+            if_generation_match: Option<i64>,
+        ) -> RSyncResult<()> {
+            self.deletes.lock().unwrap().push(if_generation_match);
+            match if_generation_match {
+                Some(generation) if generation != 2 => Err(RSyncError::StorageError(
+                    StorageError::GcsPreconditionFailed {
+                        url: path.as_str().to_owned(),
+                    },
+                )),
+                _ => self.store.delete(path).await,
+            }
+        }
+
+        async fn exists(&self, path: &RelativePath) -> RSyncResult<bool> {
+            self.store.exists(path).await
+        }
+
+        async fn size_and_mt(
+            &self,
+            path: &RelativePath,
+        ) -> RSyncResult<(Option<chrono::DateTime<chrono::Utc>>, Option<Size>)> {
+            self.store.size_and_mt(path).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mirror_listing_does_not_delete_replaced_entries() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        dest.insert("replaced.txt", "new", mtime(1)).unwrap();
+        let deletes = Arc::new(Mutex::new(Vec::new()));
+        let replaced = || ReplacedStore {
+            store: dest.clone(),
+            deletes: deletes.clone(),
+        };
+
+        let rsync = RSync::new(ReaderWriter::memory(&source), ReaderWriter::new(replaced()))
+            .with_listing_comparison(true);
+        let error = rsync
+            .mirror()
+            .await
+            .unwrap()
+            .try_buffer_unordered(CONCURRENCY_LEVEL)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap_err();
+        assert_eq!(Some(Operation::Delete), error.operation());
+        assert_eq!(Some(&path("replaced.txt")), error.path());
+        assert!(!error.is_retryable());
+        assert_eq!(vec![Some(1)], *deletes.lock().unwrap(), "listed generation");
+        assert_eq!(vec![path("replaced.txt")], dest.paths(), "left as is");
+
+        let rsync = RSync::new(ReaderWriter::memory(&source), ReaderWriter::new(replaced()));
+        assert_eq!(vec![deleted("replaced.txt")], mirror(&rsync).await);
+        assert_eq!(
+            vec![Some(1), None],
+            *deletes.lock().unwrap(),
+            "the current generation is deleted when not listed"
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use std::{ops::Not, str::FromStr};
+
+use futures::TryStreamExt;
+
+use super::{RSync, RSyncError, RSyncResult, RelativePath};
+
+/// Maximum number of destination entries a mirror may delete, see [`RSync::with_max_delete`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaxDelete {
+    Count(usize),
+    /// Percentage of the deletable destination entries (0 to 100), excluded entries protected by mirror are not counted
+    Percent(u8),
+}
+
+impl MaxDelete {
+    fn is_exceeded(self, deletions: usize, dest_entries: usize) -> bool {
+        match self {
+            Self::Count(max) => deletions > max,
+            Self::Percent(percent) => deletions * 100 > usize::from(percent) * dest_entries,
+        }
+    }
+}
+
+impl FromStr for MaxDelete {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_suffix('%') {
+            Some(percent) => match percent.parse() {
+                Ok(percent) if percent <= 100 => Ok(Self::Percent(percent)),
+                _ => Err(format!("invalid percentage {s}, expected 0% to 100%")),
+            },
+            None => s
+                .parse()
+                .map(Self::Count)
+                .map_err(|_| format!("invalid max delete {s}, expected <count> or <percent>%")),
+        }
+    }
+}
+
+impl RSync {
+    /// Guard [`RSync::mirror`] against a misconfigured source (ex: empty or wrong prefix) wiping the destination.
+    ///
+    /// Deletions are computed before anything is synced or deleted and the mirror fails with [`RSyncError::TooManyDeletions`] when they exceed `max_delete`
+    pub fn with_max_delete(mut self, max_delete: MaxDelete) -> Self {
+        self.max_delete = Some(max_delete);
+        self
+    }
+
+    /// Fail when `deletions` out of `dest_entries` deletable destination entries exceed the [`MaxDelete`] guard.
+    ///
+    /// Deletable entries are the selected destination entries that are not protected (see [`RSync::with_delete_excluded`])
+    pub(super) fn check_max_delete(
+        &self,
+        deletions: usize,
+        dest_entries: usize,
+    ) -> RSyncResult<()> {
+        match self.max_delete {
+            Some(max_delete) if max_delete.is_exceeded(deletions, dest_entries) => {
+                Err(RSyncError::TooManyDeletions {
+                    deletions,
+                    dest_entries,
+                    max_delete,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Selected destination entries along with whether they should be deleted, checked against the [`MaxDelete`] guard
+    pub(super) async fn checked_deletions(&self) -> RSyncResult<Vec<(RelativePath, bool)>> {
+        let deletions = self
+            .dest
+            .list_in_scope(self.dest_list_scope())
+            .await
+            .try_filter(|x| futures::future::ready(self.selected(x)))
+            .map_ok(|path| async move {
+                let delete = self.should_delete(&path).await?;
+                Ok((path, delete))
+            })
+            .try_buffer_unordered(self.concurrency.metadata.max(1))
+            .try_collect::<Vec<_>>()
+            .await?;
+        let count = deletions.iter().filter(|(_, delete)| *delete).count();
+        let deletable = deletions
+            .iter()
+            .filter(|(path, _)| self.is_protected(path).not())
+            .count();
+        self.check_max_delete(count, deletable)?;
+        Ok(deletions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::sync::testing::{deleted, mirror, mtime, not_deleted, path, rsync, synced, updated};
+    use crate::sync::{MemoryStore, RSync, RSyncError, ReaderWriter};
+
+    use super::MaxDelete;
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!(Ok(MaxDelete::Count(100)), MaxDelete::from_str("100"));
+        assert_eq!(Ok(MaxDelete::Percent(10)), MaxDelete::from_str("10%"));
+        assert!(MaxDelete::from_str("101%").is_err());
+        assert!(MaxDelete::from_str("-1").is_err());
+        assert!(MaxDelete::from_str("ten").is_err());
+    }
+
+    #[test]
+    fn test_is_exceeded() {
+        assert!(!MaxDelete::Count(2).is_exceeded(2, 10));
+        assert!(MaxDelete::Count(2).is_exceeded(3, 10));
+        assert!(!MaxDelete::Count(0).is_exceeded(0, 0));
+        assert!(!MaxDelete::Percent(10).is_exceeded(1, 10));
+        assert!(MaxDelete::Percent(10).is_exceeded(2, 10));
+        assert!(MaxDelete::Percent(10).is_exceeded(1, 1));
+        assert!(!MaxDelete::Percent(100).is_exceeded(1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_percent_of_deletable_entries() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        source.insert("a.txt", "a", None).unwrap();
+        dest.insert("a.txt", "a", None).unwrap();
+        dest.insert("extra.txt", "x", None).unwrap();
+        for i in 0..8 {
+            dest.insert(format!("{i}.log").as_str(), "log", None)
+                .unwrap();
+        }
+        let rsync = RSync::new(ReaderWriter::memory(&source), ReaderWriter::memory(&dest))
+            .with_excludes(&["*.log"])
+            .unwrap()
+            .with_max_delete(MaxDelete::Percent(40));
+        let too_many = |result: Result<_, RSyncError>| {
+            matches!(
+                result,
+                Err(RSyncError::TooManyDeletions {
+                    deletions: 1,
+                    dest_entries: 2,
+                    ..
+                })
+            )
+        };
+
+        assert!(too_many(rsync.plan_mirror(4).await.map(|_| ())));
+        assert!(too_many(rsync.mirror().await.map(|_| ())));
+        let rsync = rsync.with_listing_comparison(true);
+        assert!(too_many(rsync.mirror().await.map(|_| ())));
+        assert_eq!(10, dest.paths().len());
+    }
+
+    #[tokio::test]
+    async fn test_mirror_max_delete() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        source.insert("a.txt", "a", mtime(1)).unwrap();
+        for p in ["a.txt", "b.txt", "c.txt", "d.txt"] {
+            dest.insert(p, p, mtime(1)).unwrap();
+        }
+
+        for listing_comparison in [false, true] {
+            for max_delete in [MaxDelete::Count(2), MaxDelete::Percent(50)] {
+                let guarded = rsync(&source, &dest)
+                    .with_listing_comparison(listing_comparison)
+                    .with_max_delete(max_delete);
+                let error = guarded.mirror().await.err().unwrap();
+                assert!(
+                    matches!(
+                        error,
+                        RSyncError::TooManyDeletions {
+                            deletions: 3,
+                            dest_entries: 4,
+                            max_delete: x,
+                        } if x == max_delete
+                    ),
+                    "{:?}",
+                    error
+                );
+                assert_eq!(4, dest.paths().len(), "nothing is changed");
+            }
+        }
+
+        let allowed = rsync(&source, &dest)
+            .with_listing_comparison(true)
+            .with_max_delete(MaxDelete::Count(3));
+        assert_eq!(
+            vec![
+                synced(updated("different size or mtime", "a.txt")),
+                deleted("b.txt"),
+                deleted("c.txt"),
+                deleted("d.txt"),
+                not_deleted("a.txt"),
+            ],
+            mirror(&allowed).await
+        );
+        assert_eq!(vec![path("a.txt")], dest.paths());
+    }
+}
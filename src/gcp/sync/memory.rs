@@ -0,0 +1,232 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use bytes::{Bytes, BytesMut};
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
+use md5::Digest;
+
+use crate::storage::MD5;
+
+use super::{
+    ByteStream, EntryState, ListedEntry, RSyncError, RSyncResult, ReaderWriterBackend,
+    RelativePath, Size,
+};
+
+/// Object stored in a [`MemoryStore`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryObject {
+    pub content: Bytes,
+    pub mtime: Option<chrono::DateTime<chrono::Utc>>,
+    pub crc32c: u32,
+    /// Lowercase hex digest like md5sum
+    pub md5: String,
+}
+
+impl MemoryObject {
+    fn new(content: Bytes, mtime: Option<chrono::DateTime<chrono::Utc>>) -> Self {
+        let crc32c = crc32c::crc32c(&content);
+        let md5 = MD5::new(md5::Md5::digest(&content).into()).to_hex();
+        Self {
+            content,
+            mtime,
+            crc32c,
+            md5,
+        }
+    }
+
+    pub fn size(&self) -> Size {
+        self.content.len() as Size
+    }
+
+    fn state(&self) -> EntryState {
+        EntryState {
+            crc32c: Some(self.crc32c),
+            md5: Some(self.md5.to_owned()),
+            ..EntryState::new(self.mtime, Some(self.size()))
+        }
+    }
+}
+
+/// In-process storage backend, cloning a store shares the same objects.
+///
+/// Like gcs, the mtime is kept as object metadata when provided by the source.
+///
+/// Example
+/// ```rust
+/// use gcs_rsync::sync::{MemoryStore, RSync, ReaderWriter};
+///
+/// let source = MemoryStore::new();
+/// source.insert("hello.txt", "hello", None).unwrap();
+/// let dest = MemoryStore::new();
+///
+/// let rsync = RSync::new(ReaderWriter::memory(&source), ReaderWriter::memory(&dest));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    objects: Arc<Mutex<BTreeMap<RelativePath, MemoryObject>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn objects(&self) -> MutexGuard<'_, BTreeMap<RelativePath, MemoryObject>> {
+        self.objects
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn insert<T>(
+        &self,
+        path: &str,
+        content: T,
+        mtime: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> RSyncResult<()>
+    where
+        T: Into<Bytes>,
+    {
+        let path = RelativePath::new(path)?;
+        self.objects()
+            .insert(path, MemoryObject::new(content.into(), mtime));
+        Ok(())
+    }
+
+    pub fn get(&self, path: &str) -> Option<MemoryObject> {
+        let path = RelativePath::new(path).ok()?;
+        self.objects().get(&path).cloned()
+    }
+
+    pub fn remove(&self, path: &str) -> Option<MemoryObject> {
+        let path = RelativePath::new(path).ok()?;
+        self.objects().remove(&path)
+    }
+
+    /// All stored paths in lexicographic order
+    pub fn paths(&self) -> Vec<RelativePath> {
+        self.objects().keys().cloned().collect()
+    }
+}
+
+#[async_trait::async_trait]
+impl ReaderWriterBackend for MemoryStore {
+    async fn is_valid(&self) -> RSyncResult<()> {
+        Ok(())
+    }
+
+    /// Like other backends, the listing is lazy: the snapshot is taken on first poll
+    async fn list(&self) -> BoxStream<'_, RSyncResult<RelativePath>> {
+        futures::stream::once(
+            async move { futures::stream::iter(self.paths().into_iter().map(Ok)) },
+        )
+        .flatten()
+        .boxed()
+    }
+
+    async fn list_with_metadata(&self) -> BoxStream<'_, RSyncResult<ListedEntry>> {
+        futures::stream::once(async move {
+            let entries = self
+                .objects()
+                .iter()
+                .map(|(path, o)| Ok(ListedEntry::new(path.to_owned(), Some(o.state()))))
+                .collect::<Vec<_>>();
+            futures::stream::iter(entries)
+        })
+        .flatten()
+        .boxed()
+    }
+
+    async fn read(&self, path: &RelativePath) -> ByteStream {
+        let object = self
+            .objects()
+            .get(path)
+            .map(|o| o.content.clone())
+            .ok_or_else(|| RSyncError::MemoryObjectNotFound(path.to_owned()));
+        futures::stream::once(futures::future::ready(object)).boxed()
+    }
+
+    /// Like gcs downloads, the stored crc32c comes along with the content
+    async fn read_with_crc32c(&self, path: &RelativePath) -> (ByteStream, Option<u32>) {
+        let (object, crc32c) = match self.objects().get(path) {
+            Some(o) => (Ok(o.content.clone()), Some(o.crc32c)),
+            None => (Err(RSyncError::MemoryObjectNotFound(path.to_owned())), None),
+        };
+        let stream = futures::stream::once(futures::future::ready(object)).boxed();
+        (stream, crc32c)
+    }
+
+    async fn get_crc32c(&self, path: &RelativePath) -> RSyncResult<Option<u32>> {
+        Ok(self.objects().get(path).map(|o| o.crc32c))
+    }
+
+    async fn get_md5(&self, path: &RelativePath) -> RSyncResult<Option<String>> {
+        Ok(self.objects().get(path).map(|o| o.md5.to_owned()))
+    }
+
+    async fn write(
+        &self,
+        mtime: Option<chrono::DateTime<chrono::Utc>>,
+        _restore_fs_mtime: bool,
+        path: &RelativePath,
+        stream: ByteStream,
+    ) -> RSyncResult<()> {
+        let content = stream
+            .try_fold(BytesMut::new(), |mut content, data| async move {
+                content.extend_from_slice(&data);
+                Ok(content)
+            })
+            .await?
+            .freeze();
+        self.objects()
+            .insert(path.to_owned(), MemoryObject::new(content, mtime));
+        Ok(())
+    }
+
+    async fn delete(&self, path: &RelativePath) -> RSyncResult<()> {
+        self.objects().remove(path);
+        Ok(())
+    }
+
+    async fn exists(&self, path: &RelativePath) -> RSyncResult<bool> {
+        Ok(self.objects().contains_key(path))
+    }
+
+    async fn size_and_mt(
+        &self,
+        path: &RelativePath,
+    ) -> RSyncResult<(Option<chrono::DateTime<chrono::Utc>>, Option<Size>)> {
+        Ok(self
+            .objects()
+            .get(path)
+            .map(|o| (o.mtime, Some(o.size())))
+            .unwrap_or((None, None)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sync::testing::{already_synced, created, rsync, sync, updated};
+    use crate::sync::MemoryStore;
+
+    #[tokio::test]
+    async fn test_crc32c_without_mtime() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        source.insert("hello.txt", "hello", None).unwrap();
+
+        let rsync = rsync(&source, &dest);
+        assert_eq!(vec![created("hello.txt")], sync(&rsync).await);
+        assert_eq!(
+            vec![already_synced("same crc32c", "hello.txt")],
+            sync(&rsync).await
+        );
+
+        source.insert("hello.txt", "world", None).unwrap();
+        assert_eq!(
+            vec![updated("different crc32c", "hello.txt")],
+            sync(&rsync).await
+        );
+    }
+}
@@ -1,28 +1,75 @@
+mod backup;
+mod bandwidth;
+mod bisync;
+mod comparison;
+mod concurrency;
+mod entry_filter;
+mod failure;
+mod filter;
 mod fs;
 mod gcs;
+mod integrity;
+mod listing;
+mod max_delete;
+mod memory;
+mod plan;
+mod progress;
+mod report;
+mod resume;
+#[cfg(test)]
+mod testing;
 
+use std::collections::BTreeSet;
 use std::ops::Not;
 use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+};
 
 use bytes::Bytes;
 use futures::future::Either;
+use futures::stream::BoxStream;
 use futures::{Future, Stream, StreamExt, TryStreamExt};
 
+pub use backup::Backup;
+pub use bandwidth::BandwidthLimiter;
+pub use bisync::{
+    BisyncEntryState, BisyncReport, BisyncSide, BisyncState, BisyncStatus, ConflictResolution,
+};
+pub use comparison::ComparisonStrategy;
+pub use concurrency::Concurrency;
+pub use failure::{ErrorPolicy, FailedEntry, Operation};
+pub use filter::{FilterAction, FilterRules, ListScope};
 use fs::FsClient;
 use gcs::GcsClient;
-use globset::{Glob, GlobSet, GlobSetBuilder};
+pub use listing::ListedEntry;
+pub use max_delete::MaxDelete;
+pub use memory::{MemoryObject, MemoryStore};
+pub use plan::{EntryState, PlannedEntry, SyncAction, SyncPlan};
+use progress::Progress;
+pub use progress::{ProgressCallback, ProgressEvent, ProgressStage, ProgressTotals};
+pub use report::{SyncFailure, SyncReport, SyncReporter};
+pub use resume::PartialContent;
 
 use crate::oauth2::token::TokenGenerator;
+use crate::storage::{ObjectClient, RetryPolicy};
 
 pub struct ReaderWriter {
-    inner: ReaderWriterInternal,
+    inner: Box<dyn ReaderWriterBackend>,
 }
 
 pub type Source = ReaderWriter;
 
 impl ReaderWriter {
-    fn new(inner: ReaderWriterInternal) -> Self {
-        Self { inner }
+    /// Wrap any [`ReaderWriterBackend`] implementation so that it can be used as a [`RSync`] source or destination
+    pub fn new<T>(backend: T) -> Self
+    where
+        T: ReaderWriterBackend + 'static,
+    {
+        Self {
+            inner: Box::new(backend),
+        }
     }
 
     pub async fn gcs(
@@ -31,122 +78,254 @@ impl ReaderWriter {
         prefix: &str,
     ) -> RSyncResult<Self> {
         let client = GcsClient::new(token_generator, bucket, prefix).await?;
-        Ok(Self::new(ReaderWriterInternal::Gcs(Box::new(client))))
+        Ok(Self::new(client))
     }
 
     pub fn gcs_no_auth(bucket: &str, prefix: &str) -> Self {
         let client = GcsClient::no_auth(bucket, prefix);
-        Self::new(ReaderWriterInternal::Gcs(Box::new(client)))
+        Self::new(client)
+    }
+
+    /// gcs backend using an already configured [`ObjectClient`] (ex: with a custom [`RetryPolicy`])
+    pub fn gcs_with_client(client: ObjectClient, bucket: &str, prefix: &str) -> Self {
+        Self::new(GcsClient::with_client(client, bucket, prefix))
     }
 
     pub fn fs(base_path: &Path) -> Self {
         let client = FsClient::new(base_path);
-        Self::new(ReaderWriterInternal::Fs(Box::new(client)))
+        Self::new(client)
+    }
+
+    /// fs backend keeping the partial file of a failed write (`.<name>.gcs-rsync-partial` next to the file) instead of removing it.
+    ///
+    /// Files are always written to a partial file first and renamed once complete. The next transfer of the file resumes a kept
+    /// partial file from its end when the source crc32c is known, the whole file is verified and a partial file failing the
+    /// verification is removed. Partial files are never synced and mirror deletes the leftover ones of its destination
+    pub fn fs_keep_partial(base_path: &Path) -> Self {
+        let client = FsClient::new(base_path).with_keep_partial(true);
+        Self::new(client)
+    }
+
+    /// In-process backend sharing the objects of the given [`MemoryStore`], useful for tests and staging
+    pub fn memory(store: &MemoryStore) -> Self {
+        Self::new(store.clone())
     }
 }
 
-//TODO: replace this with trait when async trait will be more stable with method returning Trait
-enum ReaderWriterInternal {
-    Gcs(Box<GcsClient>),
-    Fs(Box<FsClient>),
+pub type Size = u64;
+
+/// Stream of bytes read from or written to a [`ReaderWriterBackend`]
+pub type ByteStream = BoxStream<'static, RSyncResult<Bytes>>;
+
+/// Checks of [`ReaderWriterBackend::write_checked`] on the written content and on the entry it replaces
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteChecks {
+    /// crc32c of the content when known beforehand (ex: listed source crc32c)
+    pub crc32c: Option<u32>,
+    /// Generation of the replaced entry as planned (see [`EntryState::generation`]), `0` when the entry must not exist
+    pub if_generation_match: Option<i64>,
+    /// Size of the partial content the stream follows (see [`ReaderWriterBackend::resumable`]), the write appends to it
+    pub resume_from: Option<Size>,
 }
 
-type Size = u64;
+/// Storage backend used by [`RSync`] as a source or a destination.
+///
+/// gcs and fs are built-in implementations (see [`ReaderWriter::gcs`] and [`ReaderWriter::fs`]), any other storage can be plugged with [`ReaderWriter::new`].
+#[async_trait::async_trait]
+pub trait ReaderWriterBackend: Send + Sync {
+    /// Check that the backend root (bucket, prefix, folder, ...) is reachable
+    async fn is_valid(&self) -> RSyncResult<()>;
 
-impl ReaderWriterInternal {
-    async fn is_valid(&self) -> RSyncResult<()> {
-        match self {
-            ReaderWriterInternal::Fs(client) => client.is_valid().await,
-            ReaderWriterInternal::Gcs(gcs_client) => gcs_client.is_valid().await,
-        }
+    /// List all entries relatively to the backend root
+    async fn list(&self) -> BoxStream<'_, RSyncResult<RelativePath>>;
+
+    /// List all entries sorted by path with the metadata available in the listing.
+    ///
+    /// Used by [`RSync::with_listing_comparison`] to merge-join source and destination listings.
+    /// The default implementation sorts [`ReaderWriterBackend::list`] without any metadata.
+    async fn list_with_metadata(&self) -> BoxStream<'_, RSyncResult<ListedEntry>> {
+        futures::stream::once(async move {
+            let mut paths = self.list().await.try_collect::<Vec<_>>().await?;
+            paths.sort();
+            Ok(futures::stream::iter(
+                paths
+                    .into_iter()
+                    .map(|path| Ok(ListedEntry::new(path, None))),
+            ))
+        })
+        .try_flatten()
+        .boxed()
     }
 
-    async fn list(
-        &self,
-    ) -> Either<
-        impl Stream<Item = RSyncResult<RelativePath>> + '_,
-        impl Stream<Item = RSyncResult<RelativePath>> + '_,
-    > {
-        match self {
-            ReaderWriterInternal::Gcs(client) => Either::Left(client.list().await),
-            ReaderWriterInternal::Fs(client) => Either::Right(client.list().await),
-        }
+    /// Read the entry content, errors (ex: not found) are returned through the stream
+    async fn read(&self, path: &RelativePath) -> ByteStream;
+
+    /// Read the entry content along with the crc32c learned while opening it (ex: gcs `x-goog-hash`), if any
+    async fn read_with_crc32c(&self, path: &RelativePath) -> (ByteStream, Option<u32>) {
+        (self.read(path).await, None)
+    }
+
+    /// [`ReaderWriterBackend::read_with_crc32c`] of the content from `offset`, the crc32c is still the one of the whole content.
+    ///
+    /// Used to resume a partial content (see [`ReaderWriterBackend::resumable`]), the default implementation skips the first bytes
+    async fn read_from(&self, path: &RelativePath, offset: Size) -> (ByteStream, Option<u32>) {
+        let (stream, crc32c) = self.read_with_crc32c(path).await;
+        (resume::skip_bytes(stream, offset), crc32c)
+    }
+
+    /// Get the crc32c of an entry or `None` when the entry does not exist
+    async fn get_crc32c(&self, path: &RelativePath) -> RSyncResult<Option<u32>>;
+
+    /// Get the md5 of an entry as a lowercase hex digest or `None` when the entry does not exist or its md5 is unknown (ex: gcs composite objects).
+    ///
+    /// The default implementation does not know any md5 so that [`ComparisonStrategy::Md5`] falls back to crc32c
+    async fn get_md5(&self, _path: &RelativePath) -> RSyncResult<Option<String>> {
+        Ok(None)
     }
 
-    async fn read(
+    /// Write the entry content.
+    ///
+    /// `mtime` is the source modification time when known, `restore_fs_mtime` tells file system like backends whether it should be applied
+    async fn write(
         &self,
+        mtime: Option<chrono::DateTime<chrono::Utc>>,
+        restore_fs_mtime: bool,
         path: &RelativePath,
-    ) -> Either<impl Stream<Item = RSyncResult<Bytes>>, impl Stream<Item = RSyncResult<Bytes>>>
-    {
-        match self {
-            ReaderWriterInternal::Gcs(client) => Either::Left(client.read(path).await),
-            ReaderWriterInternal::Fs(client) => Either::Right(client.read(path).await),
-        }
+        stream: ByteStream,
+    ) -> RSyncResult<()>;
+
+    /// [`ReaderWriterBackend::write`] along with [`WriteChecks`] that backends should enforce on their side when they can.
+    ///
+    /// [`RSync`] already verifies the crc32c on the fly, gcs uploads are also rejected by gcs on a crc32c mismatch or when
+    /// the object generation is not the expected one (see [`ReaderWriterBackend::supports_generation_match`]).
+    /// The default implementation ignores the checks
+    async fn write_checked(
+        &self,
+        mtime: Option<chrono::DateTime<chrono::Utc>>,
+        restore_fs_mtime: bool,
+        path: &RelativePath,
+        _checks: WriteChecks,
+        stream: ByteStream,
+    ) -> RSyncResult<()> {
+        self.write(mtime, restore_fs_mtime, path, stream).await
     }
 
-    async fn get_crc32c(&self, path: &RelativePath) -> RSyncResult<Option<Entry>> {
-        match self {
-            ReaderWriterInternal::Gcs(client) => client.get_crc32c(path).await,
-            ReaderWriterInternal::Fs(client) => client.get_crc32c(path).await,
-        }
+    /// Whether [`ReaderWriterBackend::write_checked`] enforces [`WriteChecks::if_generation_match`], which makes a write safe to replay.
+    ///
+    /// Writes to a remote backend are only retried by [`RSync`] when they are guarded this way
+    fn supports_generation_match(&self) -> bool {
+        false
+    }
+
+    /// Content kept by a failed write of the entry (ex: fs partial file) that the next write can append to.
+    ///
+    /// [`RSync`] then only reads the rest of the source and passes [`WriteChecks::resume_from`], a backend returning some
+    /// content must append to it and drop it when the write fails on a crc32c mismatch. `None` by default
+    async fn resumable(&self, _path: &RelativePath) -> RSyncResult<Option<PartialContent>> {
+        Ok(None)
     }
 
-    async fn write<S>(
+    /// Delete the entry, deleting a missing entry is not an error
+    async fn delete(&self, path: &RelativePath) -> RSyncResult<()>;
+
+    /// [`ReaderWriterBackend::delete`] of the entry generation as planned (see [`EntryState::generation`]) when known.
+    ///
+    /// Backends enforcing the generation (see [`ReaderWriterBackend::supports_generation_match`]) must fail when the entry
+    /// has been replaced since and leave it as is. The default implementation ignores the generation
+    async fn delete_checked(
         &self,
-        mtime: Option<chrono::DateTime<chrono::Utc>>,
-        set_fs_mtime: bool,
         path: &RelativePath,
-        stream: S,
-    ) -> RSyncResult<()>
-    where
-        S: futures::TryStream<Ok = bytes::Bytes, Error = RSyncError> + Send + Sync + 'static,
-    {
-        async {
-            match self {
-                ReaderWriterInternal::Gcs(client) => match mtime {
-                    Some(mtime) => client.write_mtime(mtime, path, stream).await,
-                    None => client.write(path, stream).await,
-                },
-                ReaderWriterInternal::Fs(client) => match (mtime, set_fs_mtime) {
-                    (Some(mtime), true) => client.write_mtime(mtime, path, stream).await,
-                    _ => client.write(path, stream).await,
-                },
-            }
-        }
-        .await
+        _if_generation_match: Option<i64>,
+    ) -> RSyncResult<()> {
+        self.delete(path).await
     }
 
-    async fn delete(&self, path: &RelativePath) -> RSyncResult<()> {
-        match self {
-            ReaderWriterInternal::Gcs(client) => client.delete(path).await,
-            ReaderWriterInternal::Fs(client) => client.delete(path).await,
+    /// Keep the current content of an entry at `backup_path` before it is overwritten, `false` when the entry does not exist.
+    ///
+    /// The entry itself must be left as is (ex: gcs copy, fs hard link) so that a failed overwrite does not lose it.
+    /// The default implementation copies the entry with its modification time
+    async fn backup(&self, path: &RelativePath, backup_path: &RelativePath) -> RSyncResult<bool> {
+        let (mtime, size) = self.size_and_mt(path).await?;
+        if size.is_none() {
+            return Ok(false);
         }
+        let stream = self.read(path).await;
+        self.write(mtime, true, backup_path, stream).await?;
+        Ok(true)
     }
 
-    async fn exists(&self, path: &RelativePath) -> RSyncResult<bool> {
-        match self {
-            ReaderWriterInternal::Gcs(client) => client.exists(path).await,
-            ReaderWriterInternal::Fs(client) => client.exists(path).await,
-        }
+    /// [`ReaderWriterBackend::backup`] of an entry deleted right after, the entry may be moved instead (ex: fs rename).
+    ///
+    /// The default implementation is [`ReaderWriterBackend::backup`]
+    async fn backup_before_delete(
+        &self,
+        path: &RelativePath,
+        backup_path: &RelativePath,
+    ) -> RSyncResult<bool> {
+        self.backup(path, backup_path).await
     }
 
+    async fn exists(&self, path: &RelativePath) -> RSyncResult<bool>;
+
+    /// Get the modification time and size of an entry, `(None, None)` when the entry does not exist
     async fn size_and_mt(
         &self,
         path: &RelativePath,
-    ) -> RSyncResult<(Option<chrono::DateTime<chrono::Utc>>, Option<Size>)> {
-        match self {
-            ReaderWriterInternal::Gcs(client) => client.size_and_mt(path).await,
-            ReaderWriterInternal::Fs(client) => client.size_and_mt(path).await,
-        }
+    ) -> RSyncResult<(Option<chrono::DateTime<chrono::Utc>>, Option<Size>)>;
+
+    /// State of an entry as needed to plan it, the default implementation only knows [`ReaderWriterBackend::size_and_mt`]
+    async fn entry_state(&self, path: &RelativePath) -> RSyncResult<EntryState> {
+        let (mtime, size) = self.size_and_mt(path).await?;
+        Ok(EntryState::new(mtime, size))
+    }
+
+    /// [`ReaderWriterBackend::list`] skipping the directories or prefixes that the scope excludes.
+    ///
+    /// Entries are still filtered by [`RSync`] afterwards, the default implementation lists everything
+    async fn list_in_scope(&self, _scope: ListScope) -> BoxStream<'_, RSyncResult<RelativePath>> {
+        self.list().await
+    }
+
+    /// [`ReaderWriterBackend::list_with_metadata`] skipping the directories or prefixes that the scope excludes
+    async fn list_with_metadata_in_scope(
+        &self,
+        _scope: ListScope,
+    ) -> BoxStream<'_, RSyncResult<ListedEntry>> {
+        self.list_with_metadata().await
+    }
+
+    /// Remote backends (ex: gcs) are subject to the upload and download limits of [`RSync`]
+    fn is_remote(&self) -> bool {
+        false
     }
 }
 
 pub struct RSync {
-    source: ReaderWriterInternal,
-    dest: ReaderWriterInternal,
+    source: Box<dyn ReaderWriterBackend>,
+    dest: Box<dyn ReaderWriterBackend>,
     restore_fs_mtime: bool,
-    includes: Option<GlobSet>,
-    excludes: Option<GlobSet>,
+    filter: Arc<filter::Filter>,
+    dry_run: bool,
+    listing_comparison: bool,
+    comparison_strategy: ComparisonStrategy,
+    update_only: bool,
+    ignore_existing: bool,
+    modify_window: std::time::Duration,
+    conflict_resolution: ConflictResolution,
+    progress: Option<Arc<Progress>>,
+    retry_policy: RetryPolicy,
+    bandwidth_limiter: Option<BandwidthLimiter>,
+    upload_limiter: Option<BandwidthLimiter>,
+    download_limiter: Option<BandwidthLimiter>,
+    concurrency: Concurrency,
+    limits: Option<Arc<concurrency::Limits>>,
+    transferred: Arc<AtomicU64>,
+    error_policy: ErrorPolicy,
+    only_paths: Option<BTreeSet<RelativePath>>,
+    entry_filter: entry_filter::EntryFilter,
+    max_delete: Option<MaxDelete>,
+    delete_excluded: bool,
+    backup: Option<Backup>,
 }
 
 impl RSync {
@@ -155,8 +334,28 @@ impl RSync {
             source: source.inner,
             dest: dest.inner,
             restore_fs_mtime: false,
-            includes: None,
-            excludes: None,
+            filter: Arc::default(),
+            dry_run: false,
+            listing_comparison: false,
+            comparison_strategy: ComparisonStrategy::default(),
+            update_only: false,
+            ignore_existing: false,
+            modify_window: std::time::Duration::ZERO,
+            conflict_resolution: ConflictResolution::default(),
+            progress: None,
+            retry_policy: RetryPolicy::default(),
+            bandwidth_limiter: None,
+            upload_limiter: None,
+            download_limiter: None,
+            concurrency: Concurrency::default(),
+            limits: None,
+            transferred: Arc::new(AtomicU64::new(0)),
+            error_policy: ErrorPolicy::default(),
+            only_paths: None,
+            entry_filter: entry_filter::EntryFilter::default(),
+            max_delete: None,
+            delete_excluded: false,
+            backup: None,
         }
     }
 
@@ -165,106 +364,340 @@ impl RSync {
         self
     }
 
-    fn glob_set(globs: &[&str]) -> RSyncResult<Option<GlobSet>> {
-        fn glob_error(error: globset::Error) -> RSyncError {
-            RSyncError::GlobError(error.to_string())
-        }
+    /// Dry run compares source and destination as usual but never writes nor deletes anything on the destination.
+    ///
+    /// Statuses are reported as `Would*` variants (ex: [`RSyncStatus::WouldCreate`], [`RMirrorStatus::WouldDelete`])
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Listing comparison merge-joins the source and destination listings (with size, mtime and crc32c when available)
+    /// instead of requesting metadata entry by entry.
+    ///
+    /// Per entry requests are only done when the listing lacks the needed data (ex: crc32c on fs).
+    /// Entries are sorted in memory for backends which do not list in order (ex: fs).
+    pub fn with_listing_comparison(mut self, listing_comparison: bool) -> Self {
+        self.listing_comparison = listing_comparison;
+        self
+    }
+
+    /// Progress calls `callback` when a transfer starts, for every chunk read from the source or consumed by the destination and when it ends.
+    ///
+    /// Events report the bytes of the file along with the totals of all the transfers done by this client
+    pub fn with_progress<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&ProgressEvent) + Send + Sync + 'static,
+    {
+        self.progress = Some(Arc::new(Progress::new(Arc::new(callback))));
+        self
+    }
+
+    /// Retry policy of transfers failing with a transient error (see [`RSyncError::is_retryable`]), [`RetryPolicy::default`] by default.
+    ///
+    /// Streamed uploads cannot be replayed by the storage client so the whole transfer is retried by re-opening the source.
+    /// Transfers to a remote destination are only replayed when guarded by a generation precondition (creates expect no
+    /// object and updates the planned generation, see [`ReaderWriterBackend::supports_generation_match`]) so that a replay
+    /// never overwrites a concurrent write, they fail with a precondition error instead.
+    ///
+    /// This is the only retry of a transfer: gcs downloads are not retried by the storage client on top of it, so a transfer is
+    /// attempted at most `max_attempts` times within the deadline
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
 
-        if globs.is_empty() {
-            Ok(None)
+    /// Limit the bandwidth of all the transfers, whatever the source and destination
+    pub fn with_bandwidth_limiter(mut self, limiter: BandwidthLimiter) -> Self {
+        self.bandwidth_limiter = Some(limiter);
+        self
+    }
+
+    /// Limit the bandwidth of the transfers writing to a remote destination (ex: gcs)
+    pub fn with_upload_limiter(mut self, limiter: BandwidthLimiter) -> Self {
+        self.upload_limiter = Some(limiter);
+        self
+    }
+
+    /// Limit the bandwidth of the transfers reading from a remote source (ex: gcs)
+    pub fn with_download_limiter(mut self, limiter: BandwidthLimiter) -> Self {
+        self.download_limiter = Some(limiter);
+        self
+    }
+
+    fn throttle(
+        &self,
+        from: &dyn ReaderWriterBackend,
+        to: &dyn ReaderWriterBackend,
+        stream: ByteStream,
+    ) -> ByteStream {
+        [
+            (true, &self.bandwidth_limiter),
+            (to.is_remote(), &self.upload_limiter),
+            (from.is_remote(), &self.download_limiter),
+        ]
+        .into_iter()
+        .filter_map(|(applies, limiter)| limiter.as_ref().filter(|_| applies))
+        .fold(stream, |stream, limiter| limiter.throttle(stream))
+    }
+
+    /// Copy an entry from a backend to another one, `source` is the state of the entry being read and `dest` the planned state of the replaced entry.
+    ///
+    /// Transfers to a remote backend are only retried when guarded by a generation precondition, an unguarded replay could
+    /// overwrite a concurrent write
+    async fn transfer(
+        &self,
+        from: &dyn ReaderWriterBackend,
+        from_path: &RelativePath,
+        to: &dyn ReaderWriterBackend,
+        to_path: &RelativePath,
+        source: &EntryState,
+        dest: &EntryState,
+    ) -> RSyncResult<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        let (mtime, restore_fs_mtime) = (source.mtime, self.restore_fs_mtime);
+        let checks = WriteChecks {
+            crc32c: source.crc32c,
+            if_generation_match: if dest.exists() {
+                dest.generation
+            } else {
+                Some(0)
+            },
+            resume_from: None,
+        };
+        let guarded = to.supports_generation_match() && checks.if_generation_match.is_some();
+        let retry_policy = if to.is_remote() && guarded.not() {
+            RetryPolicy::no_retry()
         } else {
-            let glob_set = {
-                let mut builer = GlobSetBuilder::new();
-                for glob in globs {
-                    builer.add(Glob::new(glob).map_err(glob_error)?);
+            self.retry_policy.to_owned()
+        };
+        let file = self
+            .progress
+            .as_ref()
+            .map(|progress| progress.start(to_path, source.size));
+        let result = retry_policy
+            .retry(RSyncError::is_retryable, || async {
+                let _permit = match &self.limits {
+                    Some(limits) => Some(limits.transfers.acquire().await),
+                    None => None,
+                };
+                let read_failed = Arc::new(AtomicBool::new(false));
+                let (stream, expected_crc32c, resumed) =
+                    resume::open_source(from, from_path, to, to_path, source)
+                        .await
+                        .map_err(|e| e.entry_failed(to_path, Operation::Write))?;
+                let stream = {
+                    let read_failed = read_failed.clone();
+                    stream
+                        .inspect_err(move |_| read_failed.store(true, Ordering::Relaxed))
+                        .boxed()
+                };
+                let checks = WriteChecks {
+                    crc32c: expected_crc32c,
+                    resume_from: resumed.map(|x| x.size),
+                    ..checks
+                };
+                let (stream, mismatch) = match expected_crc32c {
+                    Some(expected) => {
+                        // a resumed content is verified as a whole
+                        let (stream, mismatch) = integrity::verify_crc32c(
+                            stream,
+                            from_path,
+                            expected,
+                            resumed.map_or(0, |x| x.crc32c),
+                        );
+                        (stream, Some(mismatch))
+                    }
+                    None => (stream, None),
+                };
+                let stream = self.throttle(from, to, stream);
+                let (stream, transferred) = report::count(stream);
+                let stream = match &file {
+                    Some(file) => file.track(stream),
+                    None => stream,
+                };
+                let result = to
+                    .write_checked(mtime, restore_fs_mtime, to_path, checks, stream)
+                    .await;
+                // the writer may wrap the stream error (ex: gcs uploads) or not even return it
+                let actual = mismatch.as_ref().and_then(|x| x.get().copied());
+                let result = match (actual, expected_crc32c) {
+                    (Some(actual), Some(expected)) => Err(RSyncError::Crc32cMismatch {
+                        path: from_path.to_owned(),
+                        expected,
+                        actual,
+                    }
+                    .entry_failed(to_path, Operation::Verify)),
+                    _ => result,
+                };
+                if let Some(limits) = &self.limits {
+                    limits.transfers.record(&result);
+                }
+                if result.is_ok() {
+                    let transferred = transferred.load(Ordering::Relaxed);
+                    self.transferred.fetch_add(transferred, Ordering::Relaxed);
                 }
-                builer.build().map_err(glob_error)?
-            };
-            Ok(Some(glob_set))
+                result.map_err(|e| match (read_failed.load(Ordering::Relaxed), e) {
+                    (true, e) => e.entry_failed(from_path, Operation::Read),
+                    // the writer verified the uploaded content itself (ex: gcs uploads without a known crc32c)
+                    (false, e @ RSyncError::Crc32cMismatch { .. }) => {
+                        e.entry_failed(to_path, Operation::Verify)
+                    }
+                    (false, e) => e.entry_failed(to_path, Operation::Write),
+                })
+            })
+            .await;
+        if let Some(file) = file {
+            file.finish(&result);
         }
+        result
     }
 
-    pub fn with_includes(mut self, includes: &[&str]) -> RSyncResult<Self> {
-        self.includes = Self::glob_set(includes)?;
-        Ok(self)
+    async fn write_entry(&self, entry: &PlannedEntry) -> RSyncResult<()> {
+        let path = &entry.path;
+        self.transfer(
+            self.source.as_ref(),
+            path,
+            self.dest.as_ref(),
+            path,
+            &entry.source,
+            &entry.dest,
+        )
+        .await
     }
 
-    pub fn with_excludes(mut self, excludes: &[&str]) -> RSyncResult<Self> {
-        self.excludes = Self::glob_set(excludes)?;
-        Ok(self)
+    fn created(&self, path: &RelativePath) -> RSyncStatus {
+        if self.dry_run {
+            RSyncStatus::WouldCreate(path.to_owned())
+        } else {
+            RSyncStatus::Created(path.to_owned())
+        }
     }
 
-    async fn write_entry(
-        &self,
-        mtime: Option<chrono::DateTime<chrono::Utc>>,
+    fn updated(&self, reason: &str, path: &RelativePath) -> RSyncStatus {
+        if self.dry_run {
+            RSyncStatus::would_update(reason, path)
+        } else {
+            RSyncStatus::updated(reason, path)
+        }
+    }
+
+    async fn entry_state(
+        client: &dyn ReaderWriterBackend,
         path: &RelativePath,
-    ) -> RSyncResult<()> {
-        let source = self.source.read(path).await;
-        self.dest
-            .write(mtime, self.restore_fs_mtime, path, source)
-            .await?;
-        Ok(())
+    ) -> RSyncResult<EntryState> {
+        client
+            .entry_state(path)
+            .await
+            .map_err(|e| e.entry_failed(path, Operation::Stat))
     }
 
-    async fn sync_entry_crc32c(&self, path: &RelativePath) -> RSyncResult<RSyncStatus> {
-        Ok(match self.dest.get_crc32c(path).await? {
-            None => {
-                self.write_entry(None, path).await?;
-                RSyncStatus::updated("no dest crc32c", path)
-            }
+    async fn crc32c(
+        client: &dyn ReaderWriterBackend,
+        path: &RelativePath,
+    ) -> RSyncResult<Option<u32>> {
+        client
+            .get_crc32c(path)
+            .await
+            .map_err(|e| e.entry_failed(path, Operation::Stat))
+    }
+
+    fn compare_crc32c(&self, source: Option<u32>, dest: Option<u32>) -> SyncAction {
+        match dest {
+            None => SyncAction::update("no dest crc32c"),
             Some(crc32c_dest) => {
-                let crc32c_source = self.source.get_crc32c(path).await?;
-                if Some(crc32c_dest) == crc32c_source {
-                    RSyncStatus::already_synced("same crc32c", path)
+                if Some(crc32c_dest) == source {
+                    SyncAction::skip("same crc32c")
                 } else {
-                    self.write_entry(None, path).await?;
-                    RSyncStatus::updated("different crc32c", path)
+                    SyncAction::update("different crc32c")
                 }
             }
-        })
+        }
     }
 
-    async fn sync_entry(&self, path: &RelativePath) -> RSyncResult<RSyncStatus> {
-        Ok(match self.dest.size_and_mt(path).await? {
-            (Some(dest_dt), Some(dest_size)) => match self.source.size_and_mt(path).await? {
-                (Some(source_dt), Some(source_size)) => {
-                    let dest_ts = dest_dt.timestamp();
-                    let source_ts = source_dt.timestamp();
-                    if dest_ts == source_ts && dest_size == source_size {
-                        RSyncStatus::already_synced("same mtime and size", path)
-                    } else {
-                        self.write_entry(Some(source_dt), path).await?;
-                        RSyncStatus::updated("different size or mtime", path)
-                    }
+    /// Checksum rule, checksums already known (ex: listed) are not requested again
+    async fn plan_entry_checksum(
+        &self,
+        path: &RelativePath,
+        mut source: EntryState,
+        mut dest: EntryState,
+    ) -> RSyncResult<PlannedEntry> {
+        if let Some(action) = self.compare_md5(path, &mut source, &mut dest).await? {
+            return Ok(PlannedEntry::new(path, action, source, dest));
+        }
+        if dest.crc32c.is_none() {
+            dest.crc32c = Self::crc32c(self.dest.as_ref(), path).await?;
+        }
+        if dest.crc32c.is_some() && source.crc32c.is_none() {
+            source.crc32c = Self::crc32c(self.source.as_ref(), path).await?;
+        }
+        let action = self.compare_crc32c(source.crc32c, dest.crc32c);
+        Ok(PlannedEntry::new(path, action, source, dest))
+    }
+
+    async fn md5(
+        client: &dyn ReaderWriterBackend,
+        path: &RelativePath,
+    ) -> RSyncResult<Option<String>> {
+        client
+            .get_md5(path)
+            .await
+            .map_err(|e| e.entry_failed(path, Operation::Stat))
+    }
+
+    /// Compare source and destination without changing anything, this is where all the sync rules live
+    async fn plan_entry(&self, path: &RelativePath) -> RSyncResult<PlannedEntry> {
+        let dest = Self::entry_state(self.dest.as_ref(), path).await?;
+        let source = Self::entry_state(self.source.as_ref(), path).await?;
+        if dest.exists().not() {
+            return Ok(PlannedEntry::new(path, SyncAction::Create, source, dest));
+        }
+        match self.compare_metadata(&source, &dest) {
+            Some(action) => Ok(PlannedEntry::new(path, action, source, dest)),
+            None => self.plan_entry_checksum(path, source, dest).await,
+        }
+    }
+
+    async fn apply_sync_action(&self, entry: &PlannedEntry) -> RSyncResult<RSyncStatus> {
+        let path = &entry.path;
+        Ok(match &entry.action {
+            SyncAction::Create => {
+                self.write_entry(entry).await?;
+                self.created(path)
+            }
+            SyncAction::Update { reason } => {
+                let backup = self.backup_entry(path, false).await?;
+                self.write_entry(entry).await?;
+                match backup {
+                    Some(backup) => RSyncStatus::UpdatedWithBackup {
+                        reason: reason.to_owned(),
+                        path: path.to_owned(),
+                        backup,
+                    },
+                    None => self.updated(reason, path),
                 }
-                _ => self.sync_entry_crc32c(path).await?,
-            },
-            (None, None) => {
-                let (mtime, _) = self.source.size_and_mt(path).await?;
-                self.write_entry(mtime, path).await?;
-                RSyncStatus::Created(path.to_owned())
             }
-            _ => self.sync_entry_crc32c(path).await?,
+            SyncAction::Skip { reason } => RSyncStatus::already_synced(reason, path),
+            SyncAction::SkipExisting => RSyncStatus::SkippedExisting(path.to_owned()),
+            SyncAction::SkipNewerDest => RSyncStatus::SkippedNewerDest(path.to_owned()),
+            SyncAction::Delete => {
+                return Err(RSyncError::UnexpectedSyncAction {
+                    path: path.to_owned(),
+                    action: entry.action.to_owned(),
+                })
+            }
         })
     }
 
+    async fn sync_entry(&self, path: &RelativePath) -> RSyncResult<RSyncStatus> {
+        let entry = self.plan_entry(path).await?;
+        self.apply_sync_action(&entry).await
+    }
+
     fn filter(&self, relative_path: &RelativePath) -> bool {
-        let i = self
-            .includes
-            .as_ref()
-            .map(|includes| {
-                includes
-                    .matches(relative_path.path.as_str())
-                    .is_empty()
-                    .not()
-            })
-            .unwrap_or(true);
-        let x = self
-            .excludes
-            .as_ref()
-            .map(|excludes| excludes.matches(relative_path.path.as_str()).is_empty())
-            .unwrap_or(true);
-        i && x
+        self.filter.is_included(relative_path) && self.selected(relative_path)
     }
 
     /// Sync synchronize source to destination by comparing crc32c if destination already exists
@@ -317,22 +750,83 @@ impl RSync {
         &self,
     ) -> impl Stream<Item = RSyncResult<impl Future<Output = RSyncResult<RSyncStatus>> + '_>> + '_
     {
-        self.source
-            .list()
+        if self.listing_comparison {
+            return Either::Left(self.sync_listing().await.map_ok(Either::Left));
+        }
+
+        Either::Right(
+            self.source_paths()
+                .await
+                .map_ok(move |path| Either::Right(async move { self.sync_entry(&path).await })),
+        )
+    }
+
+    /// Delete a destination entry, only its `generation` when known so that an entry replaced in the meantime is left as is
+    async fn delete_entry(
+        &self,
+        path: &RelativePath,
+        generation: Option<i64>,
+    ) -> RSyncResult<RMirrorStatus> {
+        if self.dry_run {
+            return Ok(RMirrorStatus::WouldDelete(path.to_owned()));
+        }
+        let backup = self.backup_entry(path, true).await?;
+        self.dest
+            .delete_checked(path, generation)
             .await
-            .try_filter(|x| futures::future::ready(self.filter(x)))
-            .map_ok(move |path| async move { self.sync_entry(&path).await })
+            .map_err(|e| e.entry_failed(path, Operation::Delete))?;
+        Ok(match backup {
+            Some(backup) => RMirrorStatus::DeletedWithBackup {
+                path: path.to_owned(),
+                backup,
+            },
+            None => RMirrorStatus::Deleted(path.to_owned()),
+        })
     }
 
+    async fn should_delete(&self, path: &RelativePath) -> RSyncResult<bool> {
+        if self.filter.is_included(path).not() {
+            return Ok(self.delete_excluded);
+        }
+        let exists = self
+            .source
+            .exists(path)
+            .await
+            .map_err(|e| e.entry_failed(path, Operation::Stat))?;
+        Ok(exists.not())
+    }
+
+    /// Delete the extra destination entries, `checked` are the deletions already computed for the [`MaxDelete`] guard
     async fn delete_extras(
         &self,
+        checked: Option<Vec<(RelativePath, bool)>>,
     ) -> impl Stream<Item = RSyncResult<impl Future<Output = RSyncResult<RMirrorStatus>> + '_>> + '_
     {
-        self.dest.list().await.map(move |result| {
-            result.map(|path| async move {
-                if self.source.exists(&path).await?.not() || self.filter(&path).not() {
-                    self.dest.delete(&path).await?;
-                    Ok(RMirrorStatus::Deleted(path))
+        let paths = match checked {
+            Some(checked) => Either::Left(futures::stream::iter(
+                checked
+                    .into_iter()
+                    .map(|(path, delete)| Ok((path, Some(delete)))),
+            )),
+            None => Either::Right(
+                self.dest
+                    .list_in_scope(self.dest_list_scope())
+                    .await
+                    .try_filter(|x| futures::future::ready(self.selected(x)))
+                    .map_ok(|path| (path, None)),
+            ),
+        };
+        paths.map(move |result| {
+            result.map(|(path, delete)| async move {
+                if self.is_protected(&path) {
+                    return Ok(RMirrorStatus::Excluded(path));
+                }
+                let delete = match delete {
+                    Some(delete) => delete,
+                    None => self.should_delete(&path).await?,
+                };
+                if delete {
+                    self.delete_entry(&path, None).await
                 } else {
                     Ok(RMirrorStatus::NotDeleted(path))
                 }
@@ -342,6 +836,8 @@ impl RSync {
 
     /// Mirror synchronize source to destination by deleting extras (destination)
     ///
+    /// With [`RSync::with_max_delete`] deletions are computed first and nothing is changed when they exceed the guard
+    ///
     /// Example
     /// ```rust
     /// use std::{path::PathBuf, str::FromStr};
@@ -397,6 +893,17 @@ impl RSync {
     > {
         self.source.is_valid().await?;
 
+        if self.listing_comparison {
+            return Ok(Either::Left(
+                self.mirror_listing().await?.map_ok(Either::Left),
+            ));
+        }
+
+        let checked = match self.max_delete {
+            Some(_) => Some(self.checked_deletions().await?),
+            None => None,
+        };
+
         let synced = self
             .sync()
             .await
@@ -404,15 +911,16 @@ impl RSync {
             .map_ok(futures::future::Either::Left);
 
         let deleted = self
-            .delete_extras()
+            .delete_extras(checked)
             .await
             .map_ok(futures::future::Either::Right);
 
-        Ok(synced.chain(deleted))
+        Ok(Either::Right(synced.chain(deleted).map_ok(Either::Right)))
     }
 }
 
-#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
 pub struct RelativePath {
     path: String,
 }
@@ -432,19 +940,23 @@ impl RelativePath {
             Ok(Self { path })
         }
     }
+
+    pub fn as_str(&self) -> &str {
+        self.path.as_str()
+    }
 }
-#[derive(Debug, PartialEq, Clone)]
-struct Entry {
-    path: RelativePath,
-    crc32c: u32,
+
+impl TryFrom<String> for RelativePath {
+    type Error = RSyncError;
+
+    fn try_from(path: String) -> Result<Self, Self::Error> {
+        Self::new(&path)
+    }
 }
 
-impl Entry {
-    pub(self) fn new(path: &RelativePath, crc32c: u32) -> Self {
-        Self {
-            path: path.to_owned(),
-            crc32c,
-        }
+impl From<RelativePath> for String {
+    fn from(relative_path: RelativePath) -> Self {
+        relative_path.path
     }
 }
 
@@ -460,9 +972,47 @@ pub enum RSyncError {
     EmptyRelativePathError,
     GlobError(String),
     InvalidRsyncSource(String),
+    MemoryObjectNotFound(RelativePath),
+    PlanOutdated {
+        path: RelativePath,
+        reason: String,
+    },
+    UnsortedListing {
+        side: String,
+        path: RelativePath,
+    },
+    BisyncConflict(RelativePath),
+    InvalidFilterRule(String),
+    EntryFailed(Box<FailedEntry>),
+    /// A mirror would delete more than [`RSync::with_max_delete`] allows, nothing has been changed
+    TooManyDeletions {
+        deletions: usize,
+        dest_entries: usize,
+        max_delete: MaxDelete,
+    },
+    /// The crc32c computed while transferring an entry differs from the source crc32c, the destination has not been written
+    Crc32cMismatch {
+        path: RelativePath,
+        expected: u32,
+        actual: u32,
+    },
+    /// A planned action that can not be applied by sync (ex: a [`SyncAction::Delete`] outside of [`RSync::execute`])
+    UnexpectedSyncAction {
+        path: RelativePath,
+        action: SyncAction,
+    },
 }
 
 impl RSyncError {
+    /// Only transient storage errors are retried (see [`crate::storage::Error::is_retryable`])
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            RSyncError::StorageError(e) => e.is_retryable(),
+            RSyncError::EntryFailed(entry) => entry.error.is_retryable(),
+            _ => false,
+        }
+    }
+
     fn fs_io_error<T, U>(message: U, path: T, error: std::io::Error) -> RSyncError
     where
         T: AsRef<Path>,
@@ -487,8 +1037,29 @@ impl std::error::Error for RSyncError {}
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum RSyncStatus {
     Created(RelativePath),
-    Updated { reason: String, path: RelativePath },
-    AlreadySynced { reason: String, path: RelativePath },
+    Updated {
+        reason: String,
+        path: RelativePath,
+    },
+    AlreadySynced {
+        reason: String,
+        path: RelativePath,
+    },
+    WouldCreate(RelativePath),
+    WouldUpdate {
+        reason: String,
+        path: RelativePath,
+    },
+    /// Not overwritten because the destination exists, see [`RSync::with_ignore_existing`]
+    SkippedExisting(RelativePath),
+    /// Not overwritten because the destination is newer, see [`RSync::with_update_only`]
+    SkippedNewerDest(RelativePath),
+    /// Updated after the previous destination entry was kept at `backup`, see [`RSync::with_backup`]
+    UpdatedWithBackup {
+        reason: String,
+        path: RelativePath,
+        backup: RelativePath,
+    },
 }
 
 impl RSyncStatus {
@@ -498,6 +1069,12 @@ impl RSyncStatus {
         Self::Updated { reason, path }
     }
 
+    fn would_update(reason: &str, path: &RelativePath) -> Self {
+        let reason = reason.to_owned();
+        let path = path.to_owned();
+        Self::WouldUpdate { reason, path }
+    }
+
     fn already_synced(reason: &str, path: &RelativePath) -> Self {
         let reason = reason.to_owned();
         let path = path.to_owned();
@@ -510,12 +1087,34 @@ pub enum RMirrorStatus {
     Synced(RSyncStatus),
     Deleted(RelativePath),
     NotDeleted(RelativePath),
+    WouldDelete(RelativePath),
+    /// Not deleted because it is excluded, see [`RSync::with_delete_excluded`].
+    /// Entries of excluded directories pruned from the destination listing are not reported
+    Excluded(RelativePath),
+    /// Deleted after it was kept at `backup`, see [`RSync::with_backup`]
+    DeletedWithBackup {
+        path: RelativePath,
+        backup: RelativePath,
+    },
 }
 
 pub type RSyncResult<T> = Result<T, RSyncError>;
 
 #[cfg(test)]
 mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    };
+    use std::time::Duration;
+
+    use futures::TryStreamExt;
+
+    use crate::storage::RetryPolicy;
+    use crate::sync::testing::{
+        content, created, flaky_rsync, mtime, sync, FlakyStore, CONCURRENCY_LEVEL,
+    };
+    use crate::sync::{MemoryStore, ProgressEvent, RSync, ReaderWriter};
     use crate::{gcp::sync::RelativePath, sync::RSyncError};
 
     #[test]
@@ -537,4 +1136,94 @@ mod tests {
             RelativePath::new("hello/world").unwrap().path
         );
     }
+
+    #[tokio::test]
+    async fn test_retries_transient_write_errors() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        source.insert("a.txt", "hello", mtime(1)).unwrap();
+
+        let (rsync, writes) = flaky_rsync(&source, &dest, 2, 3);
+        assert_eq!(vec![created("a.txt")], sync(&rsync).await);
+        assert_eq!(3, writes.load(Ordering::SeqCst));
+        assert_eq!(Some("hello".into()), content(&dest, "a.txt"));
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_attempts() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        source.insert("a.txt", "hello", mtime(1)).unwrap();
+
+        let (rsync, writes) = flaky_rsync(&source, &dest, 2, 2);
+        let actual = rsync
+            .sync()
+            .await
+            .try_buffer_unordered(CONCURRENCY_LEVEL)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap_err();
+        assert!(actual.is_retryable());
+        assert_eq!(2, writes.load(Ordering::SeqCst));
+        assert!(dest.paths().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_retries_do_not_double_count_progress() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        source.insert("a.txt", "hello", mtime(1)).unwrap();
+
+        let (rsync, _) = flaky_rsync(&source, &dest, 2, 3);
+        let events = Arc::new(Mutex::new(Vec::<ProgressEvent>::new()));
+        let rsync = {
+            let events = events.clone();
+            rsync.with_progress(move |x| events.lock().unwrap().push(x.to_owned()))
+        };
+        assert_eq!(vec![created("a.txt")], sync(&rsync).await);
+
+        let events = events.lock().unwrap();
+        let totals = &events.last().unwrap().totals;
+        assert_eq!(
+            (1, 1, 5, 5, 0),
+            (
+                totals.files_started,
+                totals.files_finished,
+                totals.bytes_read,
+                totals.bytes_written,
+                totals.bytes_remaining
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_does_not_replay_unguarded_remote_writes() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        source.insert("a.txt", "hello", mtime(1)).unwrap();
+
+        let writes = Arc::new(AtomicUsize::new(0));
+        let flaky = FlakyStore {
+            store: dest.clone(),
+            failures: 1,
+            writes: writes.clone(),
+            remote: true,
+        };
+        let retry_policy = RetryPolicy::default().with_max_attempts(3).with_backoff(
+            Duration::ZERO,
+            Duration::ZERO,
+            2.0,
+        );
+        let rsync = RSync::new(ReaderWriter::memory(&source), ReaderWriter::new(flaky))
+            .with_retry_policy(retry_policy);
+        let actual = rsync
+            .sync()
+            .await
+            .try_buffer_unordered(CONCURRENCY_LEVEL)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap_err();
+        assert!(actual.is_retryable());
+        assert_eq!(1, writes.load(Ordering::SeqCst));
+    }
 }
@@ -0,0 +1,417 @@
+use std::ops::Not;
+
+use futures::{Future, Stream, StreamExt, TryStreamExt};
+
+use super::{RMirrorStatus, RSync, RSyncError, RSyncResult, RelativePath, Size};
+
+/// Entry metadata as seen while planning, used to detect changes before executing a plan
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EntryState {
+    pub mtime: Option<chrono::DateTime<chrono::Utc>>,
+    pub size: Option<Size>,
+    pub crc32c: Option<u32>,
+    /// Lowercase hex digest like md5sum
+    pub md5: Option<String>,
+    /// Version of the entry content for backends versioning their entries (ex: gcs object generation), used as a write precondition
+    #[serde(default)]
+    pub generation: Option<i64>,
+    /// The mtime only carries whole seconds (ex: gcs objects written by gsutil) so its sub-second part is not compared
+    #[serde(default)]
+    pub mtime_in_seconds: bool,
+}
+
+impl EntryState {
+    pub(super) fn new(mtime: Option<chrono::DateTime<chrono::Utc>>, size: Option<Size>) -> Self {
+        Self {
+            mtime,
+            size,
+            crc32c: None,
+            md5: None,
+            generation: None,
+            mtime_in_seconds: false,
+        }
+    }
+
+    pub fn exists(&self) -> bool {
+        self.mtime.is_some() || self.size.is_some()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum SyncAction {
+    Create,
+    Update {
+        reason: String,
+    },
+    Delete,
+    Skip {
+        reason: String,
+    },
+    /// The destination exists and is not overwritten, see [`RSync::with_ignore_existing`]
+    SkipExisting,
+    /// The destination is newer than the source, see [`RSync::with_update_only`]
+    SkipNewerDest,
+}
+
+impl SyncAction {
+    pub(super) fn update(reason: &str) -> Self {
+        let reason = reason.to_owned();
+        Self::Update { reason }
+    }
+
+    pub(super) fn skip(reason: &str) -> Self {
+        let reason = reason.to_owned();
+        Self::Skip { reason }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedEntry {
+    pub path: RelativePath,
+    pub action: SyncAction,
+    pub source: EntryState,
+    pub dest: EntryState,
+}
+
+impl PlannedEntry {
+    pub(super) fn new(
+        path: &RelativePath,
+        action: SyncAction,
+        source: EntryState,
+        dest: EntryState,
+    ) -> Self {
+        Self {
+            path: path.to_owned(),
+            action,
+            source,
+            dest,
+        }
+    }
+}
+
+/// Serializable list of actions computed by [`RSync::plan`] or [`RSync::plan_mirror`] and run by [`RSync::execute`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncPlan {
+    pub entries: Vec<PlannedEntry>,
+}
+
+impl SyncPlan {
+    fn new(mut entries: Vec<PlannedEntry>) -> Self {
+        entries.sort_by(|x, y| (&x.path, &x.action).cmp(&(&y.path, &y.action)));
+        Self { entries }
+    }
+}
+
+impl RSync {
+    async fn plan_deletion(&self, path: RelativePath) -> RSyncResult<Option<PlannedEntry>> {
+        if self.should_delete(&path).await?.not() {
+            return Ok(None);
+        }
+        let source = Self::entry_state(self.source.as_ref(), &path).await?;
+        let dest = Self::entry_state(self.dest.as_ref(), &path).await?;
+        Ok(Some(PlannedEntry::new(
+            &path,
+            SyncAction::Delete,
+            source,
+            dest,
+        )))
+    }
+
+    /// Plan computes what [`RSync::sync`] would do without changing anything.
+    ///
+    /// The plan can be reviewed, stored (serde) and then run with [`RSync::execute`]
+    pub async fn plan(&self, concurrency: usize) -> RSyncResult<SyncPlan> {
+        let entries = self
+            .source_paths()
+            .await
+            .map_ok(|path| async move { self.plan_entry(&path).await })
+            .try_buffer_unordered(concurrency)
+            .try_collect::<Vec<_>>()
+            .await?;
+        Ok(SyncPlan::new(entries))
+    }
+
+    /// Plan computes what [`RSync::mirror`] would do without changing anything, including deletions
+    pub async fn plan_mirror(&self, concurrency: usize) -> RSyncResult<SyncPlan> {
+        self.source.is_valid().await?;
+        let mut plan = self.plan(concurrency).await?;
+
+        let dest_paths = self
+            .dest
+            .list_in_scope(self.dest_list_scope())
+            .await
+            .try_filter(|x| futures::future::ready(self.selected(x) && self.is_protected(x).not()))
+            .try_collect::<Vec<_>>()
+            .await?;
+        let dest_entries = dest_paths.len();
+        let deletions = futures::stream::iter(dest_paths)
+            .map(|path| self.plan_deletion(path))
+            .buffer_unordered(concurrency)
+            .try_filter_map(futures::future::ok)
+            .try_collect::<Vec<_>>()
+            .await?;
+        self.check_max_delete(deletions.len(), dest_entries)?;
+
+        plan.entries.extend(deletions);
+        Ok(SyncPlan::new(plan.entries))
+    }
+
+    async fn check_unchanged(&self, entry: &PlannedEntry) -> RSyncResult<()> {
+        async fn check(
+            side: &str,
+            client: &dyn super::ReaderWriterBackend,
+            entry: &PlannedEntry,
+            expected: &EntryState,
+        ) -> RSyncResult<()> {
+            let path = &entry.path;
+            let mut actual = RSync::entry_state(client, path).await?;
+            if expected.crc32c.is_some() {
+                actual.crc32c = RSync::crc32c(client, path).await?;
+            }
+            if expected.md5.is_some() {
+                actual.md5 = RSync::md5(client, path).await?;
+            }
+            if expected.generation.is_none() {
+                actual.generation = None;
+            }
+
+            if &actual == expected {
+                Ok(())
+            } else {
+                Err(RSyncError::PlanOutdated {
+                    path: path.to_owned(),
+                    reason: format!("{side} changed since planning"),
+                })
+            }
+        }
+
+        check("source", self.source.as_ref(), entry, &entry.source).await?;
+        check("dest", self.dest.as_ref(), entry, &entry.dest).await
+    }
+
+    async fn execute_entry(&self, entry: &PlannedEntry) -> RSyncResult<RMirrorStatus> {
+        match entry.action {
+            SyncAction::Skip { .. } | SyncAction::SkipExisting | SyncAction::SkipNewerDest => self
+                .apply_sync_action(entry)
+                .await
+                .map(RMirrorStatus::Synced),
+            SyncAction::Delete => {
+                self.check_unchanged(entry).await?;
+                self.delete_entry(&entry.path, entry.dest.generation).await
+            }
+            SyncAction::Create | SyncAction::Update { .. } => {
+                self.check_unchanged(entry).await?;
+                self.apply_sync_action(entry)
+                    .await
+                    .map(RMirrorStatus::Synced)
+            }
+        }
+    }
+
+    /// Execute runs exactly the actions of a plan computed by [`RSync::plan`] or [`RSync::plan_mirror`].
+    ///
+    /// An entry fails with [`RSyncError::PlanOutdated`] when its source or destination changed since planning
+    ///
+    /// Example
+    /// ```rust
+    /// use futures::TryStreamExt;
+    /// use gcs_rsync::sync::{MemoryStore, RSync, RSyncResult, ReaderWriter};
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> RSyncResult<()> {
+    ///     let source = MemoryStore::new();
+    ///     source.insert("hello.txt", "hello", None)?;
+    ///     let rsync = RSync::new(ReaderWriter::memory(&source), ReaderWriter::memory(&MemoryStore::new()));
+    ///
+    ///     let plan = rsync.plan_mirror(12).await?;
+    ///     println!("{}", serde_json::to_string(&plan).unwrap());
+    ///
+    ///     rsync
+    ///         .execute(&plan)
+    ///         .try_buffer_unordered(12)
+    ///         .try_for_each(|x| {
+    ///             println!("{:?}", x);
+    ///             futures::future::ok(())
+    ///         })
+    ///         .await
+    /// }
+    /// ```
+    pub fn execute<'a>(
+        &'a self,
+        plan: &'a SyncPlan,
+    ) -> impl Stream<Item = RSyncResult<impl Future<Output = RSyncResult<RMirrorStatus>> + 'a>> + 'a
+    {
+        futures::stream::iter(plan.entries.iter())
+            .map(move |entry| Ok(async move { self.execute_entry(entry).await }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{StreamExt, TryStreamExt};
+
+    use crate::sync::testing::{
+        already_synced, created, deleted, mtime, path, rsync, synced, updated, CONCURRENCY_LEVEL,
+    };
+    use crate::sync::{
+        FilterRules, MemoryStore, RMirrorStatus, RSync, RSyncError, RSyncStatus, ReaderWriter,
+        RelativePath,
+    };
+
+    use super::{EntryState, PlannedEntry, SyncAction, SyncPlan};
+
+    async fn execute(rsync: &RSync, plan: &SyncPlan) -> Vec<Result<RMirrorStatus, RSyncError>> {
+        let mut actual = rsync
+            .execute(plan)
+            .try_buffer_unordered(CONCURRENCY_LEVEL)
+            .into_stream()
+            .collect::<Vec<_>>()
+            .await;
+        actual.sort_by_key(|x| format!("{:?}", x));
+        actual
+    }
+
+    async fn deletions(rsync: &RSync) -> Vec<RelativePath> {
+        let plan = rsync.plan_mirror(4).await.unwrap();
+        plan.entries
+            .into_iter()
+            .filter(|x| x.action == SyncAction::Delete)
+            .map(|x| x.path)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_plan_mirror_follows_mirror_scope() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        source.insert("a.txt", "a", None).unwrap();
+        dest.insert("cache/b.txt", "b", None).unwrap();
+        dest.insert("extra.txt", "x", None).unwrap();
+        let rules = FilterRules::parse("- /cache/\n").unwrap();
+        let rsync = RSync::new(ReaderWriter::memory(&source), ReaderWriter::memory(&dest))
+            .with_filter_rules(rules.to_owned());
+        assert_eq!(vec![path("extra.txt")], deletions(&rsync).await);
+
+        let rsync = rsync.with_delete_excluded(true);
+        assert_eq!(
+            vec![path("cache/b.txt"), path("extra.txt")],
+            deletions(&rsync).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_refuses_delete_action() {
+        let store = MemoryStore::new();
+        let rsync = RSync::new(ReaderWriter::memory(&store), ReaderWriter::memory(&store));
+        let entry = PlannedEntry::new(
+            &path("a.txt"),
+            SyncAction::Delete,
+            EntryState::default(),
+            EntryState::default(),
+        );
+        assert!(matches!(
+            rsync.apply_sync_action(&entry).await,
+            Err(RSyncError::UnexpectedSyncAction { path, .. }) if path.as_str() == "a.txt"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_plan_and_execute() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        source.insert("new.txt", "new", mtime(1)).unwrap();
+        source.insert("same.txt", "same", mtime(1)).unwrap();
+        source.insert("updated.txt", "updated", mtime(2)).unwrap();
+        dest.insert("same.txt", "same", mtime(1)).unwrap();
+        dest.insert("updated.txt", "old", mtime(1)).unwrap();
+        dest.insert("extra.txt", "extra", mtime(1)).unwrap();
+
+        let rsync = rsync(&source, &dest);
+        let plan = rsync.plan_mirror(CONCURRENCY_LEVEL).await.unwrap();
+        assert_eq!(
+            vec![
+                (path("extra.txt"), SyncAction::Delete),
+                (path("new.txt"), SyncAction::Create),
+                (
+                    path("same.txt"),
+                    SyncAction::Skip {
+                        reason: "same mtime and size".to_owned()
+                    }
+                ),
+                (
+                    path("updated.txt"),
+                    SyncAction::Update {
+                        reason: "different size or mtime".to_owned()
+                    }
+                ),
+            ],
+            plan.entries
+                .iter()
+                .map(|x| (x.path.to_owned(), x.action.to_owned()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some(7), plan.entries[3].source.size);
+        assert_eq!(mtime(1), plan.entries[3].dest.mtime);
+        assert_eq!(3, dest.paths().len(), "planning must not change anything");
+
+        let json = serde_json::to_string(&plan).unwrap();
+        let plan: SyncPlan = serde_json::from_str(&json).unwrap();
+
+        let mut actual = rsync
+            .execute(&plan)
+            .try_buffer_unordered(CONCURRENCY_LEVEL)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        actual.sort();
+        assert_eq!(
+            vec![
+                synced(created("new.txt")),
+                synced(updated("different size or mtime", "updated.txt")),
+                synced(already_synced("same mtime and size", "same.txt")),
+                deleted("extra.txt"),
+            ],
+            actual
+        );
+        assert_eq!(
+            vec![path("new.txt"), path("same.txt"), path("updated.txt")],
+            dest.paths()
+        );
+        assert_eq!(dest.get("updated.txt").unwrap().content, "updated");
+    }
+
+    #[tokio::test]
+    async fn test_execute_refuses_outdated_plan() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        source.insert("changed.txt", "v1", mtime(1)).unwrap();
+        source.insert("unchanged.txt", "v1", mtime(1)).unwrap();
+        dest.insert("extra.txt", "extra", mtime(1)).unwrap();
+
+        let rsync = rsync(&source, &dest);
+        let plan = rsync.plan_mirror(CONCURRENCY_LEVEL).await.unwrap();
+
+        source.insert("changed.txt", "v2", mtime(2)).unwrap();
+        source.insert("extra.txt", "extra", mtime(1)).unwrap();
+
+        let actual = execute(&rsync, &plan).await;
+        assert_eq!(3, actual.len());
+        assert!(matches!(
+            &actual[0],
+            Err(RSyncError::PlanOutdated { path, .. }) if path.as_str() == "changed.txt"
+        ));
+        assert!(matches!(
+            &actual[1],
+            Err(RSyncError::PlanOutdated { path, .. }) if path.as_str() == "extra.txt"
+        ));
+        assert!(matches!(
+            &actual[2],
+            Ok(RMirrorStatus::Synced(RSyncStatus::Created(_)))
+        ));
+        assert_eq!(vec![path("extra.txt"), path("unchanged.txt")], dest.paths());
+    }
+}
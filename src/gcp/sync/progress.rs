@@ -0,0 +1,266 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use futures::StreamExt;
+
+use super::{ByteStream, RSyncResult, RelativePath, Size};
+
+/// Callback given to [`super::RSync::with_progress`], it is called from the transfer futures so it has to be fast
+pub type ProgressCallback = Arc<dyn Fn(&ProgressEvent) + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProgressStage {
+    Started,
+    Transferring,
+    Finished,
+    Failed,
+}
+
+/// Aggregate counters of all the transfers done by a [`super::RSync`] client
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProgressTotals {
+    pub files_started: u64,
+    pub files_finished: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Bytes left to write for the transfers in progress, based on their source size when known
+    pub bytes_remaining: u64,
+}
+
+/// Progress of a single file transfer along with the aggregate totals.
+///
+/// `read` is the number of bytes read from the source and `written` the number of bytes consumed by the destination
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgressEvent {
+    pub path: RelativePath,
+    pub stage: ProgressStage,
+    pub size: Option<Size>,
+    pub read: u64,
+    pub written: u64,
+    pub totals: ProgressTotals,
+}
+
+#[derive(Default)]
+struct Counters {
+    files_started: AtomicU64,
+    files_finished: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    bytes_remaining: AtomicU64,
+}
+
+impl Counters {
+    fn totals(&self) -> ProgressTotals {
+        ProgressTotals {
+            files_started: self.files_started.load(Ordering::Relaxed),
+            files_finished: self.files_finished.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            bytes_remaining: self.bytes_remaining.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub(super) struct Progress {
+    callback: ProgressCallback,
+    counters: Counters,
+}
+
+impl Progress {
+    pub(super) fn new(callback: ProgressCallback) -> Self {
+        Self {
+            callback,
+            counters: Counters::default(),
+        }
+    }
+
+    /// Start tracking a file transfer, its attempts are tracked with [`FileProgress::track`] and it ends with [`FileProgress::finish`]
+    pub(super) fn start(
+        self: &Arc<Self>,
+        path: &RelativePath,
+        size: Option<Size>,
+    ) -> Arc<FileProgress> {
+        let file = Arc::new(FileProgress {
+            progress: self.clone(),
+            path: path.to_owned(),
+            size,
+            read: AtomicU64::new(0),
+            written: AtomicU64::new(0),
+        });
+        self.counters.files_started.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .bytes_remaining
+            .fetch_add(size.unwrap_or(0), Ordering::Relaxed);
+        file.emit(ProgressStage::Started);
+        file
+    }
+}
+
+pub(super) struct FileProgress {
+    progress: Arc<Progress>,
+    path: RelativePath,
+    size: Option<Size>,
+    read: AtomicU64,
+    written: AtomicU64,
+}
+
+impl FileProgress {
+    fn emit(&self, stage: ProgressStage) {
+        let event = ProgressEvent {
+            path: self.path.to_owned(),
+            stage,
+            size: self.size,
+            read: self.read.load(Ordering::Relaxed),
+            written: self.written.load(Ordering::Relaxed),
+            totals: self.progress.counters.totals(),
+        };
+        (self.progress.callback)(&event);
+    }
+
+    /// Wrap the source stream of an attempt so that every chunk read from the source and consumed by the destination is reported.
+    ///
+    /// The bytes of a previous failed attempt are taken back from the totals so that a retried file is only counted once
+    pub(super) fn track(self: &Arc<Self>, stream: ByteStream) -> ByteStream {
+        self.reset();
+        let tracked = self.clone();
+        futures::stream::unfold((stream, 0), move |(mut stream, consumed)| {
+            let file = tracked.clone();
+            async move {
+                // the destination polls the next chunk once the previous one has been consumed
+                if consumed > 0 {
+                    file.written(consumed);
+                }
+                match stream.next().await {
+                    Some(Ok(chunk)) => {
+                        let len = chunk.len() as u64;
+                        file.read(len);
+                        Some((Ok(chunk), (stream, len)))
+                    }
+                    Some(Err(e)) => Some((Err(e), (stream, 0))),
+                    None => None,
+                }
+            }
+        })
+        .boxed()
+    }
+
+    fn reset(&self) {
+        let read = self.read.swap(0, Ordering::Relaxed);
+        let written = self.written.swap(0, Ordering::Relaxed);
+        let counters = &self.progress.counters;
+        counters.bytes_read.fetch_sub(read, Ordering::Relaxed);
+        counters.bytes_written.fetch_sub(written, Ordering::Relaxed);
+        counters
+            .bytes_remaining
+            .fetch_add(written.min(self.size.unwrap_or(0)), Ordering::Relaxed);
+    }
+
+    fn read(&self, len: u64) {
+        self.read.fetch_add(len, Ordering::Relaxed);
+        self.progress
+            .counters
+            .bytes_read
+            .fetch_add(len, Ordering::Relaxed);
+        self.emit(ProgressStage::Transferring);
+    }
+
+    /// Count written bytes and release the matching part of the expected size from the remaining bytes
+    fn add_written(&self, len: u64) {
+        let written = self.written.fetch_add(len, Ordering::Relaxed);
+        let size = self.size.unwrap_or(0);
+        let released = (written + len).min(size) - written.min(size);
+        let counters = &self.progress.counters;
+        counters.bytes_written.fetch_add(len, Ordering::Relaxed);
+        counters
+            .bytes_remaining
+            .fetch_sub(released, Ordering::Relaxed);
+    }
+
+    fn written(&self, len: u64) {
+        self.add_written(len);
+        self.emit(ProgressStage::Transferring);
+    }
+
+    pub(super) fn finish(&self, result: &RSyncResult<()>) {
+        let stage = if result.is_ok() {
+            let read = self.read.load(Ordering::Relaxed);
+            let written = self.written.load(Ordering::Relaxed);
+            self.add_written(read.saturating_sub(written));
+            ProgressStage::Finished
+        } else {
+            ProgressStage::Failed
+        };
+        let written = self.written.load(Ordering::Relaxed);
+        let size = self.size.unwrap_or(0);
+        let counters = &self.progress.counters;
+        counters
+            .bytes_remaining
+            .fetch_sub(size.saturating_sub(written), Ordering::Relaxed);
+        counters.files_finished.fetch_add(1, Ordering::Relaxed);
+        self.emit(stage);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::sync::testing::{already_synced, created, mtime, path, rsync, sync};
+    use crate::sync::{MemoryStore, ProgressEvent};
+
+    use super::ProgressStage;
+
+    #[tokio::test]
+    async fn test_sync_progress() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        source.insert("a.txt", "hello", mtime(1)).unwrap();
+        source.insert("b.txt", "hello world", mtime(1)).unwrap();
+        dest.insert("b.txt", "hello world", mtime(1)).unwrap();
+
+        let events = Arc::new(Mutex::new(Vec::<ProgressEvent>::new()));
+        let rsync = {
+            let events = events.clone();
+            rsync(&source, &dest).with_progress(move |x| events.lock().unwrap().push(x.to_owned()))
+        };
+        assert_eq!(
+            vec![
+                created("a.txt"),
+                already_synced("same mtime and size", "b.txt")
+            ],
+            sync(&rsync).await
+        );
+
+        let events = events.lock().unwrap();
+        assert_eq!(
+            vec![
+                (ProgressStage::Started, 0, 0),
+                (ProgressStage::Transferring, 5, 0),
+                (ProgressStage::Transferring, 5, 5),
+                (ProgressStage::Finished, 5, 5),
+            ],
+            events
+                .iter()
+                .map(|x| (x.stage, x.read, x.written))
+                .collect::<Vec<_>>()
+        );
+        assert!(events
+            .iter()
+            .all(|x| x.path == path("a.txt") && x.size == Some(5)));
+        assert_eq!(5, events[0].totals.bytes_remaining);
+
+        let totals = &events.last().unwrap().totals;
+        assert_eq!(
+            (1, 1, 5, 5, 0),
+            (
+                totals.files_started,
+                totals.files_finished,
+                totals.bytes_read,
+                totals.bytes_written,
+                totals.bytes_remaining
+            )
+        );
+    }
+}
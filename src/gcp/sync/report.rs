@@ -0,0 +1,314 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use futures::{StreamExt, TryStreamExt};
+
+use super::{
+    BisyncStatus, ByteStream, Operation, RMirrorStatus, RSync, RSyncError, RSyncResult,
+    RSyncStatus, RelativePath,
+};
+
+/// Statistics of a sync, mirror or bisync run.
+///
+/// Dry run statuses (`Would*`) are counted as the change they would make, `dry_run` tells them apart.
+/// Missing fields default to zero so that reports written by other versions can still be read
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SyncReport {
+    pub dry_run: bool,
+    pub created: u64,
+    pub updated: u64,
+    pub already_synced: u64,
+    /// Existing or newer destination entries left as is (see [`RSync::with_ignore_existing`] and [`RSync::with_update_only`])
+    pub skipped: u64,
+    pub deleted: u64,
+    pub not_deleted: u64,
+    /// Updated or deleted destination entries kept as a backup first (see [`RSync::with_backup`])
+    pub backed_up: u64,
+    /// Excluded destination entries protected by mirror (see [`RSync::with_delete_excluded`])
+    pub excluded: u64,
+    pub conflicts: u64,
+    pub failed: u64,
+    /// Bytes written to the destination by successful transfers
+    pub bytes_transferred: u64,
+    pub elapsed_secs: f64,
+    pub bytes_per_second: f64,
+    pub failures: Vec<SyncFailure>,
+}
+
+impl SyncReport {
+    pub fn has_failures(&self) -> bool {
+        self.failed > 0
+    }
+
+    /// Paths to give to [`RSync::with_only_paths`] to retry the failed entries
+    pub fn failed_paths(&self) -> Vec<RelativePath> {
+        let mut paths = self
+            .failures
+            .iter()
+            .filter_map(|x| x.path.to_owned())
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+}
+
+/// Serializable view of a failure, `path` and `operation` are unknown for errors not bound to an entry (ex: listing errors)
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncFailure {
+    pub path: Option<RelativePath>,
+    pub operation: Option<Operation>,
+    pub error: String,
+}
+
+impl From<&RSyncError> for SyncFailure {
+    fn from(error: &RSyncError) -> Self {
+        Self {
+            path: error.path().cloned(),
+            operation: error.operation(),
+            error: error.to_string(),
+        }
+    }
+}
+
+/// Collect a [`SyncReport`] while driving the streams of a [`RSync`] client (see [`RSync::reporter`])
+pub struct SyncReporter {
+    started_at: Instant,
+    transferred: Arc<AtomicU64>,
+    transferred_at_start: u64,
+    report: SyncReport,
+}
+
+impl SyncReporter {
+    fn record_status(&mut self, status: &RSyncStatus) {
+        let counter = match status {
+            RSyncStatus::UpdatedWithBackup { .. } => {
+                self.report.backed_up += 1;
+                &mut self.report.updated
+            }
+            RSyncStatus::Created(_) | RSyncStatus::WouldCreate(_) => &mut self.report.created,
+            RSyncStatus::Updated { .. } | RSyncStatus::WouldUpdate { .. } => {
+                &mut self.report.updated
+            }
+            RSyncStatus::AlreadySynced { .. } => &mut self.report.already_synced,
+            RSyncStatus::SkippedExisting(_) | RSyncStatus::SkippedNewerDest(_) => {
+                &mut self.report.skipped
+            }
+        };
+        *counter += 1;
+    }
+
+    pub fn record_error(&mut self, error: &RSyncError) {
+        self.report.failed += 1;
+        self.report.failures.push(error.into());
+    }
+
+    pub fn record_sync(&mut self, status: &RSyncResult<RSyncStatus>) {
+        match status {
+            Ok(status) => self.record_status(status),
+            Err(e) => self.record_error(e),
+        }
+    }
+
+    pub fn record_mirror(&mut self, status: &RSyncResult<RMirrorStatus>) {
+        match status {
+            Ok(RMirrorStatus::Synced(status)) => self.record_status(status),
+            Ok(RMirrorStatus::Deleted(_) | RMirrorStatus::WouldDelete(_)) => {
+                self.report.deleted += 1
+            }
+            Ok(RMirrorStatus::NotDeleted(_)) => self.report.not_deleted += 1,
+            Ok(RMirrorStatus::Excluded(_)) => self.report.excluded += 1,
+            Ok(RMirrorStatus::DeletedWithBackup { .. }) => {
+                self.report.backed_up += 1;
+                self.report.deleted += 1
+            }
+            Err(e) => self.record_error(e),
+        }
+    }
+
+    /// Failed bisync entries are reported apart in [`super::BisyncReport::failures`], see [`SyncReporter::record_error`]
+    pub fn record_bisync(&mut self, status: &BisyncStatus) {
+        match status {
+            BisyncStatus::ToDest(status) | BisyncStatus::ToSource(status) => {
+                self.record_status(status)
+            }
+            BisyncStatus::Deleted { .. } | BisyncStatus::WouldDelete { .. } => {
+                self.report.deleted += 1
+            }
+            BisyncStatus::AlreadySynced(_) => self.report.already_synced += 1,
+            BisyncStatus::Conflict { .. } => self.report.conflicts += 1,
+        }
+    }
+
+    pub fn finish(mut self) -> SyncReport {
+        let elapsed_secs = self.started_at.elapsed().as_secs_f64();
+        let bytes_transferred =
+            self.transferred.load(Ordering::Relaxed) - self.transferred_at_start;
+        self.report.bytes_transferred = bytes_transferred;
+        self.report.elapsed_secs = elapsed_secs;
+        self.report.bytes_per_second = if elapsed_secs > 0.0 {
+            bytes_transferred as f64 / elapsed_secs
+        } else {
+            0.0
+        };
+        self.report
+    }
+}
+
+/// Count the bytes going through the stream, the count is only meaningful once the stream is consumed
+pub(super) fn count(stream: ByteStream) -> (ByteStream, Arc<AtomicU64>) {
+    let counter = Arc::new(AtomicU64::new(0));
+    let counted = counter.clone();
+    let stream = stream
+        .inspect_ok(move |chunk| {
+            counted.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        })
+        .boxed();
+    (stream, counter)
+}
+
+impl RSync {
+    /// Start a [`SyncReport`], statuses have to be recorded by the caller while driving the streams and bytes transferred from now on are counted.
+    ///
+    /// Example
+    /// ```rust
+    /// use futures::StreamExt;
+    /// use gcs_rsync::sync::{MemoryStore, RSync, ReaderWriter};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let source = MemoryStore::new();
+    ///     source.insert("hello.txt", "hello", None).unwrap();
+    ///     let rsync = RSync::new(ReaderWriter::memory(&source), ReaderWriter::memory(&MemoryStore::new()));
+    ///
+    ///     let mut reporter = rsync.reporter();
+    ///     let mut statuses = rsync.run_sync().await.boxed();
+    ///     while let Some(status) = statuses.next().await {
+    ///         println!("{:?}", status);
+    ///         reporter.record_sync(&status);
+    ///     }
+    ///     let report = reporter.finish();
+    ///     assert_eq!(1, report.created);
+    ///     assert_eq!(5, report.bytes_transferred);
+    /// }
+    /// ```
+    pub fn reporter(&self) -> SyncReporter {
+        SyncReporter {
+            started_at: Instant::now(),
+            transferred: self.transferred.clone(),
+            transferred_at_start: self.transferred.load(Ordering::Relaxed),
+            report: SyncReport {
+                dry_run: self.dry_run,
+                ..SyncReport::default()
+            },
+        }
+    }
+
+    /// Run [`RSync::run_sync`] to completion and only return its [`SyncReport`]
+    pub async fn sync_report(&self) -> SyncReport {
+        let mut reporter = self.reporter();
+        let mut statuses = self.run_sync().await.boxed();
+        while let Some(status) = statuses.next().await {
+            reporter.record_sync(&status);
+        }
+        reporter.finish()
+    }
+
+    /// Run [`RSync::run_mirror`] to completion and only return its [`SyncReport`]
+    pub async fn mirror_report(&self) -> RSyncResult<SyncReport> {
+        let mut reporter = self.reporter();
+        let mut statuses = self.run_mirror().await?.boxed();
+        while let Some(status) = statuses.next().await {
+            reporter.record_mirror(&status);
+        }
+        Ok(reporter.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sync::testing::{flaky_rsync, mtime};
+    use crate::sync::{MemoryStore, Operation, RSyncError, RelativePath};
+
+    use super::SyncReport;
+
+    #[test]
+    fn test_report_json() {
+        let path = RelativePath::new("a.txt").unwrap();
+        let error =
+            RSyncError::MemoryObjectNotFound(path.to_owned()).entry_failed(&path, Operation::Read);
+        let report = SyncReport {
+            created: 2,
+            failed: 1,
+            bytes_transferred: 10,
+            failures: vec![(&error).into()],
+            ..SyncReport::default()
+        };
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(2, json["created"]);
+        assert_eq!(10, json["bytesTransferred"]);
+        assert_eq!("a.txt", json["failures"][0]["path"]);
+        assert_eq!("read", json["failures"][0]["operation"]);
+        assert_eq!(report, serde_json::from_value(json).unwrap());
+        assert!(report.has_failures());
+        assert_eq!(vec![path], report.failed_paths());
+    }
+
+    #[test]
+    fn test_report_json_missing_fields() {
+        let json = serde_json::json!({ "created": 1, "failures": [] });
+        let report: SyncReport = serde_json::from_value(json).unwrap();
+        assert_eq!(
+            SyncReport {
+                created: 1,
+                ..SyncReport::default()
+            },
+            report
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_and_mirror_report() {
+        let source = MemoryStore::new();
+        let dest = MemoryStore::new();
+        source.insert("a.txt", "hello", mtime(1)).unwrap();
+        source.insert("b.txt", "world", mtime(1)).unwrap();
+        source.insert("c.txt", "synced", mtime(1)).unwrap();
+        dest.insert("c.txt", "synced", mtime(1)).unwrap();
+        dest.insert("old.txt", "old", mtime(1)).unwrap();
+
+        let (rsync, _) = flaky_rsync(&source, &dest, 1, 1);
+        let report = rsync.sync_report().await;
+        assert_eq!(
+            (1, 1, 1, 5),
+            (
+                report.created,
+                report.already_synced,
+                report.failed,
+                report.bytes_transferred
+            )
+        );
+        assert_eq!(1, report.failures.len());
+        assert!(report.has_failures());
+
+        let report = rsync.mirror_report().await.unwrap();
+        assert_eq!(
+            (1, 2, 1, 0, 5),
+            (
+                report.created,
+                report.already_synced,
+                report.deleted,
+                report.failed,
+                report.bytes_transferred
+            )
+        );
+        assert!(!report.has_failures());
+    }
+}
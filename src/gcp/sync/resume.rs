@@ -0,0 +1,237 @@
+use std::ops::Not;
+
+use futures::{StreamExt, TryStreamExt};
+
+use super::{ByteStream, EntryState, RSyncResult, ReaderWriterBackend, RelativePath, Size};
+
+/// Content kept by a failed write of an entry that the next write can append to, see [`ReaderWriterBackend::resumable`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartialContent {
+    pub size: Size,
+    /// crc32c of the kept content so that the resumed content is verified as a whole
+    pub crc32c: u32,
+}
+
+/// Skip the first `offset` bytes of the stream
+pub(super) fn skip_bytes(stream: ByteStream, offset: Size) -> ByteStream {
+    if offset == 0 {
+        return stream;
+    }
+    let mut remaining = offset;
+    stream
+        .try_filter_map(move |mut data| {
+            let skipped = remaining.min(data.len() as Size);
+            remaining -= skipped;
+            let data = data.split_off(skipped as usize);
+            futures::future::ready(Ok(data.is_empty().not().then_some(data)))
+        })
+        .boxed()
+}
+
+/// Open the source of a transfer along with the crc32c of the whole content and the partial content of the destination it resumes, if any.
+///
+/// A partial content is only resumed when it is smaller than the source and the source crc32c is known so that the written
+/// content is verified as a whole, the source is read from the start and overwrites the partial content otherwise
+pub(super) async fn open_source(
+    from: &dyn ReaderWriterBackend,
+    from_path: &RelativePath,
+    to: &dyn ReaderWriterBackend,
+    to_path: &RelativePath,
+    source: &EntryState,
+) -> RSyncResult<(ByteStream, Option<u32>, Option<PartialContent>)> {
+    let partial = to
+        .resumable(to_path)
+        .await?
+        .filter(|partial| source.size.is_some_and(|size| partial.size < size));
+    if let Some(partial) = partial {
+        let (stream, read_crc32c) = from.read_from(from_path, partial.size).await;
+        if let Some(crc32c) = source.crc32c.or(read_crc32c) {
+            return Ok((stream, Some(crc32c), Some(partial)));
+        }
+    }
+    // the listing may not know the crc32c (ex: fs sources in default mode) while the download does
+    let (stream, read_crc32c) = from.read_with_crc32c(from_path).await;
+    Ok((stream, source.crc32c.or(read_crc32c), None))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Not;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use futures::stream::BoxStream;
+    use futures::{StreamExt, TryStreamExt};
+
+    use crate::storage::RetryPolicy;
+    use crate::sync::testing::{created, mtime, sync, CONCURRENCY_LEVEL};
+    use crate::sync::{
+        MemoryStore, Operation, RSync, RSyncError, RSyncResult, ReaderWriter, ReaderWriterBackend,
+        RelativePath, Size,
+    };
+
+    use super::{skip_bytes, ByteStream};
+
+    #[tokio::test]
+    async fn test_skip_bytes() {
+        for (offset, expected) in [(0, "hello world"), (3, "lo world"), (7, "orld"), (11, "")] {
+            let stream = futures::stream::iter(["hel", "lo ", "world"].map(|x| Ok(Bytes::from(x))));
+            let actual = skip_bytes(stream.boxed(), offset)
+                .map_ok(|x| x.to_vec())
+                .try_concat()
+                .await
+                .unwrap();
+            assert_eq!(expected.as_bytes(), actual, "offset {offset}");
+        }
+    }
+
+    /// Source failing the first read halfway with a transient error, it records the offset of every read
+    struct BrokenReadStore {
+        store: MemoryStore,
+        offsets: Arc<Mutex<Vec<Size>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl ReaderWriterBackend for BrokenReadStore {
+        async fn is_valid(&self) -> RSyncResult<()> {
+            self.store.is_valid().await
+        }
+
+        async fn list(&self) -> BoxStream<'_, RSyncResult<RelativePath>> {
+            self.store.list().await
+        }
+
+        async fn read(&self, path: &RelativePath) -> ByteStream {
+            self.read_from(path, 0).await.0
+        }
+
+        async fn read_with_crc32c(&self, path: &RelativePath) -> (ByteStream, Option<u32>) {
+            self.read_from(path, 0).await
+        }
+
+        async fn read_from(&self, path: &RelativePath, offset: Size) -> (ByteStream, Option<u32>) {
+            let first = {
+                let mut offsets = self.offsets.lock().unwrap();
+                offsets.push(offset);
+                offsets.len() == 1
+            };
+            let (stream, crc32c) = self.store.read_from(path, offset).await;
+            if first.not() {
+                return (stream, crc32c);
+            }
+            let content = Bytes::from(stream.map_ok(|x| x.to_vec()).try_concat().await.unwrap());
+            let error = RSyncError::StorageError(crate::storage::Error::GcsTransientResponse {
+                url: path.as_str().to_owned(),
+                status: 503,
+                value: "backend error".to_owned(),
+            });
+            let chunks = vec![Ok(content.slice(..content.len() / 2)), Err(error)];
+            (futures::stream::iter(chunks).boxed(), crc32c)
+        }
+
+        async fn get_crc32c(&self, path: &RelativePath) -> RSyncResult<Option<u32>> {
+            self.store.get_crc32c(path).await
+        }
+
+        async fn write(
+            &self,
+            mtime: Option<chrono::DateTime<chrono::Utc>>,
+            restore_fs_mtime: bool,
+            path: &RelativePath,
+            stream: ByteStream,
+        ) -> RSyncResult<()> {
+            self.store
+                .write(mtime, restore_fs_mtime, path, stream)
+                .await
+        }
+
+        async fn delete(&self, path: &RelativePath) -> RSyncResult<()> {
+            self.store.delete(path).await
+        }
+
+        async fn exists(&self, path: &RelativePath) -> RSyncResult<bool> {
+            self.store.exists(path).await
+        }
+
+        async fn size_and_mt(
+            &self,
+            path: &RelativePath,
+        ) -> RSyncResult<(Option<chrono::DateTime<chrono::Utc>>, Option<Size>)> {
+            self.store.size_and_mt(path).await
+        }
+    }
+
+    fn temp_dir() -> std::path::PathBuf {
+        let mut base_path = std::env::temp_dir();
+        base_path.push("rsync_resume_tests");
+        base_path.push(uuid::Uuid::new_v4().hyphenated().to_string());
+        base_path
+    }
+
+    fn retry_policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy::default()
+            .with_max_attempts(max_attempts)
+            .with_backoff(Duration::ZERO, Duration::ZERO, 2.0)
+    }
+
+    #[tokio::test]
+    async fn test_failed_download_is_resumed() {
+        let source = MemoryStore::new();
+        source.insert("a.txt", "hello world", mtime(1)).unwrap();
+        let base_path = temp_dir();
+        let offsets = Arc::new(Mutex::new(Vec::new()));
+        let broken = BrokenReadStore {
+            store: source.clone(),
+            offsets: offsets.clone(),
+        };
+        let rsync = RSync::new(
+            ReaderWriter::new(broken),
+            ReaderWriter::fs_keep_partial(base_path.as_path()),
+        )
+        .with_retry_policy(retry_policy(2));
+
+        assert_eq!(vec![created("a.txt")], sync(&rsync).await);
+        assert_eq!(
+            vec![0, 5],
+            *offsets.lock().unwrap(),
+            "the retry reads the source from the end of the partial file"
+        );
+        let file_path = base_path.join("a.txt");
+        assert_eq!("hello world", std::fs::read_to_string(&file_path).unwrap());
+        assert!(!base_path.join(".a.txt.gcs-rsync-partial").exists());
+        std::fs::remove_dir_all(base_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_partial_file_is_removed() {
+        let source = MemoryStore::new();
+        source.insert("a.txt", "hello world", mtime(1)).unwrap();
+        let base_path = temp_dir();
+        let file_path = base_path.join("a.txt");
+        let partial = base_path.join(".a.txt.gcs-rsync-partial");
+        std::fs::create_dir_all(base_path.as_path()).unwrap();
+        std::fs::write(partial.as_path(), "HELLO").unwrap();
+        let rsync = RSync::new(
+            ReaderWriter::memory(&source),
+            ReaderWriter::fs_keep_partial(base_path.as_path()),
+        )
+        .with_retry_policy(retry_policy(1));
+
+        let error = rsync
+            .sync()
+            .await
+            .try_buffer_unordered(CONCURRENCY_LEVEL)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap_err();
+        assert_eq!(Some(Operation::Verify), error.operation());
+        assert!(error.to_string().contains("Crc32cMismatch"), "{}", error);
+        assert!(!file_path.exists());
+        assert!(!partial.exists(), "a corrupted partial file is not kept");
+
+        assert_eq!(vec![created("a.txt")], sync(&rsync).await);
+        assert_eq!("hello world", std::fs::read_to_string(&file_path).unwrap());
+        std::fs::remove_dir_all(base_path).unwrap();
+    }
+}
@@ -0,0 +1,272 @@
+//! Helpers shared by the module tests syncing [`MemoryStore`]s
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use std::time::Duration;
+
+use chrono::TimeZone;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+
+use crate::storage::RetryPolicy;
+
+use super::{
+    ByteStream, ListedEntry, MemoryStore, RMirrorStatus, RSync, RSyncError, RSyncResult,
+    RSyncStatus, ReaderWriter, ReaderWriterBackend, RelativePath, Size,
+};
+
+pub(super) const CONCURRENCY_LEVEL: usize = 12;
+
+pub(super) fn mtime(secs: i64) -> Option<chrono::DateTime<chrono::Utc>> {
+    chrono::Utc.timestamp_opt(secs, 0).single()
+}
+
+pub(super) fn path(path: &str) -> RelativePath {
+    RelativePath::new(path).unwrap()
+}
+
+pub(super) fn rsync(source: &MemoryStore, dest: &MemoryStore) -> RSync {
+    RSync::new(ReaderWriter::memory(source), ReaderWriter::memory(dest))
+}
+
+pub(super) async fn sync(rsync: &RSync) -> Vec<RSyncStatus> {
+    let mut actual = rsync
+        .sync()
+        .await
+        .try_buffer_unordered(CONCURRENCY_LEVEL)
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    actual.sort();
+    actual
+}
+
+pub(super) async fn mirror(rsync: &RSync) -> Vec<RMirrorStatus> {
+    let mut actual = rsync
+        .mirror()
+        .await
+        .unwrap()
+        .try_buffer_unordered(CONCURRENCY_LEVEL)
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    actual.sort();
+    actual
+}
+
+pub(super) fn content(store: &MemoryStore, path: &str) -> Option<bytes::Bytes> {
+    store.get(path).map(|x| x.content)
+}
+
+pub(super) fn created(p: &str) -> RSyncStatus {
+    RSyncStatus::Created(path(p))
+}
+
+pub(super) fn updated(reason: &str, p: &str) -> RSyncStatus {
+    RSyncStatus::Updated {
+        reason: reason.to_owned(),
+        path: path(p),
+    }
+}
+
+pub(super) fn already_synced(reason: &str, p: &str) -> RSyncStatus {
+    RSyncStatus::AlreadySynced {
+        reason: reason.to_owned(),
+        path: path(p),
+    }
+}
+
+pub(super) fn synced(x: RSyncStatus) -> RMirrorStatus {
+    RMirrorStatus::Synced(x)
+}
+
+pub(super) fn deleted(p: &str) -> RMirrorStatus {
+    RMirrorStatus::Deleted(path(p))
+}
+
+pub(super) fn not_deleted(p: &str) -> RMirrorStatus {
+    RMirrorStatus::NotDeleted(path(p))
+}
+
+pub(super) fn excluded(p: &str) -> RMirrorStatus {
+    RMirrorStatus::Excluded(path(p))
+}
+
+/// Counts per entry metadata requests, listing with metadata is forwarded only when `with_metadata` is set
+pub(super) struct CountingStore {
+    store: MemoryStore,
+    with_metadata: bool,
+    requests: Arc<AtomicUsize>,
+}
+
+impl CountingStore {
+    pub(super) fn reader_writer(
+        store: &MemoryStore,
+        with_metadata: bool,
+        requests: &Arc<AtomicUsize>,
+    ) -> ReaderWriter {
+        ReaderWriter::new(Self {
+            store: store.clone(),
+            with_metadata,
+            requests: requests.clone(),
+        })
+    }
+
+    fn count(&self) {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[async_trait::async_trait]
+impl ReaderWriterBackend for CountingStore {
+    async fn is_valid(&self) -> RSyncResult<()> {
+        self.store.is_valid().await
+    }
+
+    async fn list(&self) -> BoxStream<'_, RSyncResult<RelativePath>> {
+        self.store.list().await
+    }
+
+    async fn list_with_metadata(&self) -> BoxStream<'_, RSyncResult<ListedEntry>> {
+        if self.with_metadata {
+            self.store.list_with_metadata().await
+        } else {
+            futures::stream::iter(
+                self.store
+                    .paths()
+                    .into_iter()
+                    .map(|path| Ok(ListedEntry::new(path, None))),
+            )
+            .boxed()
+        }
+    }
+
+    async fn read(&self, path: &RelativePath) -> ByteStream {
+        self.store.read(path).await
+    }
+
+    async fn get_crc32c(&self, path: &RelativePath) -> RSyncResult<Option<u32>> {
+        self.count();
+        self.store.get_crc32c(path).await
+    }
+
+    async fn write(
+        &self,
+        mtime: Option<chrono::DateTime<chrono::Utc>>,
+        restore_fs_mtime: bool,
+        path: &RelativePath,
+        stream: ByteStream,
+    ) -> RSyncResult<()> {
+        self.store
+            .write(mtime, restore_fs_mtime, path, stream)
+            .await
+    }
+
+    async fn delete(&self, path: &RelativePath) -> RSyncResult<()> {
+        self.store.delete(path).await
+    }
+
+    async fn exists(&self, path: &RelativePath) -> RSyncResult<bool> {
+        self.count();
+        self.store.exists(path).await
+    }
+
+    async fn size_and_mt(
+        &self,
+        path: &RelativePath,
+    ) -> RSyncResult<(Option<chrono::DateTime<chrono::Utc>>, Option<Size>)> {
+        self.count();
+        self.store.size_and_mt(path).await
+    }
+}
+
+/// Destination failing the first writes with a transient error after consuming the stream
+pub(super) struct FlakyStore {
+    pub(super) store: MemoryStore,
+    pub(super) failures: usize,
+    pub(super) writes: Arc<AtomicUsize>,
+    pub(super) remote: bool,
+}
+
+#[async_trait::async_trait]
+impl ReaderWriterBackend for FlakyStore {
+    async fn is_valid(&self) -> RSyncResult<()> {
+        self.store.is_valid().await
+    }
+
+    async fn list(&self) -> BoxStream<'_, RSyncResult<RelativePath>> {
+        self.store.list().await
+    }
+
+    async fn read(&self, path: &RelativePath) -> ByteStream {
+        self.store.read(path).await
+    }
+
+    async fn get_crc32c(&self, path: &RelativePath) -> RSyncResult<Option<u32>> {
+        self.store.get_crc32c(path).await
+    }
+
+    async fn write(
+        &self,
+        mtime: Option<chrono::DateTime<chrono::Utc>>,
+        restore_fs_mtime: bool,
+        path: &RelativePath,
+        stream: ByteStream,
+    ) -> RSyncResult<()> {
+        if self.writes.fetch_add(1, Ordering::SeqCst) < self.failures {
+            stream.try_collect::<Vec<_>>().await?;
+            return Err(RSyncError::StorageError(
+                crate::storage::Error::GcsTransientResponse {
+                    url: path.as_str().to_owned(),
+                    status: 503,
+                    value: "backend error".to_owned(),
+                },
+            ));
+        }
+        self.store
+            .write(mtime, restore_fs_mtime, path, stream)
+            .await
+    }
+
+    async fn delete(&self, path: &RelativePath) -> RSyncResult<()> {
+        self.store.delete(path).await
+    }
+
+    async fn exists(&self, path: &RelativePath) -> RSyncResult<bool> {
+        self.store.exists(path).await
+    }
+
+    async fn size_and_mt(
+        &self,
+        path: &RelativePath,
+    ) -> RSyncResult<(Option<chrono::DateTime<chrono::Utc>>, Option<Size>)> {
+        self.store.size_and_mt(path).await
+    }
+
+    fn is_remote(&self) -> bool {
+        self.remote
+    }
+}
+
+pub(super) fn flaky_rsync(
+    source: &MemoryStore,
+    dest: &MemoryStore,
+    failures: usize,
+    max_attempts: u32,
+) -> (RSync, Arc<AtomicUsize>) {
+    let writes = Arc::new(AtomicUsize::new(0));
+    let flaky = FlakyStore {
+        store: dest.clone(),
+        failures,
+        writes: writes.clone(),
+        remote: false,
+    };
+    let retry_policy = RetryPolicy::default()
+        .with_max_attempts(max_attempts)
+        .with_backoff(Duration::ZERO, Duration::ZERO, 2.0);
+    let rsync = RSync::new(ReaderWriter::memory(source), ReaderWriter::new(flaky))
+        .with_retry_policy(retry_policy);
+    (rsync, writes)
+}
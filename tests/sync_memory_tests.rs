@@ -0,0 +1,136 @@
+use chrono::TimeZone;
+use futures::TryStreamExt;
+
+use gcs_rsync::sync::{MemoryStore, RMirrorStatus, RSync, RSyncStatus, ReaderWriter, RelativePath};
+
+const CONCURRENCY_LEVEL: usize = 12;
+
+fn mtime(secs: i64) -> Option<chrono::DateTime<chrono::Utc>> {
+    chrono::Utc.timestamp_opt(secs, 0).single()
+}
+
+fn rsync(source: &MemoryStore, dest: &MemoryStore) -> RSync {
+    RSync::new(ReaderWriter::memory(source), ReaderWriter::memory(dest))
+}
+
+async fn sync(rsync: &RSync) -> Vec<RSyncStatus> {
+    let mut actual = rsync
+        .sync()
+        .await
+        .try_buffer_unordered(CONCURRENCY_LEVEL)
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    actual.sort();
+    actual
+}
+
+async fn mirror(rsync: &RSync) -> Vec<RMirrorStatus> {
+    let mut actual = rsync
+        .mirror()
+        .await
+        .unwrap()
+        .try_buffer_unordered(CONCURRENCY_LEVEL)
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    actual.sort();
+    actual
+}
+
+fn path(path: &str) -> RelativePath {
+    RelativePath::new(path).unwrap()
+}
+
+fn created(p: &str) -> RSyncStatus {
+    RSyncStatus::Created(path(p))
+}
+
+fn updated(reason: &str, p: &str) -> RSyncStatus {
+    RSyncStatus::Updated {
+        reason: reason.to_owned(),
+        path: path(p),
+    }
+}
+
+fn already_synced(reason: &str, p: &str) -> RSyncStatus {
+    RSyncStatus::AlreadySynced {
+        reason: reason.to_owned(),
+        path: path(p),
+    }
+}
+
+fn synced(x: RSyncStatus) -> RMirrorStatus {
+    RMirrorStatus::Synced(x)
+}
+
+fn deleted(p: &str) -> RMirrorStatus {
+    RMirrorStatus::Deleted(path(p))
+}
+
+fn not_deleted(p: &str) -> RMirrorStatus {
+    RMirrorStatus::NotDeleted(path(p))
+}
+
+#[tokio::test]
+async fn test_memory_sync() {
+    let source = MemoryStore::new();
+    let dest = MemoryStore::new();
+    source.insert("hello/world.txt", "hello", mtime(1)).unwrap();
+    source.insert("test.json", "{}", mtime(1)).unwrap();
+
+    let rsync = rsync(&source, &dest);
+    assert_eq!(
+        vec![created("hello/world.txt"), created("test.json")],
+        sync(&rsync).await
+    );
+    assert_eq!(source.get("test.json"), dest.get("test.json"));
+
+    assert_eq!(
+        vec![
+            already_synced("same mtime and size", "hello/world.txt"),
+            already_synced("same mtime and size", "test.json"),
+        ],
+        sync(&rsync).await
+    );
+
+    source.insert("test.json", "{\"a\":1}", mtime(2)).unwrap();
+    assert_eq!(
+        vec![
+            updated("different size or mtime", "test.json"),
+            already_synced("same mtime and size", "hello/world.txt"),
+        ],
+        sync(&rsync).await
+    );
+    assert_eq!(dest.get("test.json").unwrap().content, "{\"a\":1}");
+}
+
+#[tokio::test]
+async fn test_memory_mirror() {
+    let source = MemoryStore::new();
+    let dest = MemoryStore::new();
+    source.insert("a.txt", "a", mtime(1)).unwrap();
+    source.insert("b.json", "b", mtime(1)).unwrap();
+    dest.insert("extra.txt", "extra", mtime(1)).unwrap();
+
+    let rsync = rsync(&source, &dest);
+    assert_eq!(
+        vec![
+            synced(created("a.txt")),
+            synced(created("b.json")),
+            deleted("extra.txt"),
+        ],
+        mirror(&rsync).await
+    );
+    assert_eq!(vec![path("a.txt"), path("b.json")], dest.paths());
+
+    assert_eq!(
+        vec![
+            synced(already_synced("same mtime and size", "a.txt")),
+            synced(already_synced("same mtime and size", "b.json")),
+            not_deleted("a.txt"),
+            not_deleted("b.json"),
+        ],
+        mirror(&rsync).await
+    );
+}
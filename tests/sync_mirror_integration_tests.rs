@@ -4,7 +4,10 @@ use futures::{StreamExt, TryStreamExt};
 use gcs_rsync::{
     oauth2::token::ServiceAccountCredentials,
     storage::{Object, ObjectClient, StorageResult},
-    sync::{RMirrorStatus, RSync, RSyncError, RSyncStatus, ReaderWriter, RelativePath, Source},
+    sync::{
+        Backup, ComparisonStrategy, FilterRules, ProgressStage, RMirrorStatus, RSync, RSyncError,
+        RSyncStatus, ReaderWriter, RelativePath, Source,
+    },
 };
 use tokio::io::AsyncWriteExt;
 
@@ -103,6 +106,10 @@ fn not_deleted(path: &str) -> RMirrorStatus {
     RMirrorStatus::NotDeleted(RelativePath::new(path).unwrap())
 }
 
+fn excluded(path: &str) -> RMirrorStatus {
+    RMirrorStatus::Excluded(RelativePath::new(path).unwrap())
+}
+
 fn synced(x: RSyncStatus) -> RMirrorStatus {
     RMirrorStatus::Synced(x)
 }
@@ -553,7 +560,8 @@ async fn test_mirror_include_and_exclude_rsync_conf() {
     let fs_rw = Source::fs(fs.base_path().as_path());
     let rsync = RSync::new(fs_rw, gcs_rw)
         .with_excludes(vec!["*.json"].as_slice())
-        .unwrap();
+        .unwrap()
+        .with_delete_excluded(true);
     let actual = mirror(&rsync).await;
     assert_eq!(
         vec![
@@ -603,3 +611,261 @@ async fn test_mirror_when_fs_dest_doest_not_exist() {
     let rsync = gcs_rsync::sync::RSync::new(source, dest);
     assert!(rsync.mirror().await.is_err());
 }
+
+#[tokio::test]
+async fn test_fs_to_fs_dry_run() {
+    let src_t = FsTestConfig::new();
+    let dest_t = FsTestConfig::new();
+
+    write_to_file(src_t.file_path("new.txt").as_path(), "new").await;
+    write_to_file(src_t.file_path("updated.txt").as_path(), "updated").await;
+    write_to_file(dest_t.file_path("updated.txt").as_path(), "old").await;
+    write_to_file(dest_t.file_path("extra.txt").as_path(), "extra").await;
+
+    let rsync = RSync::new(
+        Source::fs(src_t.base_path().as_path()),
+        Source::fs(dest_t.base_path().as_path()),
+    )
+    .with_dry_run(true);
+
+    assert_eq!(
+        vec![
+            synced(RSyncStatus::WouldCreate(
+                RelativePath::new("new.txt").unwrap()
+            )),
+            synced(RSyncStatus::WouldUpdate {
+                reason: "different size or mtime".to_owned(),
+                path: RelativePath::new("updated.txt").unwrap()
+            }),
+            not_deleted("updated.txt"),
+            RMirrorStatus::WouldDelete(RelativePath::new("extra.txt").unwrap()),
+        ],
+        mirror(&rsync).await
+    );
+
+    assert!(!dest_t.file_path("new.txt").exists());
+    assert_eq!("old", dest_t.read_to_string("updated.txt").await);
+    assert_eq!("extra", dest_t.read_to_string("extra.txt").await);
+}
+
+#[tokio::test]
+async fn test_fs_to_fs_listing_comparison() {
+    let src_t = FsTestConfig::new();
+    let dest_t = FsTestConfig::new();
+
+    write_to_file(src_t.file_path("a/b.txt").as_path(), "b").await;
+    write_to_file(src_t.file_path("a.txt").as_path(), "a").await;
+    write_to_file(src_t.file_path("a/c/d.txt").as_path(), "d").await;
+    write_to_file(dest_t.file_path("a/extra.txt").as_path(), "extra").await;
+
+    let rsync = RSync::new(
+        Source::fs(src_t.base_path().as_path()),
+        Source::fs(dest_t.base_path().as_path()),
+    )
+    .with_restore_fs_mtime(true)
+    .with_listing_comparison(true);
+
+    assert_eq!(
+        vec![
+            synced(created("a.txt")),
+            synced(created("a/b.txt")),
+            synced(created("a/c/d.txt")),
+            deleted("a/extra.txt"),
+        ],
+        mirror(&rsync).await
+    );
+
+    assert_eq!(
+        vec![
+            synced(already_synced("same mtime and size", "a.txt")),
+            synced(already_synced("same mtime and size", "a/b.txt")),
+            synced(already_synced("same mtime and size", "a/c/d.txt")),
+            not_deleted("a.txt"),
+            not_deleted("a/b.txt"),
+            not_deleted("a/c/d.txt"),
+        ],
+        mirror(&rsync).await
+    );
+}
+
+#[tokio::test]
+async fn test_fs_to_fs_progress() {
+    let src_t = FsTestConfig::new();
+    let dest_t = FsTestConfig::new();
+
+    let content = "0123456789".repeat(100_000);
+    write_to_file(src_t.file_path("big.txt").as_path(), &content).await;
+
+    let last = std::sync::Arc::new(std::sync::Mutex::new(None));
+    let rsync = {
+        let last = last.clone();
+        RSync::new(
+            Source::fs(src_t.base_path().as_path()),
+            Source::fs(dest_t.base_path().as_path()),
+        )
+        .with_progress(move |x| *last.lock().unwrap() = Some(x.to_owned()))
+    };
+
+    assert_eq!(vec![created("big.txt")], sync(&rsync).await);
+
+    let last = last.lock().unwrap().to_owned().unwrap();
+    assert_eq!(ProgressStage::Finished, last.stage);
+    assert_eq!(content.len() as u64, last.read);
+    assert_eq!(content.len() as u64, last.written);
+    assert_eq!(content.len() as u64, last.totals.bytes_written);
+    assert_eq!(0, last.totals.bytes_remaining);
+}
+
+#[tokio::test]
+async fn test_fs_to_fs_excluded_dirs() {
+    let src_t = FsTestConfig::new();
+    let dest_t = FsTestConfig::new();
+
+    write_to_file(src_t.file_path("a.txt").as_path(), "a").await;
+    write_to_file(src_t.file_path("cache/b.txt").as_path(), "b").await;
+    write_to_file(src_t.file_path("src/cache/c.txt").as_path(), "c").await;
+    write_to_file(src_t.file_path("src/node_modules/d.txt").as_path(), "d").await;
+    write_to_file(dest_t.file_path("cache/old.txt").as_path(), "old").await;
+    write_to_file(dest_t.file_path("notes.tmp").as_path(), "notes").await;
+
+    let rules = FilterRules::parse("- /cache/\n").unwrap();
+    let rsync = RSync::new(
+        Source::fs(src_t.base_path().as_path()),
+        Source::fs(dest_t.base_path().as_path()),
+    )
+    .with_restore_fs_mtime(true)
+    .with_filter_rules(rules)
+    .with_excludes(&["**/node_modules/**", "*.tmp"])
+    .unwrap();
+
+    assert_eq!(
+        vec![created("a.txt"), created("src/cache/c.txt")],
+        sync(&rsync).await
+    );
+    assert!(!dest_t.file_path("cache/b.txt").exists());
+    assert_eq!("old", dest_t.read_to_string("cache/old.txt").await);
+
+    let rsync = rsync.with_listing_comparison(true);
+    assert_eq!(
+        vec![
+            synced(already_synced("same mtime and size", "a.txt")),
+            synced(already_synced("same mtime and size", "src/cache/c.txt")),
+            not_deleted("a.txt"),
+            not_deleted("src/cache/c.txt"),
+            excluded("notes.tmp"),
+        ],
+        mirror(&rsync).await,
+        "excluded directories are neither listed nor deleted, listed excluded files are reported"
+    );
+    assert_eq!("old", dest_t.read_to_string("cache/old.txt").await);
+
+    assert_eq!(
+        vec![
+            synced(already_synced("same mtime and size", "a.txt")),
+            synced(already_synced("same mtime and size", "src/cache/c.txt")),
+            deleted("cache/old.txt"),
+            deleted("notes.tmp"),
+            not_deleted("a.txt"),
+            not_deleted("src/cache/c.txt"),
+        ],
+        mirror(&rsync.with_delete_excluded(true)).await
+    );
+}
+
+#[tokio::test]
+async fn test_fs_to_fs_md5() {
+    let src_t = FsTestConfig::new();
+    let dest_t = FsTestConfig::new();
+
+    write_to_file(src_t.file_path("same.txt").as_path(), "same").await;
+    write_to_file(src_t.file_path("changed.txt").as_path(), "abc").await;
+    write_to_file(dest_t.file_path("same.txt").as_path(), "same").await;
+    write_to_file(dest_t.file_path("changed.txt").as_path(), "xyz").await;
+
+    let rsync = RSync::new(
+        Source::fs(src_t.base_path().as_path()),
+        Source::fs(dest_t.base_path().as_path()),
+    )
+    .with_comparison_strategy(ComparisonStrategy::Md5);
+
+    assert_eq!(
+        vec![
+            updated("different md5", "changed.txt"),
+            already_synced("same md5", "same.txt"),
+        ],
+        sync(&rsync).await
+    );
+    assert_eq!("abc", dest_t.read_to_string("changed.txt").await);
+}
+
+#[tokio::test]
+async fn test_fs_to_fs_backup() {
+    let src_t = FsTestConfig::new();
+    let dest_t = FsTestConfig::new();
+
+    write_to_file(src_t.file_path("a/changed.txt").as_path(), "newer").await;
+    write_to_file(dest_t.file_path("a/changed.txt").as_path(), "old").await;
+    write_to_file(dest_t.file_path("extra.txt").as_path(), "extra").await;
+
+    let backup = Backup::new("backup")
+        .unwrap()
+        .with_timestamp(chrono::DateTime::from_timestamp(1706745599, 0).unwrap())
+        .with_suffix(".bak");
+    let rsync = RSync::new(
+        Source::fs(src_t.base_path().as_path()),
+        Source::fs(dest_t.base_path().as_path()),
+    )
+    .with_backup(backup);
+
+    assert_eq!(
+        vec![
+            synced(RSyncStatus::UpdatedWithBackup {
+                reason: "different size or mtime".to_owned(),
+                path: RelativePath::new("a/changed.txt").unwrap(),
+                backup: RelativePath::new("backup/20240131T235959Z/a/changed.txt.bak").unwrap(),
+            }),
+            not_deleted("a/changed.txt"),
+            RMirrorStatus::DeletedWithBackup {
+                path: RelativePath::new("extra.txt").unwrap(),
+                backup: RelativePath::new("backup/20240131T235959Z/extra.txt.bak").unwrap(),
+            },
+        ],
+        mirror(&rsync).await
+    );
+    assert_eq!("newer", dest_t.read_to_string("a/changed.txt").await);
+    assert_eq!(
+        "old",
+        dest_t
+            .read_to_string("backup/20240131T235959Z/a/changed.txt.bak")
+            .await
+    );
+    assert_eq!(
+        "extra",
+        dest_t
+            .read_to_string("backup/20240131T235959Z/extra.txt.bak")
+            .await
+    );
+    assert!(!dest_t.file_path("extra.txt").exists());
+}
+
+#[tokio::test]
+async fn test_fs_to_fs_mirror_deletes_leftover_partial_files() {
+    let src_t = FsTestConfig::new();
+    let dest_t = FsTestConfig::new();
+
+    write_to_file(src_t.file_path(".b.txt.gcs-rsync-partial").as_path(), "b").await;
+    write_to_file(dest_t.file_path(".c.txt.gcs-rsync-partial").as_path(), "c").await;
+
+    let rsync = RSync::new(
+        Source::fs(src_t.base_path().as_path()),
+        Source::fs(dest_t.base_path().as_path()),
+    );
+
+    assert_eq!(
+        vec![deleted(".c.txt.gcs-rsync-partial")],
+        mirror(&rsync).await,
+        "source partial files are not synced"
+    );
+    assert!(!dest_t.file_path(".b.txt.gcs-rsync-partial").exists());
+    assert!(!dest_t.file_path(".c.txt.gcs-rsync-partial").exists());
+}